@@ -36,11 +36,9 @@ fn get_git_version() -> Option<String> {
         .ok()
         .filter(|output| output.status.success())
         .and_then(|output| {
-            let commit_hash = String::from_utf8(output.stdout)
+            String::from_utf8(output.stdout)
                 .ok()
-                .map(|s| s.trim().to_string());
-
-            commit_hash
+                .map(|s| s.trim().to_string())
         });
 
     let is_dirty = Command::new("git")
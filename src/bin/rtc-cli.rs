@@ -0,0 +1,495 @@
+//! A headless peer for debugging rooms outside the game.
+//!
+//! Joins a room, prints peer joins and leaves and every message it receives, and sends the lines
+//! typed on stdin. See `rtc-cli --help` for usage.
+
+use darktide_plugin_rtc::codec::{self, Value};
+use darktide_plugin_rtc::protocol::Frame;
+use darktide_plugin_rtc::socket::{DEFAULT_SIGNALING_URL, DataChannel, build_socket};
+use matchbox_socket::{PeerId, PeerState, RtcIceServerConfig, WebRtcSocket};
+use std::fmt::Write as _;
+use std::process::ExitCode;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time;
+
+const USAGE: &str = "\
+Usage: rtc-cli [options] <room>
+
+Joins a room, prints what happens in it, and sends the lines typed on stdin to all peers.
+A line starting with @<peer id> is only sent to that peer. Type /quit or press Ctrl-D to leave.
+
+Options:
+  --url <url>          The signaling server, defaults to wss://rtc.darkti.de
+  --ice-server <url>   A STUN server to use instead of matchbox's default, can be repeated
+  --mode <mode>        Send with \"reliable\" (the default) or \"unreliable\" mode
+  --presence <k>=<v>   A field of the presence record sent to peers, can be repeated
+  --json               Print one JSON object per line instead of text
+  -h, --help           Print this help";
+
+/// How often the socket is checked for new peers and messages.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+struct Args {
+    room: String,
+    url: String,
+    ice_servers: Vec<String>,
+    mode: DataChannel,
+    /// The presence record, as a map of strings, or nil without any `--presence`.
+    presence: Value,
+    json: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Args>, String> {
+    let mut room = None;
+    let mut url = DEFAULT_SIGNALING_URL.to_string();
+    let mut ice_servers = Vec::new();
+    let mut mode = DataChannel::Reliable;
+    let mut presence = Vec::new();
+    let mut json = false;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{name} needs a value"));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--url" => url = value("--url")?.trim_end_matches('/').to_string(),
+            "--ice-server" => ice_servers.push(value("--ice-server")?),
+            "--mode" => {
+                let name = value("--mode")?;
+                mode = DataChannel::from_name(&name)
+                    .ok_or_else(|| format!("unknown mode {name:?}"))?;
+            }
+            "--presence" => {
+                let field = value("--presence")?;
+                let (key, value) = field
+                    .split_once('=')
+                    .ok_or_else(|| format!("presence field {field:?} should be key=value"))?;
+                presence.push((
+                    Value::String(key.as_bytes().to_vec()),
+                    Value::String(value.as_bytes().to_vec()),
+                ));
+            }
+            "--json" => json = true,
+            _ if arg.starts_with('-') => return Err(format!("unknown option {arg}")),
+            _ if room.is_none() => room = Some(arg),
+            _ => return Err(format!("unexpected argument {arg}")),
+        }
+    }
+
+    if !url.starts_with("ws://") && !url.starts_with("wss://") {
+        return Err(format!(
+            "signaling url {url:?} must start with ws:// or wss://"
+        ));
+    }
+    let room = room.ok_or("missing the room to join")?;
+    Ok(Some(Args {
+        room,
+        url,
+        ice_servers,
+        mode,
+        presence: if presence.is_empty() {
+            Value::Nil
+        } else {
+            Value::Map(presence)
+        },
+        json,
+    }))
+}
+
+/// Something that happened in the room, printed as text or JSON.
+enum Event<'a> {
+    Connected {
+        id: PeerId,
+    },
+    PeerJoined {
+        peer: PeerId,
+    },
+    PeerLeft {
+        peer: PeerId,
+    },
+    Message {
+        peer: PeerId,
+        mode: DataChannel,
+        message: &'a [u8],
+    },
+    Table {
+        peer: PeerId,
+        mode: DataChannel,
+        value: &'a Value,
+    },
+    Request {
+        peer: PeerId,
+        method: &'a [u8],
+        payload: &'a Value,
+    },
+    Presence {
+        peer: PeerId,
+        value: &'a Value,
+    },
+    Invalid {
+        peer: PeerId,
+        error: String,
+    },
+    Error {
+        error: String,
+    },
+    Disconnected,
+}
+
+impl Event<'_> {
+    fn print(&self, json: bool) {
+        if json {
+            println!("{}", self.to_json());
+        } else {
+            println!("{}", self.to_text());
+        }
+    }
+
+    fn to_text(&self) -> String {
+        match self {
+            Event::Connected { id } => format!("Connected as {id}"),
+            Event::PeerJoined { peer } => format!("Peer {peer} joined"),
+            Event::PeerLeft { peer } => format!("Peer {peer} left"),
+            Event::Message {
+                peer,
+                mode,
+                message,
+            } => format!(
+                "[{peer}] ({}) {}",
+                mode.name(),
+                String::from_utf8_lossy(message)
+            ),
+            Event::Table { peer, mode, value } => {
+                format!("[{peer}] ({}) table: {}", mode.name(), to_json(value))
+            }
+            Event::Request {
+                peer,
+                method,
+                payload,
+            } => format!(
+                "[{peer}] call to {}: {}",
+                String::from_utf8_lossy(method),
+                to_json(payload)
+            ),
+            Event::Presence { peer, value } => format!("[{peer}] presence: {}", to_json(value)),
+            Event::Invalid { peer, error } => format!("[{peer}] invalid packet: {error}"),
+            Event::Error { error } => format!("Error: {error}"),
+            Event::Disconnected => "Disconnected from the signaling server".to_string(),
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let mut fields = Vec::new();
+        let mut field = |key: &str, value: String| fields.push(format!("\"{key}\":{value}"));
+        let peer_field = |peer: &PeerId| json_string(peer.to_string().as_bytes());
+        match self {
+            Event::Connected { id } => {
+                field("event", json_string(b"connected"));
+                field("id", peer_field(id));
+            }
+            Event::PeerJoined { peer } => {
+                field("event", json_string(b"peer_joined"));
+                field("peer", peer_field(peer));
+            }
+            Event::PeerLeft { peer } => {
+                field("event", json_string(b"peer_left"));
+                field("peer", peer_field(peer));
+            }
+            Event::Message {
+                peer,
+                mode,
+                message,
+            } => {
+                field("event", json_string(b"message"));
+                field("peer", peer_field(peer));
+                field("mode", json_string(mode.name().as_bytes()));
+                field("message", json_string(message));
+            }
+            Event::Table { peer, mode, value } => {
+                field("event", json_string(b"table"));
+                field("peer", peer_field(peer));
+                field("mode", json_string(mode.name().as_bytes()));
+                field("value", to_json(value));
+            }
+            Event::Request {
+                peer,
+                method,
+                payload,
+            } => {
+                field("event", json_string(b"request"));
+                field("peer", peer_field(peer));
+                field("method", json_string(method));
+                field("payload", to_json(payload));
+            }
+            Event::Presence { peer, value } => {
+                field("event", json_string(b"presence"));
+                field("peer", peer_field(peer));
+                field("value", to_json(value));
+            }
+            Event::Invalid { peer, error } => {
+                field("event", json_string(b"invalid"));
+                field("peer", peer_field(peer));
+                field("error", json_string(error.as_bytes()));
+            }
+            Event::Error { error } => {
+                field("event", json_string(b"error"));
+                field("error", json_string(error.as_bytes()));
+            }
+            Event::Disconnected => field("event", json_string(b"disconnected")),
+        }
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+/// Quotes `s` as a JSON string. Bytes that aren't valid UTF-8 are replaced.
+fn json_string(s: &[u8]) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in String::from_utf8_lossy(s).chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Converts a table sent with `RTC.send_table` to JSON. JSON objects only have string keys, so
+/// number and boolean keys are converted to strings.
+fn to_json(value: &Value) -> String {
+    match value {
+        Value::Nil => "null".to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Integer(n) => n.to_string(),
+        // JSON has no infinities or NaN
+        Value::Float(n) if !n.is_finite() => "null".to_string(),
+        Value::Float(n) => n.to_string(),
+        Value::String(s) => json_string(s),
+        Value::Array(values) => {
+            let values: Vec<String> = values.iter().map(to_json).collect();
+            format!("[{}]", values.join(","))
+        }
+        Value::Map(entries) => {
+            let entries: Vec<String> = entries
+                .iter()
+                .map(|(key, value)| {
+                    let key = match key {
+                        Value::String(s) => json_string(s),
+                        key => json_string(to_json(key).as_bytes()),
+                    };
+                    format!("{key}:{}", to_json(value))
+                })
+                .collect();
+            format!("{{{}}}", entries.join(","))
+        }
+    }
+}
+
+fn send(socket: &mut WebRtcSocket, mode: DataChannel, peer: PeerId, frame: &Frame) {
+    let packet = frame.encode().into_boxed_slice();
+    // Sending only fails if the peer has just left
+    let _ = socket.channel_mut(mode.index()).try_send(packet, peer);
+}
+
+/// Handles a packet from a peer like the plugin would, without any registered methods.
+fn handle_packet(
+    socket: &mut WebRtcSocket,
+    json: bool,
+    peer: PeerId,
+    mode: DataChannel,
+    packet: &[u8],
+) {
+    let frame = match Frame::decode(packet) {
+        Ok(frame) => frame,
+        Err(err) => {
+            let error = err.to_string();
+            Event::Invalid { peer, error }.print(json);
+            return;
+        }
+    };
+
+    match frame {
+        Frame::Message(message) => Event::Message {
+            peer,
+            mode,
+            message: &message,
+        }
+        .print(json),
+        Frame::Table(payload) => match codec::decode(&payload) {
+            Ok(value) => Event::Table {
+                peer,
+                mode,
+                value: &value,
+            }
+            .print(json),
+            Err(err) => Event::Invalid {
+                peer,
+                error: format!("invalid table: {err}"),
+            }
+            .print(json),
+        },
+        Frame::Request {
+            id,
+            method,
+            payload,
+        } => {
+            let payload = codec::decode(&payload).unwrap_or(Value::Nil);
+            Event::Request {
+                peer,
+                method: &method,
+                payload: &payload,
+            }
+            .print(json);
+            let reply = Frame::Response {
+                id,
+                ok: false,
+                payload: codec::encode(&Value::String(b"unknown method".to_vec())),
+            };
+            send(socket, DataChannel::Reliable, peer, &reply);
+        }
+        // Calls are never made, so there is nothing to match responses to
+        Frame::Response { .. } => {}
+        Frame::Ping(timestamp) => {
+            send(socket, DataChannel::Reliable, peer, &Frame::Pong(timestamp));
+        }
+        Frame::Pong(_) => {}
+        Frame::Presence(payload) => match codec::decode(&payload) {
+            Ok(value) => Event::Presence {
+                peer,
+                value: &value,
+            }
+            .print(json),
+            Err(err) => Event::Invalid {
+                peer,
+                error: format!("invalid presence: {err}"),
+            }
+            .print(json),
+        },
+    }
+}
+
+/// Sends a line typed on stdin. Returns false if the user wants to leave.
+fn handle_line(socket: &mut WebRtcSocket, args: &Args, line: &str) -> bool {
+    let line = line.trim_end_matches(['\r', '\n']);
+    if line == "/quit" {
+        return false;
+    }
+
+    let (peers, message) = match line.strip_prefix('@') {
+        Some(rest) => {
+            let (peer, message) = rest.split_once(' ').unwrap_or((rest, ""));
+            match peer.parse() {
+                Ok(peer) => (vec![PeerId(peer)], message),
+                Err(err) => {
+                    let error = format!("{peer:?} is not a valid peer id: {err}");
+                    Event::Error { error }.print(args.json);
+                    return true;
+                }
+            }
+        }
+        None => (socket.connected_peers().collect(), line),
+    };
+
+    let frame = Frame::Message(message.as_bytes().to_vec());
+    for peer in peers {
+        send(socket, args.mode, peer, &frame);
+    }
+    true
+}
+
+async fn run(args: Args) -> ExitCode {
+    let url = format!("{}/{}", args.url, args.room);
+    let ice_servers = (!args.ice_servers.is_empty()).then(|| RtcIceServerConfig {
+        urls: args.ice_servers.clone(),
+        username: None,
+        credential: None,
+    });
+    let (mut socket, loop_fut) = build_socket(&url, ice_servers.as_ref());
+    let mut message_loop = tokio::spawn(loop_fut);
+
+    // Reading stdin blocks, and tokio's stdin would keep the runtime from shutting down until
+    // another line is typed, so it gets a thread of its own
+    let (line_tx, mut lines) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines().map_while(Result::ok) {
+            if line_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut connected = false;
+    let mut interval = time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            result = &mut message_loop => {
+                match result {
+                    Ok(Ok(())) => Event::Disconnected.print(args.json),
+                    Ok(Err(err)) => Event::Error { error: err.to_string() }.print(args.json),
+                    Err(err) => Event::Error { error: err.to_string() }.print(args.json),
+                }
+                return ExitCode::FAILURE;
+            }
+            line = lines.recv() => {
+                let keep_going = line.is_some_and(|line| handle_line(&mut socket, &args, &line));
+                if !keep_going {
+                    // Give the message loop a moment to say goodbye to the signaling server
+                    socket.close();
+                    let _ = time::timeout(Duration::from_secs(1), message_loop).await;
+                    return ExitCode::SUCCESS;
+                }
+            }
+            _ = interval.tick() => {
+                if !connected && let Some(id) = socket.id() {
+                    connected = true;
+                    Event::Connected { id }.print(args.json);
+                }
+
+                if let Ok(peers) = socket.try_update_peers() {
+                    for (peer, state) in peers {
+                        match state {
+                            PeerState::Connected => {
+                                Event::PeerJoined { peer }.print(args.json);
+                                if args.presence != Value::Nil {
+                                    let presence = Frame::Presence(codec::encode(&args.presence));
+                                    send(&mut socket, DataChannel::Reliable, peer, &presence);
+                                }
+                            }
+                            PeerState::Disconnected => Event::PeerLeft { peer }.print(args.json),
+                        }
+                    }
+                }
+
+                for mode in DataChannel::ALL {
+                    let packets = socket.channel_mut(mode.index()).receive();
+                    for (peer, packet) in packets {
+                        handle_packet(&mut socket, args.json, peer, mode, &packet);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match parse_args(std::env::args().skip(1)) {
+        Ok(Some(args)) => run(args).await,
+        Ok(None) => {
+            println!("{USAGE}");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("rtc-cli: {err}\n\n{USAGE}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,350 @@
+//! Capture files, which record a room's traffic so that it can be replayed later with the
+//! `replay` option of `RTC.connect`.
+//!
+//! A capture starts with `MAGIC`, followed by a record for every packet sent or received and
+//! every peer joining or leaving:
+//! - the time since the capture started, in microseconds (u64, big endian)
+//! - the kind of record (u8), see `Event`
+//! - the peer (16 bytes)
+//! - the data channel index (u8), unreliable for peers joining or leaving
+//! - the length of the packet (u32, big endian) and the packet, as sent between peers
+//!
+//! The game may exit in the middle of writing a record, so an incomplete last record is ignored.
+
+use crate::socket::DataChannel;
+use matchbox_socket::PeerId;
+use std::collections::{HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Identifies capture files, and the version of the format.
+const MAGIC: &[u8] = b"RTCCAP\x01";
+/// The size of a record before its packet.
+const RECORD_HEADER_LEN: usize = 8 + 1 + 16 + 1 + 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Event {
+    Sent,
+    Received,
+    PeerJoined,
+    PeerLeft,
+}
+
+impl Event {
+    const ALL: [Event; 4] = [
+        Event::Sent,
+        Event::Received,
+        Event::PeerJoined,
+        Event::PeerLeft,
+    ];
+
+    fn tag(self) -> u8 {
+        self as u8
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Record {
+    /// The time since the capture started.
+    pub at: Duration,
+    pub event: Event,
+    pub peer: PeerId,
+    pub data_channel: DataChannel,
+    pub packet: Vec<u8>,
+}
+
+/// A capture file being written.
+pub(crate) struct Capture {
+    writer: BufWriter<File>,
+    started: Instant,
+}
+
+impl Capture {
+    /// Creates the file at `path`, replacing it if it exists.
+    pub fn create(path: &Path, now: Instant) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        Ok(Self {
+            writer,
+            started: now,
+        })
+    }
+
+    pub fn write(
+        &mut self,
+        now: Instant,
+        event: Event,
+        peer: PeerId,
+        data_channel: DataChannel,
+        packet: &[u8],
+    ) -> io::Result<()> {
+        let at = now.saturating_duration_since(self.started).as_micros() as u64;
+        let len = u32::try_from(packet.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "packet is too large"))?;
+
+        let mut header = Vec::with_capacity(RECORD_HEADER_LEN);
+        header.extend_from_slice(&at.to_be_bytes());
+        header.push(event.tag());
+        header.extend_from_slice(peer.0.as_bytes());
+        header.push(data_channel.index() as u8);
+        header.extend_from_slice(&len.to_be_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(packet)
+    }
+
+    /// Writes what is still buffered and closes the file.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads every record of the capture file at `path`.
+pub(crate) fn read(path: &Path) -> Result<Vec<Record>, String> {
+    let data = std::fs::read(path).map_err(|err| err.to_string())?;
+    let mut data = data
+        .strip_prefix(MAGIC)
+        .ok_or("not a capture file, or from another version of the plugin")?;
+
+    let mut records = Vec::new();
+    while data.len() >= RECORD_HEADER_LEN {
+        let (header, rest) = data.split_at(RECORD_HEADER_LEN);
+        let len = u32::from_be_bytes(header[26..30].try_into().unwrap()) as usize;
+        if rest.len() < len {
+            break;
+        }
+        let (packet, rest) = rest.split_at(len);
+        data = rest;
+
+        let event = Event::ALL
+            .get(header[8] as usize)
+            .copied()
+            .ok_or_else(|| format!("unknown record kind {}", header[8]))?;
+        let data_channel = DataChannel::ALL
+            .get(header[25] as usize)
+            .copied()
+            .ok_or_else(|| format!("unknown data channel {}", header[25]))?;
+        records.push(Record {
+            at: Duration::from_micros(u64::from_be_bytes(header[0..8].try_into().unwrap())),
+            event,
+            peer: PeerId(Uuid::from_bytes(header[9..25].try_into().unwrap())),
+            data_channel,
+            packet: packet.to_vec(),
+        });
+    }
+    Ok(records)
+}
+
+/// A capture being played back in place of a socket.
+pub(crate) struct Replay {
+    records: VecDeque<Record>,
+    started: Instant,
+    /// The peers that have joined and not left yet.
+    pub peers: HashSet<PeerId>,
+}
+
+impl Replay {
+    pub fn new(records: Vec<Record>, now: Instant) -> Self {
+        Self {
+            records: records.into(),
+            started: now,
+            peers: HashSet::new(),
+        }
+    }
+
+    /// Takes the records that happened before `now`, relative to when the replay started.
+    /// Packets we sent are skipped, since only what peers did is played back.
+    pub fn due(&mut self, now: Instant) -> Vec<Record> {
+        let elapsed = now.saturating_duration_since(self.started);
+        let mut due = Vec::new();
+        while self
+            .records
+            .front()
+            .is_some_and(|record| record.at <= elapsed)
+        {
+            let record = self.records.pop_front().unwrap();
+            match record.event {
+                Event::Sent => continue,
+                Event::Received => {}
+                Event::PeerJoined => {
+                    self.peers.insert(record.peer);
+                }
+                Event::PeerLeft => {
+                    self.peers.remove(&record.peer);
+                }
+            }
+            due.push(record);
+        }
+        due
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const PEER: PeerId = PeerId(Uuid::from_u128(0x67e55044_10b1_426f_9247_bb680e5fe0c8));
+
+    /// A capture file that is removed when the test ends.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            Self(
+                std::env::temp_dir()
+                    .join(format!("rtc-capture-{name}-{}.rtccap", std::process::id())),
+            )
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn record(at: u64, event: Event, data_channel: DataChannel, packet: &[u8]) -> Record {
+        Record {
+            at: Duration::from_millis(at),
+            event,
+            peer: PEER,
+            data_channel,
+            packet: packet.to_vec(),
+        }
+    }
+
+    fn write(file: &TempFile, records: &[Record]) {
+        let started = Instant::now();
+        let mut capture = Capture::create(&file.0, started).unwrap();
+        for record in records {
+            capture
+                .write(
+                    started + record.at,
+                    record.event,
+                    record.peer,
+                    record.data_channel,
+                    &record.packet,
+                )
+                .unwrap();
+        }
+        capture.finish().unwrap();
+    }
+
+    fn summary(records: &[Record]) -> Vec<(Duration, Event, DataChannel, Vec<u8>)> {
+        assert!(records.iter().all(|record| record.peer == PEER));
+        records
+            .iter()
+            .map(|record| {
+                (
+                    record.at,
+                    record.event,
+                    record.data_channel,
+                    record.packet.clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let file = TempFile::new("round_trip");
+        let records = [
+            record(0, Event::PeerJoined, DataChannel::Unreliable, b""),
+            record(5, Event::Received, DataChannel::Reliable, b"hello\0"),
+            record(7, Event::Sent, DataChannel::Unreliable, &[0xff; 300]),
+            record(9, Event::PeerLeft, DataChannel::Unreliable, b""),
+        ];
+        write(&file, &records);
+
+        let data = std::fs::read(&file.0).unwrap();
+        assert!(data.starts_with(MAGIC));
+        assert_eq!(data.len(), MAGIC.len() + 4 * RECORD_HEADER_LEN + 6 + 300);
+        assert_eq!(summary(&read(&file.0).unwrap()), summary(&records));
+    }
+
+    #[test]
+    fn incomplete_last_record_is_ignored() {
+        let file = TempFile::new("incomplete");
+        write(
+            &file,
+            &[
+                record(1, Event::Received, DataChannel::Reliable, b"kept"),
+                record(2, Event::Received, DataChannel::Reliable, b"cut off"),
+            ],
+        );
+        let data = std::fs::read(&file.0).unwrap();
+
+        // Cut off in the packet, and in the header
+        for cut in [1, 7 + 1, 7 + RECORD_HEADER_LEN - 1] {
+            std::fs::write(&file.0, &data[..data.len() - cut]).unwrap();
+            let records = read(&file.0).unwrap();
+            assert_eq!(records.len(), 1, "cut {cut} bytes");
+            assert_eq!(records[0].packet, b"kept");
+        }
+    }
+
+    #[test]
+    fn invalid_files_are_rejected() {
+        let file = TempFile::new("invalid");
+
+        std::fs::write(&file.0, b"RTCCAP\x00").unwrap();
+        assert!(read(&file.0).unwrap_err().contains("not a capture file"));
+
+        let mut header = [0; RECORD_HEADER_LEN];
+        header[8] = 9;
+        std::fs::write(&file.0, [MAGIC, &header].concat()).unwrap();
+        assert_eq!(read(&file.0).unwrap_err(), "unknown record kind 9");
+
+        let mut header = [0; RECORD_HEADER_LEN];
+        header[25] = 2;
+        std::fs::write(&file.0, [MAGIC, &header].concat()).unwrap();
+        assert_eq!(read(&file.0).unwrap_err(), "unknown data channel 2");
+
+        assert!(read(&std::env::temp_dir().join("rtc-capture-missing.rtccap")).is_err());
+    }
+
+    #[test]
+    fn replay_plays_back_what_peers_did() {
+        let started = Instant::now();
+        let mut replay = Replay::new(
+            vec![
+                record(0, Event::PeerJoined, DataChannel::Unreliable, b""),
+                record(10, Event::Sent, DataChannel::Reliable, b"ours"),
+                record(20, Event::Received, DataChannel::Reliable, b"theirs"),
+                record(30, Event::PeerLeft, DataChannel::Unreliable, b""),
+            ],
+            started,
+        );
+
+        let due = replay.due(started);
+        assert_eq!(
+            summary(&due),
+            [(
+                Duration::ZERO,
+                Event::PeerJoined,
+                DataChannel::Unreliable,
+                vec![]
+            )]
+        );
+        assert!(replay.peers.contains(&PEER));
+
+        // What we sent is skipped
+        let due = replay.due(started + Duration::from_millis(25));
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].packet, b"theirs");
+        assert!(!replay.is_finished());
+
+        assert!(replay.due(started + Duration::from_millis(29)).is_empty());
+        let due = replay.due(started + Duration::from_millis(30));
+        assert_eq!(due[0].event, Event::PeerLeft);
+        assert!(replay.peers.is_empty());
+        assert!(replay.is_finished());
+    }
+}
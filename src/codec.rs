@@ -0,0 +1,416 @@
+//! A compact binary encoding for Lua values, using the MessagePack format.
+//!
+//! Only the subset of MessagePack needed to represent Lua tables is written, but any valid
+//! MessagePack value made up of nil, booleans, numbers, strings, binary data, arrays and maps
+//! can be read.
+
+/// The deepest nesting of tables that is encoded or decoded. Guards against stack overflows from
+/// malicious peers.
+pub const MAX_DEPTH: usize = 32;
+
+/// A Lua value that can be sent over the network.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    /// Lua strings are byte strings, so they aren't required to be UTF-8.
+    String(Vec<u8>),
+    /// A table with the keys `1..=n`.
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+impl Value {
+    /// Converts a Lua number to the most compact representation that loses no precision.
+    pub fn from_number(n: f64) -> Self {
+        // Integers up to 2^53 are exactly representable as `f64`
+        const MAX_SAFE: f64 = 9007199254740992.0;
+        if n.fract() == 0.0 && n.abs() <= MAX_SAFE {
+            Value::Integer(n as i64)
+        } else {
+            Value::Float(n)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    UnexpectedEnd,
+    UnsupportedType(u8),
+    TooDeep,
+    TrailingBytes,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnexpectedEnd => write!(f, "unexpected end of data"),
+            Self::UnsupportedType(marker) => write!(f, "unsupported type marker 0x{marker:02x}"),
+            Self::TooDeep => write!(f, "tables nested deeper than {MAX_DEPTH} levels"),
+            Self::TrailingBytes => write!(f, "trailing bytes after value"),
+        }
+    }
+}
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut buf = Vec::new();
+    encode_into(value, &mut buf);
+    buf
+}
+
+fn encode_len(buf: &mut Vec<u8>, len: usize, fix: Option<(u8, usize)>, m16: u8, m32: u8) {
+    match fix {
+        Some((marker, max)) if len < max => buf.push(marker | len as u8),
+        _ if len <= u16::MAX as usize => {
+            buf.push(m16);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            buf.push(m32);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+}
+
+fn encode_into(value: &Value, buf: &mut Vec<u8>) {
+    match value {
+        Value::Nil => buf.push(0xc0),
+        Value::Boolean(false) => buf.push(0xc2),
+        Value::Boolean(true) => buf.push(0xc3),
+        Value::Integer(n) => {
+            let n = *n;
+            if (0..=0x7f).contains(&n) || (-32..0).contains(&n) {
+                buf.push(n as i8 as u8);
+            } else if let Ok(n) = u8::try_from(n) {
+                buf.push(0xcc);
+                buf.push(n);
+            } else if let Ok(n) = u16::try_from(n) {
+                buf.push(0xcd);
+                buf.extend_from_slice(&n.to_be_bytes());
+            } else if let Ok(n) = u32::try_from(n) {
+                buf.push(0xce);
+                buf.extend_from_slice(&n.to_be_bytes());
+            } else if let Ok(n) = u64::try_from(n) {
+                buf.push(0xcf);
+                buf.extend_from_slice(&n.to_be_bytes());
+            } else if let Ok(n) = i8::try_from(n) {
+                buf.push(0xd0);
+                buf.push(n as u8);
+            } else if let Ok(n) = i16::try_from(n) {
+                buf.push(0xd1);
+                buf.extend_from_slice(&n.to_be_bytes());
+            } else if let Ok(n) = i32::try_from(n) {
+                buf.push(0xd2);
+                buf.extend_from_slice(&n.to_be_bytes());
+            } else {
+                buf.push(0xd3);
+                buf.extend_from_slice(&n.to_be_bytes());
+            }
+        }
+        Value::Float(n) => {
+            buf.push(0xcb);
+            buf.extend_from_slice(&n.to_be_bytes());
+        }
+        Value::String(s) => {
+            if s.len() < 32 {
+                buf.push(0xa0 | s.len() as u8);
+            } else if s.len() <= u8::MAX as usize {
+                buf.push(0xd9);
+                buf.push(s.len() as u8);
+            } else {
+                encode_len(buf, s.len(), None, 0xda, 0xdb);
+            }
+            buf.extend_from_slice(s);
+        }
+        Value::Array(items) => {
+            encode_len(buf, items.len(), Some((0x90, 16)), 0xdc, 0xdd);
+            for item in items {
+                encode_into(item, buf);
+            }
+        }
+        Value::Map(entries) => {
+            encode_len(buf, entries.len(), Some((0x80, 16)), 0xde, 0xdf);
+            for (key, value) in entries {
+                encode_into(key, buf);
+                encode_into(value, buf);
+            }
+        }
+    }
+}
+
+/// Decodes a single value that must span all of `data`.
+pub fn decode(data: &[u8]) -> Result<Value, DecodeError> {
+    let mut reader = Reader { data, pos: 0 };
+    let value = reader.value(0)?;
+    if reader.pos != data.len() {
+        return Err(DecodeError::TrailingBytes);
+    }
+    Ok(value)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .ok_or(DecodeError::UnexpectedEnd)?;
+        let bytes = self
+            .data
+            .get(self.pos..end)
+            .ok_or(DecodeError::UnexpectedEnd)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<usize, DecodeError> {
+        Ok(u16::from_be_bytes(self.array()?) as usize)
+    }
+
+    fn u32(&mut self) -> Result<usize, DecodeError> {
+        Ok(u32::from_be_bytes(self.array()?) as usize)
+    }
+
+    fn string(&mut self, len: usize) -> Result<Value, DecodeError> {
+        Ok(Value::String(self.bytes(len)?.to_vec()))
+    }
+
+    fn items(&mut self, len: usize, depth: usize) -> Result<Value, DecodeError> {
+        // Every item takes at least one byte, so don't trust the length for the allocation
+        let mut items = Vec::with_capacity(len.min(self.data.len() - self.pos));
+        for _ in 0..len {
+            items.push(self.value(depth + 1)?);
+        }
+        Ok(Value::Array(items))
+    }
+
+    fn entries(&mut self, len: usize, depth: usize) -> Result<Value, DecodeError> {
+        let mut entries = Vec::with_capacity(len.min(self.data.len() - self.pos));
+        for _ in 0..len {
+            let key = self.value(depth + 1)?;
+            let value = self.value(depth + 1)?;
+            entries.push((key, value));
+        }
+        Ok(Value::Map(entries))
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, DecodeError> {
+        if depth > MAX_DEPTH {
+            return Err(DecodeError::TooDeep);
+        }
+
+        let marker = self.u8()?;
+        match marker {
+            0x00..=0x7f => Ok(Value::Integer(marker as i64)),
+            0x80..=0x8f => self.entries((marker & 0x0f) as usize, depth),
+            0x90..=0x9f => self.items((marker & 0x0f) as usize, depth),
+            0xa0..=0xbf => self.string((marker & 0x1f) as usize),
+            0xc0 => Ok(Value::Nil),
+            0xc2 => Ok(Value::Boolean(false)),
+            0xc3 => Ok(Value::Boolean(true)),
+            0xc4 | 0xd9 => {
+                let len = self.u8()? as usize;
+                self.string(len)
+            }
+            0xc5 | 0xda => {
+                let len = self.u16()?;
+                self.string(len)
+            }
+            0xc6 | 0xdb => {
+                let len = self.u32()?;
+                self.string(len)
+            }
+            0xca => Ok(Value::Float(f32::from_be_bytes(self.array()?) as f64)),
+            0xcb => Ok(Value::Float(f64::from_be_bytes(self.array()?))),
+            0xcc => Ok(Value::Integer(self.u8()? as i64)),
+            0xcd => Ok(Value::Integer(self.u16()? as i64)),
+            0xce => Ok(Value::Integer(self.u32()? as i64)),
+            0xcf => {
+                let n = u64::from_be_bytes(self.array()?);
+                Ok(i64::try_from(n)
+                    .map(Value::Integer)
+                    .unwrap_or(Value::Float(n as f64)))
+            }
+            0xd0 => Ok(Value::Integer(i8::from_be_bytes(self.array()?) as i64)),
+            0xd1 => Ok(Value::Integer(i16::from_be_bytes(self.array()?) as i64)),
+            0xd2 => Ok(Value::Integer(i32::from_be_bytes(self.array()?) as i64)),
+            0xd3 => Ok(Value::Integer(i64::from_be_bytes(self.array()?))),
+            0xdc => {
+                let len = self.u16()?;
+                self.items(len, depth)
+            }
+            0xdd => {
+                let len = self.u32()?;
+                self.items(len, depth)
+            }
+            0xde => {
+                let len = self.u16()?;
+                self.entries(len, depth)
+            }
+            0xdf => {
+                let len = self.u32()?;
+                self.entries(len, depth)
+            }
+            0xe0..=0xff => Ok(Value::Integer(marker as i8 as i64)),
+            _ => Err(DecodeError::UnsupportedType(marker)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(value: Value) {
+        assert_eq!(decode(&encode(&value)), Ok(value));
+    }
+
+    #[test]
+    fn scalars_round_trip() {
+        round_trip(Value::Nil);
+        round_trip(Value::Boolean(false));
+        round_trip(Value::Boolean(true));
+        for n in [
+            0,
+            0x7f,
+            0x80,
+            -1,
+            -32,
+            -33,
+            i8::MIN as i64,
+            i16::MIN as i64,
+            u16::MAX as i64,
+            i32::MIN as i64,
+            i64::MAX,
+            i64::MIN,
+        ] {
+            round_trip(Value::Integer(n));
+        }
+        round_trip(Value::Float(0.5));
+        round_trip(Value::Float(f64::INFINITY));
+    }
+
+    #[test]
+    fn strings_round_trip() {
+        for len in [0, 31, 32, 255, 256, u16::MAX as usize + 1] {
+            round_trip(Value::String(vec![b'x'; len]));
+        }
+        round_trip(Value::String(b"\0\xff not utf-8".to_vec()));
+    }
+
+    #[test]
+    fn tables_round_trip() {
+        round_trip(Value::Array(vec![]));
+        round_trip(Value::Array((0..16).map(Value::Integer).collect()));
+        round_trip(Value::Array(vec![Value::Nil; u16::MAX as usize + 1]));
+        round_trip(Value::Map(
+            (0..16)
+                .map(|n| (Value::Integer(n), Value::Boolean(n % 2 == 0)))
+                .collect(),
+        ));
+        round_trip(Value::Map(vec![(
+            Value::String(b"nested".to_vec()),
+            Value::Array(vec![Value::Map(vec![]), Value::Float(1.5)]),
+        )]));
+    }
+
+    #[test]
+    fn integers_use_the_smallest_encoding() {
+        assert_eq!(encode(&Value::Integer(5)), [0x05]);
+        assert_eq!(encode(&Value::Integer(-5)), [0xfb]);
+        assert_eq!(encode(&Value::Integer(200)), [0xcc, 0xc8]);
+        assert_eq!(encode(&Value::Integer(0x1234)), [0xcd, 0x12, 0x34]);
+        assert_eq!(encode(&Value::Integer(-100)), [0xd0, 0x9c]);
+        assert_eq!(encode(&Value::Integer(-200)), [0xd1, 0xff, 0x38]);
+    }
+
+    #[test]
+    fn numbers_keep_their_precision() {
+        assert_eq!(Value::from_number(3.0), Value::Integer(3));
+        assert_eq!(Value::from_number(-0.0), Value::Integer(0));
+        assert_eq!(Value::from_number(0.25), Value::Float(0.25));
+        assert_eq!(Value::from_number(1e300), Value::Float(1e300));
+    }
+
+    #[test]
+    fn reads_types_that_are_never_written() {
+        assert_eq!(
+            decode(&[0xcf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+            Ok(Value::Float(u64::MAX as f64))
+        );
+        assert_eq!(
+            decode(&[0xca, 0x3f, 0xc0, 0x00, 0x00]),
+            Ok(Value::Float(1.5))
+        );
+        assert_eq!(
+            decode(&[0xc4, 0x02, 0x00, 0x01]),
+            Ok(Value::String(vec![0, 1]))
+        );
+    }
+
+    #[test]
+    fn truncated_input_is_rejected() {
+        assert_eq!(decode(&[]), Err(DecodeError::UnexpectedEnd));
+        let packet = encode(&Value::Map(vec![(
+            Value::String(b"key".to_vec()),
+            Value::Array(vec![Value::Integer(1000), Value::Float(2.5)]),
+        )]));
+        for len in 0..packet.len() {
+            assert_eq!(
+                decode(&packet[..len]),
+                Err(DecodeError::UnexpectedEnd),
+                "{len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn lengths_beyond_the_data_are_rejected() {
+        // A string of 4 GiB, and an array of as many items, without any of the data
+        assert_eq!(
+            decode(&[0xdb, 0xff, 0xff, 0xff, 0xff]),
+            Err(DecodeError::UnexpectedEnd)
+        );
+        assert_eq!(
+            decode(&[0xdd, 0xff, 0xff, 0xff, 0xff, 0xc0]),
+            Err(DecodeError::UnexpectedEnd)
+        );
+        assert_eq!(decode(&[0x82, 0xc0, 0xc0]), Err(DecodeError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn bad_markers_and_trailing_bytes_are_rejected() {
+        for marker in [0xc1, 0xc7, 0xd4, 0xd8] {
+            assert_eq!(
+                decode(&[marker, 0, 0]),
+                Err(DecodeError::UnsupportedType(marker))
+            );
+        }
+        assert_eq!(decode(&[0xc0, 0xc0]), Err(DecodeError::TrailingBytes));
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize| [vec![0x91; depth], vec![0xc0]].concat();
+        assert!(decode(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(decode(&nested(MAX_DEPTH + 1)), Err(DecodeError::TooDeep));
+        // Deep enough to overflow the stack without the limit
+        assert_eq!(decode(&nested(1_000_000)), Err(DecodeError::TooDeep));
+    }
+}
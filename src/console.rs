@@ -0,0 +1,250 @@
+//! Console commands for inspecting and controlling rooms from the in-game console, without a
+//! mod. The engine passes the words typed after a command as string arguments, and the output
+//! is written to the console log regardless of the log level.
+
+use crate::PLUGIN_NAME;
+use crate::codec::Value;
+use crate::get_plugin;
+use crate::plugin::{Plugin, parse_recipient};
+use crate::protocol::Frame;
+use crate::room::{Room, RoomState};
+use crate::socket::DataChannel;
+use crate::stingray_sdk::{LuaApi, lua_State};
+use std::time::Instant;
+
+pub(crate) fn register(lua: &LuaApi) {
+    lua.add_console_command(
+        "rtc_rooms",
+        rtc_rooms,
+        "List the RTC rooms, with their state and peers",
+        None,
+    );
+    lua.add_console_command(
+        "rtc_peers",
+        rtc_peers,
+        "List the peers connected in an RTC room",
+        Some(("<room>", "the room to list the peers of")),
+    );
+    lua.add_console_command(
+        "rtc_stats",
+        rtc_stats,
+        "Print the network statistics of an RTC room",
+        Some(("<room>", "the room to print the statistics of")),
+    );
+    lua.add_console_command(
+        "rtc_send",
+        rtc_send,
+        "Send a reliable message to peers in an RTC room",
+        Some((
+            "<room> <peer|all> <message>",
+            "send the rest of the line to a PeerId, or to every peer",
+        )),
+    );
+    lua.add_console_command(
+        "rtc_disconnect",
+        rtc_disconnect,
+        "Disconnect from an RTC room",
+        Some(("<room>", "the room to disconnect from")),
+    );
+}
+
+/// The words typed after the command.
+fn args(plugin: &Plugin, l: *mut lua_State) -> Vec<String> {
+    (1..=plugin.lua.gettop(l))
+        .filter_map(|idx| plugin.lua.tolstring(l, idx))
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect()
+}
+
+/// Writes lines to the console log.
+fn print(plugin: &Plugin, lines: impl IntoIterator<Item = String>) {
+    for line in lines {
+        plugin.log.console(PLUGIN_NAME, line);
+    }
+}
+
+/// Reads the room argument of the commands that take nothing else.
+fn room_arg(plugin: &Plugin, l: *mut lua_State, command: &str) -> Option<String> {
+    match args(plugin, l).as_slice() {
+        [room] => Some(room.clone()),
+        _ => {
+            print(plugin, [format!("usage: {command} <room>")]);
+            None
+        }
+    }
+}
+
+/// A room's state, and its peers if it is open.
+fn summary(room: &Room) -> String {
+    match room.state {
+        RoomState::Open => format!("{}, {} peers", room.state.name(), room.peers().len()),
+        state => state.name().to_string(),
+    }
+}
+
+/// Formats a map from `RoomStats::to_value` as indented `key: value` lines.
+fn describe(value: &Value, indent: usize, lines: &mut Vec<String>) {
+    let Value::Map(entries) = value else {
+        return;
+    };
+    for (key, value) in entries {
+        let key = match key {
+            Value::String(key) => String::from_utf8_lossy(key).into_owned(),
+            _ => continue,
+        };
+        let value = match value {
+            Value::Map(_) => {
+                lines.push(format!("{:indent$}{key}:", ""));
+                describe(value, indent + 2, lines);
+                continue;
+            }
+            Value::Integer(n) => n.to_string(),
+            Value::Float(n) => format!("{n:.3}"),
+            _ => continue,
+        };
+        lines.push(format!("{:indent$}{key}: {value}", ""));
+    }
+}
+
+extern "C" fn rtc_rooms(_l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    let mut rooms: Vec<String> = plugin
+        .rooms
+        .blocking_lock()
+        .iter()
+        .map(|(channel, room)| format!("{channel}: {}", summary(room)))
+        .collect();
+    rooms.sort();
+
+    if rooms.is_empty() {
+        print(plugin, ["No rooms".to_string()]);
+    } else {
+        print(plugin, rooms);
+    }
+    0
+}
+
+extern "C" fn rtc_peers(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    let Some(channel) = room_arg(plugin, l, "rtc_peers") else {
+        return 0;
+    };
+
+    let lines = match plugin.rooms.blocking_lock().get(&channel) {
+        Some(room) if room.state == RoomState::Open => {
+            let now = Instant::now();
+            let mut lines = vec![format!("{channel}: {}", summary(room))];
+            for peer in room.peers() {
+                let mut line = format!("  {peer}");
+                if let Some(stats) = room.stats.peers.get(&peer) {
+                    let connected_for = now.duration_since(stats.joined_at).as_secs_f64();
+                    line += &format!(", connected for {connected_for:.0}s");
+                    if let Some(rtt) = stats.rtt {
+                        line += &format!(", rtt {:.0}ms", rtt.as_secs_f64() * 1000.0);
+                    }
+                }
+                if room.peer_info.contains_key(&peer) {
+                    line += ", has presence";
+                }
+                lines.push(line);
+            }
+            lines
+        }
+        Some(room) => vec![format!("{channel}: {}", summary(room))],
+        None => vec![format!("rtc_peers: not connected to channel {channel}")],
+    };
+    print(plugin, lines);
+    0
+}
+
+extern "C" fn rtc_stats(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    let Some(channel) = room_arg(plugin, l, "rtc_stats") else {
+        return 0;
+    };
+
+    let pending_calls = plugin
+        .pending_calls
+        .blocking_lock()
+        .values()
+        .filter(|call| call.channel == channel)
+        .count();
+    let lines = match plugin.rooms.blocking_lock().get(&channel) {
+        Some(room) => {
+            let stats = room
+                .stats
+                .to_value(Instant::now(), room.send_queue.len(), pending_calls);
+            let mut lines = vec![format!("{channel}: {}", summary(room))];
+            describe(&stats, 2, &mut lines);
+            lines
+        }
+        None => vec![format!("rtc_stats: not connected to channel {channel}")],
+    };
+    print(plugin, lines);
+    0
+}
+
+extern "C" fn rtc_send(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    let args = args(plugin, l);
+    let (channel, recipient, message) = match args.as_slice() {
+        [channel, recipient, message @ ..] if !message.is_empty() => (channel, recipient, message),
+        _ => {
+            print(
+                plugin,
+                ["usage: rtc_send <room> <peer|all> <message>".to_string()],
+            );
+            return 0;
+        }
+    };
+
+    let result = parse_recipient(recipient).and_then(|recipient| {
+        // The engine splits the line into words, which are put back together
+        plugin.send_frame(
+            channel.clone(),
+            recipient.clone(),
+            DataChannel::Reliable,
+            Frame::Message(message.join(" ").into_bytes()),
+        )
+    });
+    let line = match result {
+        Ok(true) => format!("Queued message to {recipient} in {channel}"),
+        Ok(false) => {
+            format!("Dropped message to {recipient} in {channel}, over the send rate limit")
+        }
+        Err(err) => format!("rtc_send: {err}"),
+    };
+    print(plugin, [line]);
+    0
+}
+
+extern "C" fn rtc_disconnect(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    let Some(channel) = room_arg(plugin, l, "rtc_disconnect") else {
+        return 0;
+    };
+
+    let line = if plugin.rooms.blocking_lock().contains_key(&channel) {
+        plugin.disconnect_room(&channel);
+        format!("Disconnecting from {channel}")
+    } else {
+        format!("rtc_disconnect: not connected to channel {channel}")
+    };
+    print(plugin, [line]);
+    0
+}
@@ -0,0 +1,155 @@
+//! Room handles, the userdata that `RTC.connect` returns, so that mods can hold rooms as objects
+//! instead of repeating their names. The module functions that take a room accept a handle as
+//! well, and are its methods. Rooms stay connected when their handle is garbage collected, unless
+//! they were connected with `close_on_gc`.
+//!
+//! Handles stay in the Lua state across a hot reload, so they only hold plain data, laid out the
+//! same by every build. Their functions are looked up in a metatable in the registry, which
+//! `register` fills again when the new DLL is set up.
+
+use crate::plugin::LUA_REGISTRYINDEX;
+use crate::stingray_sdk::{LuaApi, LuaType, lua_State};
+use crate::{PLUGIN_NAME, get_plugin};
+use std::ptr;
+
+/// The registry key of the handles' metatable.
+const METATABLE: &str = "RTC.Room";
+
+/// The start of a handle's userdata, which is followed by the name of the room.
+#[repr(C)]
+struct Header {
+    connection_id: u64,
+    len: u64,
+}
+
+pub(crate) struct RoomHandle {
+    pub channel: String,
+    /// The id of the `connect` call that returned the handle. A handle only disconnects the
+    /// room it was returned for, and not one that replaced it.
+    pub connection_id: u64,
+}
+
+/// Creates the handles' metatable, or updates it after a hot reload. `methods` are the module
+/// functions that take the room as their first argument, by the name they have as methods.
+pub(crate) fn register(lua: &LuaApi, methods: &[(&str, extern "C" fn(*mut lua_State) -> i32)]) {
+    let l = lua.get_script_environment_state();
+
+    lua.lib_newmetatable(l, METATABLE);
+    lua.createtable(l, 0, methods.len() as i32 + 1);
+    for (name, f) in methods {
+        lua.pushcfunction(l, *f);
+        lua.setfield(l, -2, *name);
+    }
+    lua.pushcfunction(l, id);
+    lua.setfield(l, -2, "id");
+    lua.setfield(l, -2, "__index");
+    lua.pushcfunction(l, gc);
+    lua.setfield(l, -2, "__gc");
+    lua.pushcfunction(l, tostring);
+    lua.setfield(l, -2, "__tostring");
+    lua.pop(l);
+}
+
+/// Stops handles that are collected after the game shuts down from calling into the plugin,
+/// which may have been unloaded by then.
+pub(crate) fn unregister(lua: &LuaApi) {
+    let l = lua.get_script_environment_state();
+
+    lua.getfield(l, LUA_REGISTRYINDEX, METATABLE);
+    if lua.lua_type(l, -1) == LuaType::Table {
+        lua.pushnil(l);
+        lua.setfield(l, -2, "__gc");
+    }
+    lua.pop(l);
+}
+
+/// Pushes a new handle for the room of `channel`.
+pub(crate) fn push(lua: &LuaApi, l: *mut lua_State, channel: &str, connection_id: u64) {
+    let data = lua
+        .newuserdata(l, size_of::<Header>() + channel.len())
+        .cast::<u8>();
+    let header = Header {
+        connection_id,
+        len: channel.len() as u64,
+    };
+    // Safety: The userdata has room for the header and the name, and Lua aligns it like a
+    // `double`, which is enough for the header.
+    unsafe {
+        data.cast::<Header>().write(header);
+        ptr::copy_nonoverlapping(
+            channel.as_ptr(),
+            data.add(size_of::<Header>()),
+            channel.len(),
+        );
+    }
+
+    lua.getfield(l, LUA_REGISTRYINDEX, METATABLE);
+    lua.setmetatable(l, -2);
+}
+
+/// Reads the handle at `idx`, if the value is one.
+pub(crate) fn read(lua: &LuaApi, l: *mut lua_State, idx: i32) -> Option<RoomHandle> {
+    if lua.lua_type(l, idx) != LuaType::Userdata || !lua.getmetatable(l, idx) {
+        return None;
+    }
+    lua.getfield(l, LUA_REGISTRYINDEX, METATABLE);
+    let is_handle = lua.topointer(l, -1) == lua.topointer(l, -2);
+    lua.pop(l);
+    lua.pop(l);
+    if !is_handle {
+        return None;
+    }
+
+    let data = lua.touserdata(l, idx).cast::<u8>();
+    // Safety: Only `push` sets the metatable on userdata, so that it starts with a header,
+    // followed by as many bytes of the name as it says.
+    unsafe {
+        let header = data.cast::<Header>().read();
+        let channel =
+            std::slice::from_raw_parts(data.add(size_of::<Header>()), header.len as usize);
+        Some(RoomHandle {
+            channel: String::from_utf8_lossy(channel).into_owned(),
+            connection_id: header.connection_id,
+        })
+    }
+}
+
+extern "C" fn id(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    match read(&plugin.lua, l, 1) {
+        Some(handle) => plugin.lua.pushstring(l, handle.channel),
+        None => plugin.lua.pushnil(l),
+    }
+    1
+}
+
+extern "C" fn tostring(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    let name = read(&plugin.lua, l, 1).map_or_else(String::new, |handle| handle.channel);
+    plugin.lua.pushstring(l, format!("RTC room: {name}"));
+    1
+}
+
+extern "C" fn gc(l: *mut lua_State) -> i32 {
+    // Lua may collect handles at any time, even while the plugin is being hot reloaded
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    if let Some(handle) = read(&plugin.lua, l, 1)
+        && plugin.closes_on_gc(&handle)
+    {
+        plugin.log.room(&handle.channel).warning(
+            PLUGIN_NAME,
+            "The room's handle was garbage collected, disconnecting, since it was connected with close_on_gc",
+        );
+        plugin.disconnect_handle(&handle);
+    }
+    0
+}
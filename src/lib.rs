@@ -1,7 +1,26 @@
-use std::ffi::{CString, c_char};
-use std::sync::OnceLock;
+use std::ffi::{CString, c_char, c_void};
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::time::Duration;
+use tokio::runtime::{Handle, Runtime};
 
+mod capture;
+pub mod codec;
+mod console;
+mod handle;
+mod logging;
+mod lua_value;
 mod plugin;
+mod poll;
+pub mod protocol;
+mod rate_limit;
+mod reconnect;
+mod reload;
+mod room;
+mod rpc;
+pub mod socket;
+mod stats;
 mod stingray_sdk;
 
 use plugin::Plugin;
@@ -14,7 +33,71 @@ pub const PLUGIN_NAME: &str = "darktide-plugin-rtc";
 /// The module that Lua functions are assigned to.
 pub const MODULE_NAME: &str = "RTC";
 
-static PLUGIN: OnceLock<Plugin> = OnceLock::new();
+/// How long tasks left on the runtime, like webrtc's, get to finish when it is shut down.
+const RUNTIME_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The runtime that background tasks run on, until `shutdown_game` or `unloaded` shut it down.
+/// It isn't shut down by `start_reload`, since the engine still calls `unloaded` on the old DLL.
+/// A hot reload loads a new DLL with statics of its own, so only the tests, which reload within
+/// one process, use it again after a reload.
+static RUNTIME: Mutex<Option<Runtime>> = Mutex::new(None);
+
+/// The plugin set up by `setup_game` or `finish_reload`, until `start_reload`, `shutdown_game` or
+/// `unloaded`.
+static PLUGIN: AtomicPtr<Plugin> = AtomicPtr::new(ptr::null_mut());
+
+/// The running plugin, if it is set up. Lua can still call the module functions and console
+/// commands after the game shut down, which then return nothing.
+///
+/// The reference must not be held across calls from the engine, since hot reloading and shutting
+/// down free the plugin.
+pub(crate) fn get_plugin() -> Option<&'static Plugin> {
+    // Safety: The pointer is either null, or points to a leaked plugin that is only freed by
+    // `take_plugin`, which the engine doesn't call while the plugin is in use.
+    unsafe { PLUGIN.load(Ordering::Acquire).as_ref() }
+}
+
+fn take_plugin() -> Option<Box<Plugin>> {
+    let plugin = PLUGIN.swap(ptr::null_mut(), Ordering::AcqRel);
+    // Safety: Non-null pointers in `PLUGIN` come from `Box::into_raw`, and have just been
+    // removed from it, so nothing else can take them.
+    (!plugin.is_null()).then(|| unsafe { Box::from_raw(plugin) })
+}
+
+fn set_plugin(plugin: Plugin) -> &'static Plugin {
+    let plugin = Box::into_raw(Box::new(plugin));
+    PLUGIN
+        .compare_exchange(ptr::null_mut(), plugin, Ordering::AcqRel, Ordering::Acquire)
+        .expect("Failed to initalize global plugin object.");
+    // Safety: The pointer was just created from a box.
+    unsafe { &*plugin }
+}
+
+fn runtime_handle() -> Handle {
+    let mut runtime = RUNTIME.lock().unwrap_or_else(|err| err.into_inner());
+    runtime
+        .get_or_insert_with(|| Runtime::new().expect("Failed to start the tokio runtime."))
+        .handle()
+        .clone()
+}
+
+fn new_plugin(get_engine_api: GetApiFunction) -> &'static Plugin {
+    set_plugin(Plugin::new(get_engine_api, runtime_handle()))
+}
+
+/// Stops the plugin's background tasks and frees it. The tasks borrow the plugin, so they must
+/// have stopped first.
+fn drop_plugin(plugin: Box<Plugin>) {
+    plugin.stop_tasks(Duration::ZERO);
+    drop(plugin);
+}
+
+fn shutdown_runtime() {
+    let runtime = RUNTIME.lock().unwrap_or_else(|err| err.into_inner()).take();
+    if let Some(runtime) = runtime {
+        runtime.shutdown_timeout(RUNTIME_SHUTDOWN_TIMEOUT);
+    }
+}
 
 #[unsafe(no_mangle)]
 pub extern "C" fn get_name() -> *const c_char {
@@ -24,27 +107,73 @@ pub extern "C" fn get_name() -> *const c_char {
 
 #[unsafe(no_mangle)]
 pub extern "C" fn setup_game(get_engine_api: GetApiFunction) {
-    let plugin = Plugin::new(get_engine_api);
+    let plugin = new_plugin(get_engine_api);
     plugin.setup_game();
-    PLUGIN
-        .set(plugin)
-        .expect("Failed to initalize global plugin object.");
+}
+
+/// Called on the old DLL before it is swapped out. Hands the plugin's state over to
+/// `finish_reload` in the new DLL.
+#[unsafe(no_mangle)]
+pub extern "C" fn start_reload(_get_engine_api: GetApiFunction) -> *mut c_void {
+    let Some(plugin) = take_plugin() else {
+        return ptr::null_mut();
+    };
+    let state = plugin.start_reload().encode();
+    drop_plugin(plugin);
+    reload::into_raw(&state)
+}
+
+/// Called on the new DLL after a hot reload, instead of `setup_game`.
+///
+/// # Safety
+/// `state` must be null or the pointer returned by `start_reload`, and is freed by this.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn finish_reload(get_engine_api: GetApiFunction, state: *mut c_void) {
+    let plugin = new_plugin(get_engine_api);
+    plugin.setup_game();
+
+    // Safety: The engine passes the pointer returned by `start_reload` as is.
+    let Some(state) = (unsafe { reload::from_raw(state) }) else {
+        return;
+    };
+    match reload::ReloadState::decode(&state) {
+        Ok(state) => plugin.finish_reload(state),
+        Err(err) => plugin.log.error(
+            PLUGIN_NAME,
+            format!("Hot reload state can't be read, rooms need to be connected again: {err}"),
+        ),
+    }
+}
+
+/// Called just before the DLL is unloaded.
+#[unsafe(no_mangle)]
+pub extern "C" fn unloaded() {
+    // The Lua state may be gone already, so callbacks are left alone
+    if let Some(plugin) = take_plugin() {
+        drop_plugin(plugin);
+    }
+    // No code of this DLL may run after it is unloaded
+    shutdown_runtime();
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn shutdown_game() {
-    // Safety: The engine ensures that `setup_game` was called before this, so `PLUGIN` has been
-    // initialized.
-    let plugin = unsafe { PLUGIN.get().unwrap_unchecked() };
-    plugin.shutdown_game();
+    if let Some(plugin) = get_plugin() {
+        plugin.shutdown_game();
+    }
+    // Only freed once it is done, since Lua callbacks run while shutting down use it
+    if let Some(plugin) = take_plugin() {
+        drop_plugin(plugin);
+    }
+    // The game is exiting, and the engine doesn't always unload plugins first
+    shutdown_runtime();
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn update_game(dt: f32) {
-    // Safety: The engine ensures that `setup_game` was called before this, so `PLUGIN` has been
-    // initialized.
-    let plugin = unsafe { PLUGIN.get().unwrap_unchecked() };
-    plugin.update_game(dt);
+    if let Some(plugin) = get_plugin() {
+        plugin.update_game(dt);
+    }
 }
 
 #[unsafe(no_mangle)]
@@ -52,6 +181,9 @@ pub extern "C" fn get_plugin_api(id: PluginApiID) -> *mut PluginApi {
     if id == PluginApiID::PLUGIN_API_ID {
         let api = PluginApi {
             get_name: Some(get_name),
+            start_reload: Some(start_reload),
+            finish_reload: Some(finish_reload),
+            unloaded: Some(unloaded),
             setup_game: Some(setup_game),
             update_game: Some(update_game),
             shutdown_game: Some(shutdown_game),
@@ -0,0 +1,203 @@
+//! The plugin's log, filtered by level before it reaches the engine's console log.
+//!
+//! The engine only has info, warning and error logs, so debug lines are logged as info. Message
+//! contents are only logged at the debug level, truncated to `payload_limit` bytes.
+
+use crate::stingray_sdk::LoggingApi;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+/// How many bytes of a message are logged, unless changed with `RTC.set_log_payload_limit`.
+pub(crate) const DEFAULT_PAYLOAD_LIMIT: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum LogLevel {
+    Debug,
+    Info,
+    Warning,
+    Error,
+    /// Only used as a threshold, to log nothing.
+    Off,
+}
+
+impl LogLevel {
+    pub const ALL: [LogLevel; 5] = [
+        LogLevel::Debug,
+        LogLevel::Info,
+        LogLevel::Warning,
+        LogLevel::Error,
+        LogLevel::Off,
+    ];
+
+    /// The name used for the level in Lua.
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Warning => "warn",
+            LogLevel::Error => "error",
+            LogLevel::Off => "off",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|level| level.name() == name)
+    }
+}
+
+pub(crate) struct Logger {
+    api: LoggingApi,
+    /// The lowest level that is logged, as a `LogLevel` index.
+    level: AtomicU8,
+    /// Levels that replace `level` for lines about a room.
+    room_levels: Mutex<HashMap<String, LogLevel>>,
+    payload_limit: AtomicUsize,
+}
+
+impl Logger {
+    pub fn new(api: LoggingApi) -> Self {
+        Self {
+            api,
+            level: AtomicU8::new(LogLevel::Info as u8),
+            room_levels: Mutex::new(HashMap::new()),
+            payload_limit: AtomicUsize::new(DEFAULT_PAYLOAD_LIMIT),
+        }
+    }
+
+    pub fn level(&self) -> LogLevel {
+        LogLevel::ALL[self.level.load(Ordering::Relaxed) as usize]
+    }
+
+    pub fn set_level(&self, level: LogLevel) {
+        self.level.store(level as u8, Ordering::Relaxed);
+    }
+
+    /// Sets the level for lines about `channel`, or makes them use the global level again.
+    pub fn set_room_level(&self, channel: &str, level: Option<LogLevel>) {
+        let mut room_levels = self.room_levels();
+        match level {
+            Some(level) => room_levels.insert(channel.to_string(), level),
+            None => room_levels.remove(channel),
+        };
+    }
+
+    pub fn room_levels(&self) -> std::sync::MutexGuard<'_, HashMap<String, LogLevel>> {
+        // The map can't be left inconsistent, so a panic while it was locked doesn't matter
+        self.room_levels
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub fn payload_limit(&self) -> usize {
+        self.payload_limit.load(Ordering::Relaxed)
+    }
+
+    pub fn set_payload_limit(&self, limit: usize) {
+        self.payload_limit.store(limit, Ordering::Relaxed);
+    }
+
+    /// Whether a line at `level`, about `channel` if given, would be logged.
+    pub fn enabled(&self, level: LogLevel, channel: Option<&str>) -> bool {
+        let threshold = channel
+            .and_then(|channel| self.room_levels().get(channel).copied())
+            .unwrap_or_else(|| self.level());
+        level != LogLevel::Off && level >= threshold
+    }
+
+    fn log(
+        &self,
+        level: LogLevel,
+        channel: Option<&str>,
+        system: impl Into<Vec<u8>>,
+        message: impl Into<Vec<u8>>,
+    ) {
+        if !self.enabled(level, channel) {
+            return;
+        }
+        let message = match channel {
+            Some(channel) => {
+                let mut line = format!("[Channel: {channel}] ").into_bytes();
+                line.extend(message.into());
+                line
+            }
+            None => message.into(),
+        };
+        match level {
+            LogLevel::Debug | LogLevel::Info => self.api.info(system, message),
+            LogLevel::Warning => self.api.warning(system, message),
+            LogLevel::Error | LogLevel::Off => self.api.error(system, message),
+        }
+    }
+
+    pub fn info(&self, system: impl Into<Vec<u8>>, message: impl Into<Vec<u8>>) {
+        self.log(LogLevel::Info, None, system, message);
+    }
+
+    pub fn warning(&self, system: impl Into<Vec<u8>>, message: impl Into<Vec<u8>>) {
+        self.log(LogLevel::Warning, None, system, message);
+    }
+
+    pub fn error(&self, system: impl Into<Vec<u8>>, message: impl Into<Vec<u8>>) {
+        self.log(LogLevel::Error, None, system, message);
+    }
+
+    /// Writes the output of a console command, which isn't filtered, since it was asked for.
+    pub fn console(&self, system: impl Into<Vec<u8>>, message: impl Into<Vec<u8>>) {
+        self.api.info(system, message);
+    }
+
+    /// Logs lines about `channel`, which are prefixed with it and filtered by its level.
+    pub fn room<'a>(&'a self, channel: &'a str) -> RoomLog<'a> {
+        RoomLog {
+            logger: self,
+            channel,
+        }
+    }
+
+    /// Formats a message for the log, cut off after `payload_limit` bytes. With a limit of 0,
+    /// only the size is logged.
+    pub fn payload(&self, payload: &[u8]) -> String {
+        let limit = self.payload_limit();
+        if limit == 0 {
+            return format!("<{} bytes>", payload.len());
+        }
+        let shown = String::from_utf8_lossy(&payload[..payload.len().min(limit)]);
+        if payload.len() > limit {
+            format!("{shown:?}... ({} bytes)", payload.len())
+        } else {
+            format!("{shown:?}")
+        }
+    }
+}
+
+pub(crate) struct RoomLog<'a> {
+    logger: &'a Logger,
+    channel: &'a str,
+}
+
+impl RoomLog<'_> {
+    pub fn enabled(&self, level: LogLevel) -> bool {
+        self.logger.enabled(level, Some(self.channel))
+    }
+
+    pub fn debug(&self, system: impl Into<Vec<u8>>, message: impl Into<Vec<u8>>) {
+        self.logger
+            .log(LogLevel::Debug, Some(self.channel), system, message);
+    }
+
+    pub fn info(&self, system: impl Into<Vec<u8>>, message: impl Into<Vec<u8>>) {
+        self.logger
+            .log(LogLevel::Info, Some(self.channel), system, message);
+    }
+
+    pub fn warning(&self, system: impl Into<Vec<u8>>, message: impl Into<Vec<u8>>) {
+        self.logger
+            .log(LogLevel::Warning, Some(self.channel), system, message);
+    }
+
+    pub fn error(&self, system: impl Into<Vec<u8>>, message: impl Into<Vec<u8>>) {
+        self.logger
+            .log(LogLevel::Error, Some(self.channel), system, message);
+    }
+}
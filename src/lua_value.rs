@@ -0,0 +1,158 @@
+use crate::codec::{MAX_DEPTH, Value};
+use crate::stingray_sdk::{LuaApi, LuaType, lua_State};
+use std::os::raw::c_void;
+
+/// Reads the value at `idx` of the Lua stack, including nested tables.
+/// Functions, userdata, threads and tables that contain themselves can't be read, and result in
+/// an error naming the offending field.
+pub(crate) fn read_value(lua: &LuaApi, l: *mut lua_State, idx: i32) -> Result<Value, String> {
+    let top = lua.gettop(l);
+    // Convert relative indices, since reading tables pushes onto the stack
+    let idx = if idx < 0 { top + idx + 1 } else { idx };
+
+    let mut parents = Vec::new();
+    let value = read(lua, l, idx, "value", &mut parents);
+
+    lua.settop(l, top);
+    value
+}
+
+fn read(
+    lua: &LuaApi,
+    l: *mut lua_State,
+    idx: i32,
+    path: &str,
+    parents: &mut Vec<*const c_void>,
+) -> Result<Value, String> {
+    match lua.lua_type(l, idx) {
+        LuaType::Nil => Ok(Value::Nil),
+        LuaType::Boolean => Ok(Value::Boolean(lua.toboolean(l, idx))),
+        LuaType::Number => Ok(Value::from_number(lua.tonumber(l, idx))),
+        LuaType::String => Ok(Value::String(
+            lua.tolstring(l, idx).unwrap_or_default().to_vec(),
+        )),
+        LuaType::Table => read_table(lua, l, idx, path, parents),
+        other => Err(format!(
+            "{path} is of type {}, which can't be serialized",
+            other.to_string().to_lowercase()
+        )),
+    }
+}
+
+fn read_table(
+    lua: &LuaApi,
+    l: *mut lua_State,
+    idx: i32,
+    path: &str,
+    parents: &mut Vec<*const c_void>,
+) -> Result<Value, String> {
+    let table = lua.topointer(l, idx);
+    if parents.contains(&table) {
+        return Err(format!("{path} contains a cycle"));
+    }
+    if parents.len() >= MAX_DEPTH {
+        return Err(format!("{path} is nested deeper than {MAX_DEPTH} tables"));
+    }
+    if !lua.checkstack(l, 2) {
+        return Err(format!("{path} is nested too deep for the Lua stack"));
+    }
+    parents.push(table);
+
+    let mut entries = Vec::new();
+    lua.pushnil(l);
+    while lua.next(l, idx) {
+        let key_idx = lua.gettop(l) - 1;
+        // Only plain keys. Reading a number key as a string would also confuse `next`.
+        let key = match lua.lua_type(l, key_idx) {
+            LuaType::Boolean | LuaType::Number | LuaType::String => {
+                read(lua, l, key_idx, path, parents)?
+            }
+            other => {
+                return Err(format!(
+                    "{path} has a key of type {}, which can't be serialized",
+                    other.to_string().to_lowercase()
+                ));
+            }
+        };
+
+        let value = read(lua, l, key_idx + 1, &field_path(path, &key), parents)?;
+        entries.push((key, value));
+
+        // Pop the value, keeping the key for the next iteration
+        lua.pop(l);
+    }
+
+    parents.pop();
+    Ok(into_array(entries))
+}
+
+/// Tables with exactly the keys `1..=n` are sent as arrays, which are smaller and faster to read.
+fn into_array(mut entries: Vec<(Value, Value)>) -> Value {
+    entries.sort_by_key(|(key, _)| match key {
+        Value::Integer(i) => *i,
+        _ => i64::MAX,
+    });
+
+    let is_array = !entries.is_empty()
+        && entries
+            .iter()
+            .enumerate()
+            .all(|(i, (key, _))| *key == Value::Integer(i as i64 + 1));
+
+    if is_array {
+        Value::Array(entries.into_iter().map(|(_, value)| value).collect())
+    } else {
+        Value::Map(entries)
+    }
+}
+
+fn field_path(path: &str, key: &Value) -> String {
+    match key {
+        Value::String(key) => format!("{path}.{}", String::from_utf8_lossy(key)),
+        Value::Integer(key) => format!("{path}[{key}]"),
+        Value::Float(key) => format!("{path}[{key}]"),
+        Value::Boolean(key) => format!("{path}[{key}]"),
+        _ => format!("{path}[?]"),
+    }
+}
+
+/// Pushes `value` onto the Lua stack, creating tables for arrays and maps.
+/// Map entries with keys Lua can't index by (`nil` and NaN) are skipped.
+pub(crate) fn push_value(lua: &LuaApi, l: *mut lua_State, value: &Value) -> Result<(), String> {
+    match value {
+        Value::Nil => lua.pushnil(l),
+        Value::Boolean(b) => lua.pushboolean(l, *b),
+        Value::Integer(n) => lua.pushnumber(l, *n as f64),
+        Value::Float(n) => lua.pushnumber(l, *n),
+        Value::String(s) => lua.pushlstring(l, s),
+        Value::Array(items) => {
+            if !lua.checkstack(l, 2) {
+                return Err("table is nested too deep for the Lua stack".to_string());
+            }
+            lua.createtable(l, items.len() as i32, 0);
+            for (i, item) in items.iter().enumerate() {
+                push_value(lua, l, item)?;
+                lua.rawseti(l, -2, i as i32 + 1);
+            }
+        }
+        Value::Map(entries) => {
+            if !lua.checkstack(l, 3) {
+                return Err("table is nested too deep for the Lua stack".to_string());
+            }
+            lua.createtable(l, 0, entries.len() as i32);
+            for (key, value) in entries {
+                let valid_key = match key {
+                    Value::Nil => false,
+                    Value::Float(n) => !n.is_nan(),
+                    _ => true,
+                };
+                if valid_key {
+                    push_value(lua, l, key)?;
+                    push_value(lua, l, value)?;
+                    lua.rawset(l, -3);
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,33 +1,490 @@
+use crate::capture::{self, Capture, Event, Replay};
+use crate::codec::{self, Value};
+use crate::console;
+use crate::handle::{self, RoomHandle};
+use crate::logging::{LogLevel, Logger};
+use crate::lua_value::{push_value, read_value};
+use crate::poll::{DEFAULT_EVENT_BUFFER, MAX_EVENT_BUFFER, PollEvent};
+use crate::protocol::{Frame, MAX_METHOD_LEN, MAX_PRESENCE_LEN};
+use crate::rate_limit::{Direction, Limit, RateLimits};
+use crate::reconnect::{ConnectionState, ReconnectPolicy};
+use crate::reload::ReloadState;
+use crate::room::{QueuedMessage, Room, RoomConfig, RoomState};
+use crate::rpc::{CallError, DEFAULT_TIMEOUT, MAX_TIMEOUT, PendingCall};
+use crate::socket::{DEFAULT_SIGNALING_URL, DataChannel, build_socket};
 use crate::stingray_sdk::{GetApiFunction, LoggingApi, LuaApi, LuaType, lua_State};
-use crate::{MODULE_NAME, PLUGIN, PLUGIN_NAME};
+use crate::{MODULE_NAME, PLUGIN_NAME, get_plugin};
 use futures::{FutureExt, select};
-use matchbox_socket::{PeerId, PeerState, WebRtcSocket};
-use std::collections::HashMap;
+use matchbox_socket::{MessageLoopFuture, PeerId, PeerState, RtcIceServerConfig};
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::path::Path;
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 use tokio::time;
 use uuid::Uuid;
 
-const LUA_REGISTRYINDEX: i32 = -10000;
+pub(crate) const LUA_REGISTRYINDEX: i32 = -10000;
+const LUA_GLOBALSINDEX: i32 = -10002;
+
+/// How long the message loops get to pass messages flushed on shutdown to the data channels,
+/// before the sockets are closed.
+const SHUTDOWN_FLUSH_DELAY: Duration = Duration::from_millis(100);
+/// How long background tasks get to leave their rooms after their sockets are closed, before
+/// they are cancelled.
+pub(crate) const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Something that happened in a room, to be passed to Lua once the rooms are unlocked.
+enum RoomEvent {
+    State(ConnectionState),
+    PeerJoined(PeerId),
+    PeerLeft(PeerId),
+    Packet {
+        peer: PeerId,
+        data_channel: DataChannel,
+        packet: Box<[u8]>,
+    },
+}
 
 pub(crate) struct Plugin {
-    pub log: Arc<LoggingApi>,
+    pub log: Arc<Logger>,
     pub lua: LuaApi,
-    pub tokio_runtime: tokio::runtime::Runtime,
-    pub sockets: Arc<Mutex<HashMap<String, WebRtcSocket>>>,
+    pub tokio_runtime: tokio::runtime::Handle,
+    /// Every room that `connect` was called for, until it is disconnected.
+    pub rooms: Arc<Mutex<HashMap<String, Room>>>,
     pub on_peer_connected_callbacks: Arc<Mutex<HashMap<String, i32>>>,
     pub on_message_callbacks: Arc<Mutex<HashMap<String, i32>>>,
     pub on_peer_disconnected_callbacks: Arc<Mutex<HashMap<String, i32>>>,
-    pub send_queue: Arc<Mutex<HashMap<String, Vec<(String, String)>>>>,
-    pub disconnect_queue: Arc<Mutex<Vec<String>>>,
+    pub signaling_url: Arc<Mutex<String>>,
+    pub on_connection_state_callbacks: Arc<Mutex<HashMap<String, i32>>>,
+    pub on_table_message_callbacks: Arc<Mutex<HashMap<String, i32>>>,
+    pub on_peer_updated_callbacks: Arc<Mutex<HashMap<String, i32>>>,
+    pub on_rate_limited_callbacks: Arc<Mutex<HashMap<String, i32>>>,
+    pub next_connection_id: AtomicU64,
+    /// Handlers registered with `register_method`, per channel and method name.
+    pub rpc_methods: Arc<Mutex<HashMap<String, HashMap<String, i32>>>>,
+    /// Calls made with `call` that are waiting for a reply, by request id.
+    pub pending_calls: Arc<Mutex<HashMap<u32, PendingCall>>>,
+    pub next_call_id: AtomicU32,
+    /// The background tasks started by `connect`, which may still be running.
+    pub tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    /// Capture files started with `start_capture`, per channel.
+    pub captures: Arc<Mutex<HashMap<String, Capture>>>,
+}
+
+#[derive(Default)]
+struct ConnectOptions {
+    signaling_url: Option<String>,
+    reconnect: Option<ReconnectPolicy>,
+    ice_servers: Option<RtcIceServerConfig>,
+    replay: Option<String>,
+    presence: Option<Vec<u8>>,
+    rate_limits: RateLimits,
+    event_buffer: Option<usize>,
+    close_on_gc: bool,
+    on_connection_state: Option<i32>,
+    on_table_message: Option<i32>,
+    on_peer_updated: Option<i32>,
+    on_rate_limited: Option<i32>,
+}
+
+/// Checks that `url` points to a websocket server and normalizes it to have no trailing slash, so
+/// that the room name can be appended.
+fn validate_signaling_url(url: &str) -> Result<String, String> {
+    let url = url.trim().trim_end_matches('/');
+    let host = url
+        .strip_prefix("wss://")
+        .or_else(|| url.strip_prefix("ws://"))
+        .ok_or_else(|| format!("signaling url {url:?} must start with ws:// or wss://"))?;
+
+    if host.is_empty() {
+        return Err(format!("signaling url {url:?} is missing a host"));
+    }
+
+    Ok(url.to_string())
+}
+
+/// Checks that `recipient` is "all" or a PeerId, and normalizes the PeerId.
+pub(crate) fn parse_recipient(recipient: &str) -> Result<String, String> {
+    if recipient == "all" {
+        return Ok("all".to_string());
+    }
+    Uuid::parse_str(recipient)
+        .map(|uuid| uuid.to_string())
+        .map_err(|_| format!("recipient {recipient} is not \"all\" or a valid Uuid"))
+}
+
+/// Reads the number in field `key` of the table at `idx`, if it is set.
+fn get_number_field(
+    plugin: &Plugin,
+    l: *mut lua_State,
+    idx: i32,
+    key: &str,
+) -> Result<Option<f64>, String> {
+    plugin.lua.getfield(l, idx, key);
+    let value = match plugin.lua.lua_type(l, -1) {
+        LuaType::Nil => Ok(None),
+        LuaType::Number => Ok(Some(plugin.lua.tonumber(l, -1))),
+        _ => Err(format!("{key} should be a number")),
+    };
+    plugin.lua.pop(l);
+    value
+}
+
+/// Reads the string in field `key` of the table at `idx`, if it is set.
+fn get_string_field(
+    plugin: &Plugin,
+    l: *mut lua_State,
+    idx: i32,
+    key: &str,
+) -> Result<Option<String>, String> {
+    plugin.lua.getfield(l, idx, key);
+    let value = match plugin.lua.lua_type(l, -1) {
+        LuaType::Nil => Ok(None),
+        LuaType::String => Ok(plugin
+            .lua
+            .tolstring(l, -1)
+            .map(|value| String::from_utf8_lossy(value).into_owned())),
+        _ => Err(format!("{key} should be a string")),
+    };
+    plugin.lua.pop(l);
+    value
+}
+
+/// Reads the presence record at `idx`, which is a table or nil, and encodes it.
+fn read_presence(plugin: &Plugin, l: *mut lua_State, idx: i32) -> Result<Option<Vec<u8>>, String> {
+    match plugin.lua.lua_type(l, idx) {
+        LuaType::None | LuaType::Nil => Ok(None),
+        LuaType::Table => {
+            let presence = codec::encode(&read_value(&plugin.lua, l, idx)?);
+            if presence.len() > MAX_PRESENCE_LEN {
+                return Err(format!(
+                    "presence is {} bytes long, but can be at most {MAX_PRESENCE_LEN}",
+                    presence.len()
+                ));
+            }
+            Ok(Some(presence))
+        }
+        _ => Err("presence should be a table".to_string()),
+    }
+}
+
+/// Checks that `url` is a STUN or TURN server url, and returns whether it is a TURN server.
+fn validate_ice_url(url: &str) -> Result<bool, String> {
+    match url.split_once(':') {
+        Some(("stun" | "stuns", host)) if !host.is_empty() => Ok(false),
+        Some(("turn" | "turns", host)) if !host.is_empty() => Ok(true),
+        _ => Err(format!(
+            "ice server url {url:?} must start with stun:, stuns:, turn: or turns:"
+        )),
+    }
+}
+
+/// Reads the `ice_servers` option, which replaces matchbox's default STUN server.
+fn read_ice_servers(
+    plugin: &Plugin,
+    l: *mut lua_State,
+    idx: i32,
+) -> Result<Option<RtcIceServerConfig>, String> {
+    plugin.lua.getfield(l, idx, "ice_servers");
+    let config = match plugin.lua.lua_type(l, -1) {
+        LuaType::Nil => Ok(None),
+        LuaType::Table => {
+            let table = plugin.lua.gettop(l);
+            (|| {
+                // Either a single url, or an array of them
+                plugin.lua.getfield(l, table, "urls");
+                let urls =
+                    match plugin.lua.lua_type(l, -1) {
+                        LuaType::String => read_value(&plugin.lua, l, -1).map(|url| vec![url]),
+                        LuaType::Table => match read_value(&plugin.lua, l, -1) {
+                            Ok(Value::Array(urls)) => Ok(urls),
+                            _ => Err("options.ice_servers.urls should be an array of strings"
+                                .to_string()),
+                        },
+                        _ => Err(
+                            "options.ice_servers.urls should be a string or an array of strings"
+                                .to_string(),
+                        ),
+                    };
+                plugin.lua.pop(l);
+
+                let urls = urls?
+                    .into_iter()
+                    .map(|url| match url {
+                        Value::String(url) => Ok(String::from_utf8_lossy(&url).trim().to_string()),
+                        _ => {
+                            Err("options.ice_servers.urls should be an array of strings"
+                                .to_string())
+                        }
+                    })
+                    .collect::<Result<Vec<String>, String>>()?;
+
+                let mut has_turn = false;
+                for url in &urls {
+                    has_turn |= validate_ice_url(url)?;
+                }
+
+                let username = get_string_field(plugin, l, table, "username")
+                    .map_err(|err| format!("options.ice_servers.{err}"))?;
+                let credential = get_string_field(plugin, l, table, "credential")
+                    .map_err(|err| format!("options.ice_servers.{err}"))?;
+                if has_turn && (username.is_none() || credential.is_none()) {
+                    return Err(
+                        "options.ice_servers.username and credential are required for TURN servers"
+                            .to_string(),
+                    );
+                }
+
+                Ok(Some(RtcIceServerConfig {
+                    urls,
+                    username,
+                    credential,
+                }))
+            })()
+        }
+        _ => Err("options.ice_servers should be a table".to_string()),
+    };
+    plugin.lua.pop(l);
+    config
+}
+
+/// Reads the `reconnect` option, which is either a boolean, or a table overriding fields of the
+/// default policy.
+fn read_reconnect_policy(
+    plugin: &Plugin,
+    l: *mut lua_State,
+    idx: i32,
+) -> Result<Option<ReconnectPolicy>, String> {
+    plugin.lua.getfield(l, idx, "reconnect");
+    let policy = match plugin.lua.lua_type(l, -1) {
+        LuaType::Nil => Ok(None),
+        LuaType::Boolean => Ok(plugin.lua.toboolean(l, -1).then(ReconnectPolicy::default)),
+        LuaType::Table => {
+            let table = plugin.lua.gettop(l);
+            let mut policy = ReconnectPolicy::default();
+            let seconds = |key: &str| -> Result<Option<Duration>, String> {
+                match get_number_field(plugin, l, table, key)? {
+                    // Too long to represent is as good as forever
+                    Some(secs) if secs.is_finite() && secs >= 0.0 => Ok(Some(
+                        Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX),
+                    )),
+                    Some(_) => Err(format!("{key} should be a non-negative number")),
+                    None => Ok(None),
+                }
+            };
+
+            (|| {
+                if let Some(max_attempts) = get_number_field(plugin, l, table, "max_attempts")? {
+                    if max_attempts < 0.0 {
+                        return Err("max_attempts should be a non-negative number".to_string());
+                    }
+                    policy.max_attempts = max_attempts as u32;
+                }
+                if let Some(delay) = seconds("initial_delay")? {
+                    policy.initial_delay = delay;
+                }
+                if let Some(delay) = seconds("max_delay")? {
+                    policy.max_delay = delay;
+                }
+                if let Some(jitter) = get_number_field(plugin, l, table, "jitter")? {
+                    if !(0.0..=1.0).contains(&jitter) {
+                        return Err("jitter should be between 0 and 1".to_string());
+                    }
+                    policy.jitter = jitter;
+                }
+                Ok(Some(policy))
+            })()
+            .map_err(|err| format!("options.reconnect.{err}"))
+        }
+        _ => Err("options.reconnect should be a boolean or a table".to_string()),
+    };
+    plugin.lua.pop(l);
+    policy
+}
+
+/// Reads the limit in field `key` of the `rate_limits` table at `idx`, which is either a number
+/// of messages per second, or a table with the `rate` and how many messages are allowed at once
+/// (`burst`). The burst defaults to a second's worth of messages.
+fn read_limit(
+    plugin: &Plugin,
+    l: *mut lua_State,
+    idx: i32,
+    key: &str,
+) -> Result<Option<Limit>, String> {
+    let positive = |value: Option<f64>, field: &str| match value {
+        Some(value) if value.is_finite() && value > 0.0 => Ok(value),
+        _ => Err(format!("rate_limits.{field} should be a positive number")),
+    };
+
+    plugin.lua.getfield(l, idx, key);
+    let limit = match plugin.lua.lua_type(l, -1) {
+        LuaType::Nil => Ok(None),
+        LuaType::Number => positive(Some(plugin.lua.tonumber(l, -1)), key).map(|rate| {
+            Some(Limit {
+                rate,
+                burst: rate.max(1.0),
+            })
+        }),
+        LuaType::Table => {
+            let table = plugin.lua.gettop(l);
+            (|| {
+                let rate = get_number_field(plugin, l, table, "rate")
+                    .map_err(|err| format!("rate_limits.{key}.{err}"))?;
+                let rate = positive(rate, &format!("{key}.rate"))?;
+                let burst = match get_number_field(plugin, l, table, "burst")
+                    .map_err(|err| format!("rate_limits.{key}.{err}"))?
+                {
+                    Some(burst) if burst.is_finite() && burst >= 1.0 => burst.floor(),
+                    Some(_) => {
+                        return Err(format!("rate_limits.{key}.burst should be at least 1"));
+                    }
+                    None => rate.max(1.0),
+                };
+                Ok(Some(Limit { rate, burst }))
+            })()
+        }
+        _ => Err(format!("rate_limits.{key} should be a number or a table")),
+    };
+    plugin.lua.pop(l);
+    limit
+}
+
+/// Reads the `rate_limits` option.
+fn read_rate_limits(plugin: &Plugin, l: *mut lua_State, idx: i32) -> Result<RateLimits, String> {
+    plugin.lua.getfield(l, idx, "rate_limits");
+    let limits = match plugin.lua.lua_type(l, -1) {
+        LuaType::Nil => Ok(RateLimits::default()),
+        LuaType::Table => {
+            let table = plugin.lua.gettop(l);
+            (|| -> Result<RateLimits, String> {
+                Ok(RateLimits {
+                    receive_per_peer: read_limit(plugin, l, table, "receive_per_peer")?,
+                    receive_per_room: read_limit(plugin, l, table, "receive_per_room")?,
+                    send_per_peer: read_limit(plugin, l, table, "send_per_peer")?,
+                    send_per_room: read_limit(plugin, l, table, "send_per_room")?,
+                })
+            })()
+            .map_err(|err| format!("options.{err}"))
+        }
+        _ => Err("options.rate_limits should be a table".to_string()),
+    };
+    plugin.lua.pop(l);
+    limits
+}
+
+/// Reads the optional options table passed to `connect` at `idx`.
+/// Callbacks are only referenced once all other options are valid, so nothing leaks on error.
+fn read_connect_options(
+    plugin: &Plugin,
+    l: *mut lua_State,
+    idx: i32,
+) -> Result<ConnectOptions, String> {
+    match plugin.lua.lua_type(l, idx) {
+        LuaType::None | LuaType::Nil => return Ok(ConnectOptions::default()),
+        LuaType::Table => {}
+        _ => return Err("options should be a table".to_string()),
+    }
+
+    plugin.lua.getfield(l, idx, "signaling_url");
+    let signaling_url = match plugin.lua.lua_type(l, -1) {
+        LuaType::Nil => Ok(None),
+        LuaType::String => {
+            let url = plugin
+                .lua
+                .tolstring(l, -1)
+                .map(|url| String::from_utf8_lossy(url).into_owned())
+                .unwrap_or_default();
+            validate_signaling_url(&url).map(Some)
+        }
+        _ => Err("options.signaling_url should be a string".to_string()),
+    };
+    plugin.lua.pop(l);
+    let signaling_url = signaling_url?;
+
+    let reconnect = read_reconnect_policy(plugin, l, idx)?;
+    let ice_servers = read_ice_servers(plugin, l, idx)?;
+    let replay =
+        get_string_field(plugin, l, idx, "replay").map_err(|err| format!("options.{err}"))?;
+    plugin.lua.getfield(l, idx, "presence");
+    let presence = read_presence(plugin, l, -1);
+    plugin.lua.pop(l);
+    let presence = presence.map_err(|err| format!("options.{err}"))?;
+    let rate_limits = read_rate_limits(plugin, l, idx)?;
+    let event_buffer = match get_number_field(plugin, l, idx, "event_buffer")
+        .map_err(|err| format!("options.{err}"))?
+    {
+        Some(size) if (1.0..=MAX_EVENT_BUFFER as f64).contains(&size) => Some(size as usize),
+        Some(_) => {
+            return Err(format!(
+                "options.event_buffer should be between 1 and {MAX_EVENT_BUFFER}"
+            ));
+        }
+        None => None,
+    };
+    plugin.lua.getfield(l, idx, "close_on_gc");
+    let close_on_gc = match plugin.lua.lua_type(l, -1) {
+        LuaType::Nil => Ok(false),
+        LuaType::Boolean => Ok(plugin.lua.toboolean(l, -1)),
+        _ => Err("options.close_on_gc should be a boolean".to_string()),
+    };
+    plugin.lua.pop(l);
+    let close_on_gc = close_on_gc?;
+
+    // Only reference the callbacks once everything else is known to be valid
+    const CALLBACKS: [&str; 4] = [
+        "on_connection_state",
+        "on_table_message",
+        "on_peer_updated",
+        "on_rate_limited",
+    ];
+    for key in CALLBACKS {
+        plugin.lua.getfield(l, idx, key);
+        let lua_type = plugin.lua.lua_type(l, -1);
+        plugin.lua.pop(l);
+        if !matches!(lua_type, LuaType::Nil | LuaType::Function) {
+            return Err(format!("options.{key} should be a function"));
+        }
+    }
+    let [
+        on_connection_state,
+        on_table_message,
+        on_peer_updated,
+        on_rate_limited,
+    ] = CALLBACKS.map(|key| {
+        plugin.lua.getfield(l, idx, key);
+        if plugin.lua.lua_type(l, -1) == LuaType::Function {
+            // `lib_ref` pops the function
+            Some(plugin.lua.lib_ref(l, LUA_REGISTRYINDEX))
+        } else {
+            plugin.lua.pop(l);
+            None
+        }
+    });
+
+    Ok(ConnectOptions {
+        signaling_url,
+        reconnect,
+        ice_servers,
+        replay,
+        presence,
+        rate_limits,
+        event_buffer,
+        close_on_gc,
+        on_connection_state,
+        on_table_message,
+        on_peer_updated,
+        on_rate_limited,
+    })
 }
 
 extern "C" fn connect(l: *mut lua_State) -> i32 {
-    // Safety: Plugin must have been initialized for this to be registered as module
-    // function.
-    let plugin = unsafe { PLUGIN.get().unwrap_unchecked() };
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
 
     let arg_1_type = plugin
         .lua
@@ -46,215 +503,935 @@ extern "C" fn connect(l: *mut lua_State) -> i32 {
         .lua_typename(l, 4)
         .unwrap_or("unknown".to_string());
 
-    if plugin.lua.lua_type(l, 2) != LuaType::Function {
+    // Without callbacks, the options follow the room name, and events are buffered for `poll`
+    let polled = matches!(
+        plugin.lua.lua_type(l, 2),
+        LuaType::None | LuaType::Nil | LuaType::Table
+    );
+    if !polled {
+        if plugin.lua.lua_type(l, 2) != LuaType::Function {
+            plugin.log.error(
+                PLUGIN_NAME,
+                format!("connect: second argument is not a function ({arg_2_type}), should be a on_peer_connected callback"),
+            );
+            plugin.lua.pushboolean(l, false); // error
+            return 1;
+        }
+        if plugin.lua.lua_type(l, 3) != LuaType::Function {
+            plugin.log.error(
+                PLUGIN_NAME,
+                format!("connect: third argument is not a function ({arg_3_type}), should be a on_message callback"),
+            );
+            plugin.lua.pushboolean(l, false); // error
+            return 1;
+        }
+        if plugin.lua.lua_type(l, 4) != LuaType::Function {
+            plugin.log.error(
+                PLUGIN_NAME,
+                format!("connect: fourth argument is not a function ({arg_4_type}), should be a on_peer_disconnected callback"),
+            );
+            plugin.lua.pushboolean(l, false); // error
+            return 1;
+        }
+    }
+
+    let Some(channel) = plugin.lua.tolstring(l, 1) else {
         plugin.log.error(
             PLUGIN_NAME,
-            format!("connect: second argument is not a function ({arg_2_type}), should be a on_peer_connected callback"),
+            format!("connect: first argument is not a string ({arg_1_type})"),
         );
         plugin.lua.pushboolean(l, false); // error
         return 1;
-    }
-    if plugin.lua.lua_type(l, 3) != LuaType::Function {
+    };
+    if channel.contains(&0) {
         plugin.log.error(
             PLUGIN_NAME,
-            format!("connect: third argument is not a function ({arg_3_type}), should be a on_message callback"),
+            "connect: first argument should be a room name without NUL bytes",
         );
         plugin.lua.pushboolean(l, false); // error
         return 1;
     }
-    if plugin.lua.lua_type(l, 4) != LuaType::Function {
+
+    let options = match read_connect_options(plugin, l, if polled { 2 } else { 5 }) {
+        Ok(options) => options,
+        Err(err) => {
+            plugin.log.error(PLUGIN_NAME, format!("connect: {err}"));
+            plugin.lua.pushboolean(l, false); // error
+            return 1;
+        }
+    };
+    let signaling_url = options
+        .signaling_url
+        .unwrap_or_else(|| plugin.signaling_url.blocking_lock().clone());
+
+    let channel = String::from_utf8_lossy(channel).into_owned();
+
+    let [on_peer_connected, on_message, on_peer_disconnected] = [2, 3, 4].map(|idx| {
+        (!polled).then(|| {
+            plugin.lua.pushvalue(l, idx);
+            plugin.lua.lib_ref(l, LUA_REGISTRYINDEX)
+        })
+    });
+    let callbacks = [
+        (&plugin.on_peer_connected_callbacks, on_peer_connected),
+        (&plugin.on_message_callbacks, on_message),
+        (&plugin.on_peer_disconnected_callbacks, on_peer_disconnected),
+        (
+            &plugin.on_connection_state_callbacks,
+            options.on_connection_state,
+        ),
+        (&plugin.on_table_message_callbacks, options.on_table_message),
+        (&plugin.on_peer_updated_callbacks, options.on_peer_updated),
+        (&plugin.on_rate_limited_callbacks, options.on_rate_limited),
+    ];
+    for (callbacks, callback) in callbacks {
+        let mut callbacks = callbacks.blocking_lock();
+        let old = match callback {
+            Some(callback) => callbacks.insert(channel.clone(), callback),
+            None => callbacks.remove(&channel),
+        };
+        if let Some(old) = old {
+            plugin.release_callback(old);
+        }
+    }
+
+    let config = RoomConfig {
+        url: format!("{signaling_url}/{channel}"),
+        reconnect: options.reconnect,
+        ice_servers: options.ice_servers,
+        replay: options.replay,
+        presence: options.presence,
+        rate_limits: options.rate_limits,
+        event_buffer: polled.then(|| options.event_buffer.unwrap_or(DEFAULT_EVENT_BUFFER)),
+        close_on_gc: options.close_on_gc,
+    };
+    let connection_id = plugin.next_connection_id.fetch_add(1, Ordering::Relaxed);
+    plugin.start_room(channel.clone(), config, connection_id);
+
+    handle::push(&plugin.lua, l, &channel, connection_id);
+    1
+}
+
+/// Reads the room, recipient and mode arguments shared by `send` and `send_table`, and queues
+/// the frame that `read_payload` builds from the third argument.
+/// Returns the number of values pushed as the result of the Lua function.
+fn queue_send(
+    plugin: &Plugin,
+    l: *mut lua_State,
+    function: &str,
+    read_payload: impl FnOnce() -> Result<Frame, String>,
+) -> i32 {
+    let Some(channel) = read_room(plugin, l) else {
+        if plugin.lua.lua_type(l, 1) == LuaType::Nil {
+            plugin
+                .log
+                .error(PLUGIN_NAME, format!("{function}: first argument is nil"));
+        } else {
+            plugin.log.error(
+                PLUGIN_NAME,
+                format!("{function}: first argument should be the room (handle or string)"),
+            );
+        }
+        plugin.lua.pushboolean(l, false); // error
+        return 1;
+    };
+
+    let Some(recipient) = plugin.lua.tolstring(l, 2) else {
         plugin.log.error(
             PLUGIN_NAME,
-            format!("connect: fourth argument is not a function ({arg_4_type}), should be a on_peer_disconnected callback"),
+            format!("{function}: second argument should be the recipient (string)"),
         );
         plugin.lua.pushboolean(l, false); // error
         return 1;
-    }
+    };
+    let raw_recipient = String::from_utf8_lossy(recipient).into_owned();
 
-    if let Some(channel_c_str) = plugin.lua.tolstring(l, 1) {
-        let channel = channel_c_str.to_string_lossy().to_string();
+    let frame = match read_payload() {
+        Ok(frame) => frame,
+        Err(err) => {
+            plugin.log.error(PLUGIN_NAME, format!("{function}: {err}"));
+            plugin.lua.pushboolean(l, false); // error
+            return 1;
+        }
+    };
 
-        plugin.lua.pushvalue(l, 2);
-        let on_peer_connected_callback = plugin.lua.lib_ref(l, LUA_REGISTRYINDEX);
-        {
-            let mut callbacks = plugin.on_peer_connected_callbacks.blocking_lock();
-            callbacks.insert(channel.clone(), on_peer_connected_callback);
+    let data_channel = match plugin.lua.lua_type(l, 4) {
+        LuaType::None | LuaType::Nil => DataChannel::Unreliable,
+        _ => {
+            let mode = plugin
+                .lua
+                .tolstring(l, 4)
+                .map(|mode| String::from_utf8_lossy(mode).into_owned())
+                .unwrap_or_default();
+            match DataChannel::from_name(&mode) {
+                Some(data_channel) => data_channel,
+                None => {
+                    plugin.log.error(
+                        PLUGIN_NAME,
+                        format!("{function}: mode {mode:?} is not \"unreliable\" or \"reliable\""),
+                    );
+                    plugin.lua.pushboolean(l, false); // error
+                    return 1;
+                }
+            }
         }
+    };
 
-        plugin.lua.pushvalue(l, 3);
-        let on_message_callback = plugin.lua.lib_ref(l, LUA_REGISTRYINDEX);
-        {
-            let mut callbacks = plugin.on_message_callbacks.blocking_lock();
-            callbacks.insert(channel.clone(), on_message_callback);
+    let recipient = match parse_recipient(&raw_recipient) {
+        Ok(recipient) => recipient,
+        Err(err) => {
+            plugin.log.error(PLUGIN_NAME, format!("{function}: {err}"));
+            plugin.lua.pushboolean(l, false); // error
+            return 1;
         }
+    };
 
-        plugin.lua.pushvalue(l, 4);
-        let on_peer_disconnected_callback = plugin.lua.lib_ref(l, LUA_REGISTRYINDEX);
-        {
-            let mut callbacks = plugin.on_peer_disconnected_callbacks.blocking_lock();
-            callbacks.insert(channel.clone(), on_peer_disconnected_callback);
+    match plugin.send_frame(channel, recipient, data_channel, frame) {
+        Ok(queued) => {
+            // Dropped messages are logged once a second, rather than one error each
+            plugin.lua.pushboolean(l, queued);
+        }
+        Err(err) => {
+            plugin.log.error(PLUGIN_NAME, format!("{function}: {err}"));
+            plugin.lua.pushboolean(l, false); // error
         }
+    }
+    1
+}
 
-        let url = format!("wss://rtc.darkti.de/{}", channel);
-        plugin.log.info(PLUGIN_NAME, format!("Connecting to {url}"));
+extern "C" fn send(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
 
-        plugin.tokio_runtime.spawn(async move {
-            let result = std::panic::AssertUnwindSafe(async move {
-                let (socket, loop_fut) =
-                    WebRtcSocket::new_unreliable(&url);
+    queue_send(plugin, l, "send", || {
+        plugin
+            .lua
+            .tolstring(l, 3)
+            .map(|message| Frame::Message(message.to_vec()))
+            .ok_or_else(|| "third argument should be the message (string)".to_string())
+    })
+}
 
-                {
-                    let mut sockets = plugin.sockets.lock().await;
-                    sockets.insert(channel, socket);
-                }
+extern "C" fn send_table(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
 
-                let loop_fut = loop_fut.fuse();
-                futures::pin_mut!(loop_fut);
+    queue_send(plugin, l, "send_table", || {
+        if plugin.lua.lua_type(l, 3) != LuaType::Table {
+            return Err("third argument should be the message (table)".to_string());
+        }
+        let value = read_value(&plugin.lua, l, 3)?;
+        Ok(Frame::Table(codec::encode(&value)))
+    })
+}
 
-                let timeout = time::sleep(Duration::from_millis(100));
-                tokio::pin!(timeout);
+/// Reads the method name argument of `call` and `register_method`.
+fn get_method_arg(plugin: &Plugin, l: *mut lua_State, idx: i32) -> Result<String, String> {
+    let method = plugin
+        .lua
+        .tolstring(l, idx)
+        .ok_or_else(|| "method name should be a string".to_string())?;
+    if method.is_empty() || method.len() > MAX_METHOD_LEN {
+        return Err(format!(
+            "method name should be between 1 and {MAX_METHOD_LEN} bytes long"
+        ));
+    }
+    Ok(String::from_utf8_lossy(method).into_owned())
+}
 
+extern "C" fn call(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
 
-                loop {
-                    select! {
-                        // Restart this loop every 100ms
-                        _ = (&mut timeout).fuse() => {
-                            timeout.as_mut().reset(tokio::time::Instant::now() + Duration::from_millis(100));
-                        }
+    let Some(channel) = get_room_arg(plugin, l, "call") else {
+        plugin.lua.pushboolean(l, false); // error
+        return 1;
+    };
 
-                        // Or break if the message loop ends (disconnected, closed, etc.)
-                        _ = &mut loop_fut => {
-                            plugin.log.info(PLUGIN_NAME, "Connection closed");
-                            break;
-                        }
-                    }
-                }
-            })
-            .catch_unwind()
-            .await;
+    let peer = plugin
+        .lua
+        .tolstring(l, 2)
+        .and_then(|peer| Uuid::parse_str(&String::from_utf8_lossy(peer)).ok());
+    let Some(peer) = peer.map(PeerId::from) else {
+        plugin.log.error(
+            PLUGIN_NAME,
+            "call: second argument should be the peer (a valid Uuid)",
+        );
+        plugin.lua.pushboolean(l, false); // error
+        return 1;
+    };
 
-            if let Err(panic) = result {
-                plugin.log.info(PLUGIN_NAME, format!("Background task panicked: {:?}", panic));
-            }
-        });
+    let method = match get_method_arg(plugin, l, 3) {
+        Ok(method) => method,
+        Err(err) => {
+            plugin.log.error(PLUGIN_NAME, format!("call: {err}"));
+            plugin.lua.pushboolean(l, false); // error
+            return 1;
+        }
+    };
 
-        0
-    } else {
+    let payload = match read_value(&plugin.lua, l, 4) {
+        Ok(payload) => codec::encode(&payload),
+        Err(err) => {
+            plugin.log.error(PLUGIN_NAME, format!("call: {err}"));
+            plugin.lua.pushboolean(l, false); // error
+            return 1;
+        }
+    };
+
+    if plugin.lua.lua_type(l, 5) != LuaType::Function {
         plugin.log.error(
             PLUGIN_NAME,
-            format!("connect: first argument is not a string ({arg_1_type})"),
+            "call: fifth argument should be the on_reply callback (function)",
         );
         plugin.lua.pushboolean(l, false); // error
         return 1;
     }
+
+    let timeout = match plugin.lua.lua_type(l, 6) {
+        LuaType::None | LuaType::Nil => DEFAULT_TIMEOUT,
+        LuaType::Number if plugin.lua.tonumber(l, 6) > 0.0 => {
+            // Capped, so that the deadline can't overflow
+            Duration::from_secs_f64(plugin.lua.tonumber(l, 6).min(MAX_TIMEOUT.as_secs_f64()))
+        }
+        _ => {
+            plugin.log.error(
+                PLUGIN_NAME,
+                "call: sixth argument should be the timeout in seconds (positive number)",
+            );
+            plugin.lua.pushboolean(l, false); // error
+            return 1;
+        }
+    };
+
+    if let Err(err) = plugin.check_sendable(&channel) {
+        plugin.log.error(PLUGIN_NAME, format!("call: {err}"));
+        plugin.lua.pushboolean(l, false); // error
+        return 1;
+    }
+
+    // Calls count as messages sent, like `send`, so that they can't flood the peer either
+    if !plugin.rate_limit(&channel, Direction::Send, &peer.to_string()) {
+        plugin.lua.pushboolean(l, false);
+        return 1;
+    }
+
+    plugin.lua.pushvalue(l, 5);
+    let on_reply = plugin.lua.lib_ref(l, LUA_REGISTRYINDEX);
+
+    let id = plugin.next_call_id.fetch_add(1, Ordering::Relaxed);
+    plugin.pending_calls.blocking_lock().insert(
+        id,
+        PendingCall {
+            channel: channel.clone(),
+            peer,
+            method: method.clone(),
+            on_reply,
+            deadline: Instant::now() + timeout,
+        },
+    );
+
+    // Calls always go over the reliable channel, so that only a missing reply causes a timeout
+    plugin.queue_frame(
+        channel,
+        peer.to_string(),
+        DataChannel::Reliable,
+        Frame::Request {
+            id,
+            method: method.into_bytes(),
+            payload,
+        },
+    );
+
+    plugin.lua.pushboolean(l, true);
+    1
 }
 
-extern "C" fn send(l: *mut lua_State) -> i32 {
-    // Safety: Plugin must have been initialized for this to be registered as module
-    // function.
-    let plugin = unsafe { PLUGIN.get().unwrap_unchecked() };
-
-    if let Some(channel) = plugin.lua.tolstring(l, 1) {
-        if let Some(recipient) = plugin.lua.tolstring(l, 2) {
-            if let Some(message) = plugin.lua.tolstring(l, 3) {
-                let channel = channel.to_string_lossy().to_string();
-                let raw_recipient = recipient.to_string_lossy().to_string();
-                let message = message.to_string_lossy().to_string();
-
-                let recipient = if raw_recipient == "all" {
-                    "all".to_string()
-                } else {
-                    match Uuid::parse_str(&raw_recipient) {
-                        Ok(uuid) => uuid.to_string(),
-                        Err(_) => {
-                            plugin.log.error(
-                                PLUGIN_NAME,
-                                format!(
-                                    "send: recipient {raw_recipient} is not \"all\" or a valid Uuid"
-                                ),
-                            );
-                            plugin.lua.pushboolean(l, false); // error
-                            return 1;
-                        }
-                    }
-                };
+extern "C" fn register_method(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    let Some(channel) = get_room_arg(plugin, l, "register_method") else {
+        plugin.lua.pushboolean(l, false); // error
+        return 1;
+    };
+
+    let method = match get_method_arg(plugin, l, 2) {
+        Ok(method) => method,
+        Err(err) => {
+            plugin
+                .log
+                .error(PLUGIN_NAME, format!("register_method: {err}"));
+            plugin.lua.pushboolean(l, false); // error
+            return 1;
+        }
+    };
+
+    let handler = match plugin.lua.lua_type(l, 3) {
+        LuaType::None | LuaType::Nil => None,
+        LuaType::Function => {
+            plugin.lua.pushvalue(l, 3);
+            Some(plugin.lua.lib_ref(l, LUA_REGISTRYINDEX))
+        }
+        _ => {
+            plugin.log.error(
+                PLUGIN_NAME,
+                "register_method: third argument should be the handler (function or nil)",
+            );
+            plugin.lua.pushboolean(l, false); // error
+            return 1;
+        }
+    };
+
+    let old = {
+        let mut rpc_methods = plugin.rpc_methods.blocking_lock();
+        let methods = rpc_methods.entry(channel.clone()).or_default();
+        let old = match handler {
+            Some(handler) => methods.insert(method, handler),
+            None => methods.remove(&method),
+        };
+        if methods.is_empty() {
+            rpc_methods.remove(&channel);
+        }
+        old
+    };
+    if let Some(old) = old {
+        plugin.release_callback(old);
+    }
+
+    plugin.lua.pushboolean(l, true);
+    1
+}
 
-                let mut send_queue = plugin.send_queue.blocking_lock();
-                send_queue
-                    .entry(channel)
-                    .or_insert_with(Vec::new)
-                    .push((recipient, message));
+extern "C" fn set_signaling_url(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
 
+    if let Some(url) = plugin.lua.tolstring(l, 1) {
+        match validate_signaling_url(&String::from_utf8_lossy(url)) {
+            Ok(url) => {
+                plugin
+                    .log
+                    .info(PLUGIN_NAME, format!("Using signaling server {url}"));
+                *plugin.signaling_url.blocking_lock() = url;
                 plugin.lua.pushboolean(l, true);
-                return 1;
-            } else {
+            }
+            Err(err) => {
+                plugin
+                    .log
+                    .error(PLUGIN_NAME, format!("set_signaling_url: {err}"));
+                plugin.lua.pushboolean(l, false); // error
+            }
+        }
+    } else {
+        plugin.log.error(
+            PLUGIN_NAME,
+            "set_signaling_url: first argument should be the url (string)",
+        );
+        plugin.lua.pushboolean(l, false); // error
+    }
+    1
+}
+
+extern "C" fn set_log_level(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    let level = match plugin.lua.lua_type(l, 1) {
+        LuaType::Nil => None,
+        LuaType::String => {
+            let name = plugin.lua.tolstring(l, 1).unwrap_or_default();
+            let name = String::from_utf8_lossy(name);
+            match LogLevel::parse(&name) {
+                Some(level) => Some(level),
+                None => {
+                    plugin.log.error(
+                        PLUGIN_NAME,
+                        format!(
+                            "set_log_level: level {name:?} is not \"debug\", \"info\", \"warn\", \"error\" or \"off\""
+                        ),
+                    );
+                    plugin.lua.pushboolean(l, false); // error
+                    return 1;
+                }
+            }
+        }
+        _ => {
+            plugin.log.error(
+                PLUGIN_NAME,
+                "set_log_level: first argument should be the level (string, or nil with a room)",
+            );
+            plugin.lua.pushboolean(l, false); // error
+            return 1;
+        }
+    };
+
+    match plugin.lua.lua_type(l, 2) {
+        LuaType::None | LuaType::Nil => match level {
+            Some(level) => plugin.log.set_level(level),
+            None => {
                 plugin.log.error(
                     PLUGIN_NAME,
-                    format!("send: third argument should be the message (string)"),
+                    "set_log_level: the level can only be nil for a room",
                 );
                 plugin.lua.pushboolean(l, false); // error
                 return 1;
             }
-        } else {
+        },
+        LuaType::String => {
+            let channel = plugin.lua.tolstring(l, 2).unwrap_or_default();
+            plugin
+                .log
+                .set_room_level(&String::from_utf8_lossy(channel), level);
+        }
+        _ => {
             plugin.log.error(
                 PLUGIN_NAME,
-                format!("send: second argument should be the recipient (string)"),
+                "set_log_level: second argument should be the channel name (string or nil)",
             );
             plugin.lua.pushboolean(l, false); // error
             return 1;
         }
-    } else if plugin.lua.lua_type(l, 1) == LuaType::Nil {
+    }
+    plugin.lua.pushboolean(l, true);
+    1
+}
+
+extern "C" fn set_log_payload_limit(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    if plugin.lua.lua_type(l, 1) == LuaType::Number && plugin.lua.tonumber(l, 1) >= 0.0 {
         plugin
             .log
-            .error(PLUGIN_NAME, format!("send: first argument is nil"));
-        plugin.lua.pushboolean(l, false); // error
-        return 1;
+            .set_payload_limit(plugin.lua.tonumber(l, 1) as usize);
+        plugin.lua.pushboolean(l, true);
     } else {
         plugin.log.error(
             PLUGIN_NAME,
-            format!("send: first argument should be the channel name (string)"),
+            "set_log_payload_limit: first argument should be the number of bytes (number, 0 or more)",
         );
         plugin.lua.pushboolean(l, false); // error
-        return 1;
     }
+    1
 }
 
-extern "C" fn disconnect(l: *mut lua_State) -> i32 {
-    // Safety: Plugin must have been initialized for this to be registered as module
-    // function.
-    let plugin = unsafe { PLUGIN.get().unwrap_unchecked() };
+extern "C" fn start_capture(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
 
-    if let Some(channel) = plugin.lua.tolstring(l, 1) {
-        let channel = channel.to_string_lossy().to_string();
-        plugin.disconnect_queue.blocking_lock().push(channel);
-        plugin.lua.pushboolean(l, true);
+    let Some(channel) = get_room_arg(plugin, l, "start_capture") else {
+        plugin.lua.pushboolean(l, false); // error
         return 1;
-    } else {
+    };
+    if plugin.lua.lua_type(l, 2) != LuaType::String {
         plugin.log.error(
             PLUGIN_NAME,
-            format!("disconnect: first argument should be the channel name (string)"),
+            "start_capture: second argument should be the file path (string)",
         );
         plugin.lua.pushboolean(l, false); // error
         return 1;
     }
-}
-
-impl Plugin {
-    pub fn new(get_engine_api: GetApiFunction) -> Self {
-        let log = Arc::new(LoggingApi::get(get_engine_api));
-        let lua = LuaApi::get(get_engine_api);
-        let tokio_runtime = tokio::runtime::Runtime::new().unwrap();
+    let path = String::from_utf8_lossy(plugin.lua.tolstring(l, 2).unwrap_or_default()).into_owned();
+
+    plugin.stop_capture(&channel);
+    let now = Instant::now();
+    let mut capture = match Capture::create(Path::new(&path), now) {
+        Ok(capture) => capture,
+        Err(err) => {
+            plugin.log.error(
+                PLUGIN_NAME,
+                format!("start_capture: {path} can't be created: {err}"),
+            );
+            plugin.lua.pushboolean(l, false); // error
+            return 1;
+        }
+    };
+
+    // Peers that are already there join at the start of the capture
+    let peers = plugin
+        .rooms
+        .blocking_lock()
+        .get(&channel)
+        .filter(|room| room.socket.is_some())
+        .map(Room::peers)
+        .unwrap_or_default();
+    for peer in peers {
+        // Writes are buffered, and errors reported again on the next write
+        let _ = capture.write(now, Event::PeerJoined, peer, DataChannel::Unreliable, &[]);
+    }
+
+    plugin
+        .log
+        .room(&channel)
+        .info(PLUGIN_NAME, format!("Capturing to {path}"));
+    plugin.captures.blocking_lock().insert(channel, capture);
+    plugin.lua.pushboolean(l, true);
+    1
+}
+
+extern "C" fn stop_capture(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    let stopped = get_room_arg(plugin, l, "stop_capture")
+        .is_some_and(|channel| plugin.stop_capture(&channel));
+    plugin.lua.pushboolean(l, stopped);
+    1
+}
+
+extern "C" fn set_presence(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    let Some(channel) = get_room_arg(plugin, l, "set_presence") else {
+        plugin.lua.pushboolean(l, false); // error
+        return 1;
+    };
+
+    let presence = match read_presence(plugin, l, 2) {
+        Ok(presence) => presence,
+        Err(err) => {
+            plugin
+                .log
+                .error(PLUGIN_NAME, format!("set_presence: {err}"));
+            plugin.lua.pushboolean(l, false); // error
+            return 1;
+        }
+    };
+
+    if let Err(err) = plugin.check_sendable(&channel) {
+        plugin
+            .log
+            .error(PLUGIN_NAME, format!("set_presence: {err}"));
+        plugin.lua.pushboolean(l, false); // error
+        return 1;
+    }
+    if let Some(room) = plugin.rooms.blocking_lock().get_mut(&channel) {
+        room.config.presence = presence.clone();
+    }
+
+    // Peers that join later get the record when they do
+    let presence = presence.unwrap_or_else(|| codec::encode(&Value::Nil));
+    plugin.queue_frame(
+        channel,
+        "all".to_string(),
+        DataChannel::Reliable,
+        Frame::Presence(presence),
+    );
+
+    plugin.lua.pushboolean(l, true);
+    1
+}
+
+/// Reads the room passed as the first argument, which is either a handle returned by `connect`
+/// or the room's name.
+fn read_room(plugin: &Plugin, l: *mut lua_State) -> Option<String> {
+    match handle::read(&plugin.lua, l, 1) {
+        Some(handle) => Some(handle.channel),
+        None => plugin
+            .lua
+            .tolstring(l, 1)
+            .map(|room| String::from_utf8_lossy(room).into_owned()),
+    }
+}
+
+/// Reads the room passed as the first argument of the query functions.
+fn get_room_arg(plugin: &Plugin, l: *mut lua_State, function: &str) -> Option<String> {
+    let room = read_room(plugin, l);
+    if room.is_none() {
+        plugin.log.error(
+            PLUGIN_NAME,
+            format!("{function}: first argument should be the room (handle or string)"),
+        );
+    }
+    room
+}
+
+extern "C" fn is_connected(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    let connected = get_room_arg(plugin, l, "is_connected").is_some_and(|channel| {
+        plugin
+            .rooms
+            .blocking_lock()
+            .get(&channel)
+            .is_some_and(|room| room.state == RoomState::Open)
+    });
+    plugin.lua.pushboolean(l, connected);
+    1
+}
+
+extern "C" fn my_id(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    let id = get_room_arg(plugin, l, "my_id").and_then(|channel| {
+        plugin
+            .rooms
+            .blocking_lock()
+            .get_mut(&channel)
+            .filter(|room| room.state == RoomState::Open)?
+            .socket
+            .as_mut()?
+            .id()
+    });
+    match id {
+        Some(id) => plugin.lua.pushstring(l, id.to_string()),
+        None => plugin.lua.pushnil(l),
+    }
+    1
+}
+
+extern "C" fn peers(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    let peers = get_room_arg(plugin, l, "peers")
+        .and_then(|channel| plugin.rooms.blocking_lock().get(&channel).map(Room::peers))
+        .unwrap_or_default();
+
+    plugin.lua.createtable(l, peers.len() as i32, 0);
+    for (i, peer) in peers.iter().enumerate() {
+        plugin.lua.pushstring(l, peer.to_string());
+        plugin.lua.rawseti(l, -2, i as i32 + 1);
+    }
+    1
+}
+
+extern "C" fn peer_info(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    let info = get_room_arg(plugin, l, "peer_info").and_then(|channel| {
+        let peer = plugin.lua.tolstring(l, 2)?;
+        let peer = PeerId::from(Uuid::parse_str(&String::from_utf8_lossy(peer)).ok()?);
+        plugin
+            .rooms
+            .blocking_lock()
+            .get(&channel)
+            .filter(|room| room.state == RoomState::Open)?
+            .peer_info
+            .get(&peer)
+            .cloned()
+    });
+    match info {
+        Some(info) => plugin.push_decoded_value(l, &info),
+        None => plugin.lua.pushnil(l),
+    }
+    1
+}
+
+extern "C" fn rooms(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    // Rooms being disconnected are gone as far as Lua is concerned
+    let mut rooms: Vec<String> = plugin
+        .rooms
+        .blocking_lock()
+        .iter()
+        .filter(|(_, room)| room.state != RoomState::Closing)
+        .map(|(channel, _)| channel.clone())
+        .collect();
+    rooms.sort();
+
+    plugin.lua.createtable(l, rooms.len() as i32, 0);
+    for (i, room) in rooms.into_iter().enumerate() {
+        plugin.lua.pushstring(l, room);
+        plugin.lua.rawseti(l, -2, i as i32 + 1);
+    }
+    1
+}
+
+extern "C" fn room_state(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    let state = get_room_arg(plugin, l, "room_state").and_then(|channel| {
+        plugin
+            .rooms
+            .blocking_lock()
+            .get(&channel)
+            .map(|room| room.state)
+    });
+    match state {
+        Some(state) => plugin.lua.pushstring(l, state.name()),
+        None => plugin.lua.pushnil(l),
+    }
+    1
+}
+
+extern "C" fn stats(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    let stats = get_room_arg(plugin, l, "stats").and_then(|channel| {
+        let pending_calls = plugin
+            .pending_calls
+            .blocking_lock()
+            .values()
+            .filter(|call| call.channel == channel)
+            .count();
+        plugin.rooms.blocking_lock().get(&channel).map(|room| {
+            room.stats
+                .to_value(Instant::now(), room.send_queue.len(), pending_calls)
+        })
+    });
+    match stats {
+        Some(stats) => plugin.push_decoded_value(l, &stats),
+        None => plugin.lua.pushnil(l),
+    }
+    1
+}
+
+extern "C" fn poll(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    let Some(channel) = get_room_arg(plugin, l, "poll") else {
+        plugin.lua.pushboolean(l, false); // error
+        return 1;
+    };
+    let max = match plugin.lua.lua_type(l, 2) {
+        LuaType::None | LuaType::Nil => usize::MAX,
+        LuaType::Number if plugin.lua.tonumber(l, 2) >= 1.0 => plugin.lua.tonumber(l, 2) as usize,
+        _ => {
+            plugin.log.error(
+                PLUGIN_NAME,
+                "poll: second argument should be the maximum number of events (positive number)",
+            );
+            plugin.lua.pushboolean(l, false); // error
+            return 1;
+        }
+    };
+
+    let polled = match plugin.rooms.blocking_lock().get_mut(&channel) {
+        Some(room) => room.events.as_mut().map(|events| events.take(max)),
+        // The room may have been disconnected, which leaves nothing to poll
+        None => Some((Vec::new(), 0)),
+    };
+    let Some((events, dropped)) = polled else {
+        plugin.log.error(
+            PLUGIN_NAME,
+            format!("poll: channel {channel} was connected with callbacks"),
+        );
+        plugin.lua.pushboolean(l, false); // error
+        return 1;
+    };
+
+    plugin.lua.createtable(l, events.len() as i32, 0);
+    for (i, event) in events.into_iter().enumerate() {
+        plugin.lua.createtable(l, 0, 4);
+        plugin.lua.pushstring(l, event.name());
+        plugin.lua.setfield(l, -2, "type");
+        match event {
+            PollEvent::Message {
+                peer,
+                data,
+                data_channel,
+            } => {
+                plugin.lua.pushstring(l, peer.to_string());
+                plugin.lua.setfield(l, -2, "peer");
+                plugin.lua.pushlstring(l, &data);
+                plugin.lua.setfield(l, -2, "data");
+                plugin.lua.pushstring(l, data_channel.name());
+                plugin.lua.setfield(l, -2, "mode");
+            }
+            PollEvent::Table {
+                peer,
+                value,
+                data_channel,
+            } => {
+                plugin.lua.pushstring(l, peer.to_string());
+                plugin.lua.setfield(l, -2, "peer");
+                plugin.push_decoded_value(l, &value);
+                plugin.lua.setfield(l, -2, "data");
+                plugin.lua.pushstring(l, data_channel.name());
+                plugin.lua.setfield(l, -2, "mode");
+            }
+            PollEvent::PeerConnected(peer) | PollEvent::PeerDisconnected(peer) => {
+                plugin.lua.pushstring(l, peer.to_string());
+                plugin.lua.setfield(l, -2, "peer");
+            }
+            PollEvent::State(state) => {
+                plugin.lua.pushstring(l, state.name());
+                plugin.lua.setfield(l, -2, "state");
+            }
+        }
+        plugin.lua.rawseti(l, -2, i as i32 + 1);
+    }
+    plugin.lua.pushnumber(l, dropped as f64);
+    2
+}
+
+extern "C" fn disconnect(l: *mut lua_State) -> i32 {
+    let Some(plugin) = get_plugin() else {
+        return 0;
+    };
+
+    if let Some(handle) = handle::read(&plugin.lua, l, 1) {
+        plugin.disconnect_handle(&handle);
+        plugin.lua.pushboolean(l, true);
+        1
+    } else if let Some(channel) = plugin.lua.tolstring(l, 1) {
+        let channel = String::from_utf8_lossy(channel).into_owned();
+        plugin.disconnect_room(&channel);
+        plugin.lua.pushboolean(l, true);
+        1
+    } else {
+        plugin.log.error(
+            PLUGIN_NAME,
+            "disconnect: first argument should be the room (handle or string)",
+        );
+        plugin.lua.pushboolean(l, false); // error
+        1
+    }
+}
+
+impl Plugin {
+    pub fn new(get_engine_api: GetApiFunction, tokio_runtime: tokio::runtime::Handle) -> Self {
+        let log = Arc::new(Logger::new(LoggingApi::get(get_engine_api)));
+        let lua = LuaApi::get(get_engine_api);
 
         Self {
             log,
             lua,
             tokio_runtime,
-            sockets: Arc::new(Mutex::new(HashMap::new())),
+            rooms: Arc::new(Mutex::new(HashMap::new())),
             on_peer_connected_callbacks: Arc::new(Mutex::new(HashMap::new())),
             on_message_callbacks: Arc::new(Mutex::new(HashMap::new())),
             on_peer_disconnected_callbacks: Arc::new(Mutex::new(HashMap::new())),
-            send_queue: Arc::new(Mutex::new(HashMap::new())),
-            disconnect_queue: Arc::new(Mutex::new(Vec::new())),
+            signaling_url: Arc::new(Mutex::new(DEFAULT_SIGNALING_URL.to_string())),
+            on_connection_state_callbacks: Arc::new(Mutex::new(HashMap::new())),
+            on_table_message_callbacks: Arc::new(Mutex::new(HashMap::new())),
+            on_peer_updated_callbacks: Arc::new(Mutex::new(HashMap::new())),
+            on_rate_limited_callbacks: Arc::new(Mutex::new(HashMap::new())),
+            next_connection_id: AtomicU64::new(0),
+            rpc_methods: Arc::new(Mutex::new(HashMap::new())),
+            pending_calls: Arc::new(Mutex::new(HashMap::new())),
+            next_call_id: AtomicU32::new(0),
+            tasks: Arc::new(Mutex::new(Vec::new())),
+            captures: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -272,123 +1449,1490 @@ impl Plugin {
         self.lua
             .add_module_function(MODULE_NAME, "connect", connect);
         self.lua.add_module_function(MODULE_NAME, "send", send);
+        self.lua
+            .add_module_function(MODULE_NAME, "send_table", send_table);
+        self.lua.add_module_function(MODULE_NAME, "call", call);
+        self.lua
+            .add_module_function(MODULE_NAME, "register_method", register_method);
         self.lua
             .add_module_function(MODULE_NAME, "disconnect", disconnect);
+        self.lua
+            .add_module_function(MODULE_NAME, "set_signaling_url", set_signaling_url);
+        self.lua
+            .add_module_function(MODULE_NAME, "set_log_level", set_log_level);
+        self.lua
+            .add_module_function(MODULE_NAME, "start_capture", start_capture);
+        self.lua
+            .add_module_function(MODULE_NAME, "stop_capture", stop_capture);
+        self.lua
+            .add_module_function(MODULE_NAME, "set_log_payload_limit", set_log_payload_limit);
+        self.lua
+            .add_module_function(MODULE_NAME, "set_presence", set_presence);
+        self.lua
+            .add_module_function(MODULE_NAME, "is_connected", is_connected);
+        self.lua.add_module_function(MODULE_NAME, "my_id", my_id);
+        self.lua.add_module_function(MODULE_NAME, "peers", peers);
+        self.lua
+            .add_module_function(MODULE_NAME, "peer_info", peer_info);
+        self.lua.add_module_function(MODULE_NAME, "rooms", rooms);
+        self.lua
+            .add_module_function(MODULE_NAME, "room_state", room_state);
+        self.lua.add_module_function(MODULE_NAME, "stats", stats);
+        self.lua.add_module_function(MODULE_NAME, "poll", poll);
         self.lua.set_module_string(MODULE_NAME, "version", version);
+        handle::register(
+            &self.lua,
+            &[
+                ("send", send),
+                ("send_table", send_table),
+                ("call", call),
+                ("register_method", register_method),
+                ("set_presence", set_presence),
+                ("is_connected", is_connected),
+                ("my_id", my_id),
+                ("peers", peers),
+                ("peer_info", peer_info),
+                ("state", room_state),
+                ("stats", stats),
+                ("poll", poll),
+                ("close", disconnect),
+            ],
+        );
+        console::register(&self.lua);
     }
 
     pub fn shutdown_game(&self) {
-        for (channel, socket) in self.sockets.blocking_lock().iter_mut() {
-            self.log
-                .info(PLUGIN_NAME, format!("Closing connection to: {channel}"));
-            socket.close();
+        // Taking the rooms stops the background tasks from reconnecting
+        let mut rooms = std::mem::take(&mut *self.rooms.blocking_lock());
+
+        // Send what was queued since the last update, to the rooms that are open
+        let mut flushed = false;
+        for (channel, room) in rooms.iter_mut() {
+            if room.state == RoomState::Open && room.socket.is_some() {
+                flushed |= self.flush_send_queue(channel, room);
+            } else if !room.send_queue.is_empty() {
+                self.log.room(channel).warning(
+                    PLUGIN_NAME,
+                    format!(
+                        "Dropping {} queued messages, the room is {}",
+                        room.send_queue.len(),
+                        room.state.name()
+                    ),
+                );
+            }
+        }
+        if flushed {
+            self.tokio_runtime
+                .block_on(async { time::sleep(SHUTDOWN_FLUSH_DELAY).await });
+        }
+
+        // Closing the sockets ends their message loops, which tells the signaling server that
+        // we left, so that peers don't have to wait for the connection to time out.
+        for (channel, room) in rooms.iter_mut() {
+            if room.socket.is_some() {
+                self.log
+                    .room(channel)
+                    .info(PLUGIN_NAME, "Closing connection");
+                room.close_socket();
+            }
+        }
+        drop(rooms);
+        self.stop_tasks(SHUTDOWN_TIMEOUT);
+
+        for call in self.take_calls(|_| true) {
+            self.release_callback(call.on_reply);
         }
+        self.stop_captures();
+
+        let mut channels: HashSet<String> = self
+            .on_message_callbacks
+            .blocking_lock()
+            .keys()
+            .cloned()
+            .collect();
+        // Methods can be registered before connecting
+        channels.extend(self.rpc_methods.blocking_lock().keys().cloned());
+        for channel in channels {
+            self.remove_callbacks(&channel);
+        }
+        handle::unregister(&self.lua);
+
         self.log.info(PLUGIN_NAME, "Shutting down");
     }
 
-    pub fn update_game(&self, _dt: f32) {
-        for channel in self.disconnect_queue.blocking_lock().drain(..) {
-            // Close the socket if it exists
-            if let Some(socket) = self.sockets.blocking_lock().get_mut(&channel) {
-                self.log
-                    .info(PLUGIN_NAME, format!("Disconnecting from {channel}"));
-                socket.close();
+    /// Creates the room for `channel` and starts the background task that keeps it connected,
+    /// replacing any previous room. Messages queued for the previous room are kept.
+    fn start_room(&'static self, channel: String, config: RoomConfig, connection_id: u64) {
+        let mut room = Room::new(connection_id, config.clone(), Instant::now());
+
+        if let Some(path) = &config.replay {
+            self.load_replay(&channel, &mut room, path);
+        } else {
+            self.log
+                .room(&channel)
+                .info(PLUGIN_NAME, format!("Connecting to {}", config.url));
+        }
+
+        {
+            let mut rooms = self.rooms.blocking_lock();
+            if let Some(mut old) = rooms.remove(&channel) {
+                // A pending disconnect is replaced as well, and leaves nothing to send
+                old.close_socket();
+                room.send_queue = old.send_queue;
+            }
+            rooms.insert(channel.clone(), room);
+        }
+        if config.replay.is_some() {
+            return;
+        }
+
+        let task = self.tokio_runtime.spawn(async move {
+            let result = std::panic::AssertUnwindSafe(self.run_connection(
+                channel,
+                config.url,
+                config.reconnect,
+                config.ice_servers,
+                connection_id,
+            ))
+            .catch_unwind()
+            .await;
+
+            if let Err(panic) = result {
+                self.log.error(
+                    PLUGIN_NAME,
+                    format!("Background task panicked: {:?}", panic),
+                );
+            }
+        });
+
+        let mut tasks = self.tasks.blocking_lock();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(task);
+    }
+
+    /// Loads the capture file at `path`, to be played back in place of a socket for `channel`
+    /// as if its peers were there. The room is closed if the file can't be read.
+    fn load_replay(&self, channel: &str, room: &mut Room, path: &str) {
+        let log = self.log.room(channel);
+        match capture::read(Path::new(path)) {
+            Ok(records) => {
+                log.info(
+                    PLUGIN_NAME,
+                    format!("Replaying {} records from {path}", records.len()),
+                );
+                room.replay = Some(Replay::new(records, Instant::now()));
+                room.state = RoomState::Signaling;
+            }
+            Err(err) => {
+                log.error(PLUGIN_NAME, format!("Can't replay {path}: {err}"));
+                room.state = RoomState::Closed;
+                room.states.push(ConnectionState::Closed);
+            }
+        }
+    }
+
+    /// Stops a room from sending, receiving and reconnecting. It is removed on the next update,
+    /// which tells Lua that it closed and fails its calls. The callbacks of a channel without a
+    /// room are released right away.
+    pub(crate) fn disconnect_room(&self, channel: &str) {
+        let mut rooms = self.rooms.blocking_lock();
+        let Some(room) = rooms.get_mut(channel) else {
+            drop(rooms);
+            self.remove_callbacks(channel);
+            return;
+        };
+        if room.state == RoomState::Closing {
+            return;
+        }
+        room.state = RoomState::Closing;
+        if room.socket.is_some() {
+            self.log.room(channel).info(PLUGIN_NAME, "Disconnecting");
+            room.close_socket();
+        }
+        room.replay = None;
+        room.send_queue.clear();
+        room.states.clear();
+    }
+
+    /// Disconnects the room that `handle` was returned for, unless it was replaced since.
+    pub(crate) fn disconnect_handle(&self, handle: &RoomHandle) {
+        let is_current = self
+            .rooms
+            .blocking_lock()
+            .get(&handle.channel)
+            .is_some_and(|room| room.connection_id == handle.connection_id);
+        if is_current {
+            self.disconnect_room(&handle.channel);
+        }
+    }
+
+    /// Whether the room that `handle` was returned for is disconnected when the handle is
+    /// collected, which it only is if it was connected with `close_on_gc` and not replaced since.
+    pub(crate) fn closes_on_gc(&self, handle: &RoomHandle) -> bool {
+        self.rooms
+            .blocking_lock()
+            .get(&handle.channel)
+            .is_some_and(|room| {
+                room.connection_id == handle.connection_id && room.config.close_on_gc
+            })
+    }
+
+    /// Removes the rooms that are being disconnected, and tells Lua that they closed.
+    fn remove_closing_rooms(&self) {
+        let closing: Vec<(String, Room)> = {
+            let mut rooms = self.rooms.blocking_lock();
+            rooms
+                .extract_if(|_, room| room.state == RoomState::Closing)
+                .collect()
+        };
+
+        for (channel, room) in closing {
+            self.stop_capture(&channel);
+
+            // Taken first, so that calls made by the callbacks below aren't failed
+            let calls = self.take_calls(|call| call.channel == channel);
+            if room.reported != Some(ConnectionState::Closed) {
+                self.dispatch_connection_state(&channel, ConnectionState::Closed);
+            }
+            for call in calls {
+                self.finish_call(call, Err(CallError::Disconnected));
+            }
+            // Unless a callback connected to the channel again
+            if !self.rooms.blocking_lock().contains_key(&channel) {
+                self.remove_callbacks(&channel);
+            }
+        }
+    }
+
+    /// Records a packet or peer event of `channel`, if it is being captured. The capture is
+    /// stopped if the file can't be written.
+    fn capture(
+        &self,
+        channel: &str,
+        event: Event,
+        peer: PeerId,
+        data_channel: DataChannel,
+        packet: &[u8],
+    ) {
+        let mut captures = self.captures.blocking_lock();
+        let Some(capture) = captures.get_mut(channel) else {
+            return;
+        };
+        if let Err(err) = capture.write(Instant::now(), event, peer, data_channel, packet) {
+            captures.remove(channel);
+            self.log.room(channel).error(
+                PLUGIN_NAME,
+                format!("Capture stopped, it can't be written: {err}"),
+            );
+        }
+    }
+
+    /// Closes the capture file of `channel`, if there is one. Returns whether there was.
+    fn stop_capture(&self, channel: &str) -> bool {
+        let Some(capture) = self.captures.blocking_lock().remove(channel) else {
+            return false;
+        };
+        let log = self.log.room(channel);
+        match capture.finish() {
+            Ok(()) => log.info(PLUGIN_NAME, "Capture stopped"),
+            Err(err) => log.error(PLUGIN_NAME, format!("Capture can't be written: {err}")),
+        }
+        true
+    }
+
+    fn stop_captures(&self) {
+        let channels: Vec<String> = self.captures.blocking_lock().keys().cloned().collect();
+        for channel in channels {
+            self.stop_capture(&channel);
+        }
+    }
+
+    /// Waits up to `timeout` for the background tasks to finish, then cancels the rest and waits
+    /// for them to stop, after which nothing refers to the plugin anymore.
+    pub fn stop_tasks(&self, timeout: Duration) {
+        let tasks = std::mem::take(&mut *self.tasks.blocking_lock());
+        if tasks.is_empty() {
+            return;
+        }
+        self.tokio_runtime.block_on(async {
+            let deadline = time::Instant::now() + timeout;
+            for task in &tasks {
+                while !task.is_finished() && time::Instant::now() < deadline {
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+            }
+            let unfinished = tasks.iter().filter(|task| !task.is_finished()).count();
+            if unfinished > 0 {
+                self.log.warning(
+                    PLUGIN_NAME,
+                    format!("Cancelling {unfinished} background tasks that didn't stop in time"),
+                );
+            }
+            for task in tasks {
+                task.abort();
+                let _ = task.await;
+            }
+        });
+    }
+
+    /// Called on the old plugin when the DLL is about to be hot reloaded. Closes every socket,
+    /// and hands the rooms, callbacks and queues over without releasing anything, so that the
+    /// new plugin can pick up where this one left off.
+    pub fn start_reload(&self) -> ReloadState {
+        self.log.info(PLUGIN_NAME, "Starting hot reload");
+
+        // Taking the rooms stops the background tasks from reconnecting
+        let mut rooms = std::mem::take(&mut *self.rooms.blocking_lock());
+        for room in rooms.values_mut() {
+            room.close_socket();
+        }
+        // Let the rooms be left cleanly, since the tasks can't outlive this DLL
+        self.stop_tasks(SHUTDOWN_TIMEOUT);
+        // Replays start over from their files, but captures would miss the reconnection
+        self.stop_captures();
+
+        let callbacks = self
+            .callback_maps()
+            .into_iter()
+            .flat_map(|(kind, callbacks)| {
+                let callbacks = std::mem::take(&mut *callbacks.blocking_lock());
+                callbacks
+                    .into_iter()
+                    .map(move |(channel, callback)| (kind.to_string(), channel, callback))
+            })
+            .collect();
+        let methods = std::mem::take(&mut *self.rpc_methods.blocking_lock())
+            .into_iter()
+            .flat_map(|(channel, methods)| {
+                methods
+                    .into_iter()
+                    .map(move |(method, handler)| (channel.clone(), method, handler))
+            })
+            .collect();
+        let disconnects = rooms
+            .iter()
+            .filter(|(_, room)| room.state == RoomState::Closing)
+            .map(|(channel, _)| channel.clone())
+            .collect();
+        let mut queued = Vec::new();
+        let mut configs = Vec::new();
+        for (channel, room) in rooms {
+            queued.extend(
+                room.send_queue
+                    .into_iter()
+                    .map(|message| (channel.clone(), message)),
+            );
+            configs.push((channel, room.connection_id, room.config));
+        }
+
+        ReloadState {
+            signaling_url: self.signaling_url.blocking_lock().clone(),
+            rooms: configs,
+            next_connection_id: self.next_connection_id.load(Ordering::Relaxed),
+            callbacks,
+            methods,
+            queued,
+            calls: std::mem::take(&mut *self.pending_calls.blocking_lock())
+                .into_iter()
+                .collect(),
+            next_call_id: self.next_call_id.load(Ordering::Relaxed),
+            disconnects,
+            log_level: self.log.level(),
+            room_log_levels: self
+                .log
+                .room_levels()
+                .iter()
+                .map(|(channel, level)| (channel.clone(), *level))
+                .collect(),
+            log_payload_limit: self.log.payload_limit(),
+        }
+    }
+
+    /// Called on the new plugin after a hot reload, with what the old plugin handed over.
+    /// Rooms are connected again, so peers see us leave and join with a new PeerId.
+    pub fn finish_reload(&'static self, state: ReloadState) {
+        *self.signaling_url.blocking_lock() = state.signaling_url;
+        self.log.set_level(state.log_level);
+        for (channel, level) in state.room_log_levels {
+            self.log.set_room_level(&channel, Some(level));
+        }
+        self.log.set_payload_limit(state.log_payload_limit);
+
+        let callback_maps = self.callback_maps();
+        for (kind, channel, callback) in state.callbacks {
+            match callback_maps.iter().find(|(name, _)| *name == kind) {
+                Some((_, callbacks)) => {
+                    callbacks.blocking_lock().insert(channel, callback);
+                }
+                None => self.release_callback(callback),
+            }
+        }
+
+        let mut rpc_methods = self.rpc_methods.blocking_lock();
+        for (channel, method, handler) in state.methods {
+            rpc_methods
+                .entry(channel)
+                .or_default()
+                .insert(method, handler);
+        }
+        drop(rpc_methods);
+
+        self.pending_calls.blocking_lock().extend(state.calls);
+        self.next_call_id
+            .store(state.next_call_id, Ordering::Relaxed);
+
+        // Rooms keep their ids, so that the handles in Lua still refer to them. Rooms that were
+        // being disconnected are removed on the first update, as they would have been.
+        self.next_connection_id
+            .store(state.next_connection_id, Ordering::Relaxed);
+        let disconnects: HashSet<String> = state.disconnects.into_iter().collect();
+        for (channel, connection_id, config) in state.rooms {
+            if disconnects.contains(&channel) {
+                let mut room = Room::new(connection_id, config, Instant::now());
+                room.state = RoomState::Closing;
+                room.states.clear();
+                self.rooms.blocking_lock().insert(channel, room);
+            } else {
+                self.start_room(channel, config, connection_id);
+            }
+        }
+        for channel in disconnects {
+            if !self.rooms.blocking_lock().contains_key(&channel) {
+                self.remove_callbacks(&channel);
+            }
+        }
+
+        let mut rooms = self.rooms.blocking_lock();
+        for (channel, message) in state.queued {
+            if let Some(room) = rooms
+                .get_mut(&channel)
+                .filter(|room| room.state.accepts_sends())
+            {
+                room.send_queue.push(message);
             }
+        }
+        drop(rooms);
+        self.log.info(PLUGIN_NAME, "Finished hot reload");
+    }
+
+    async fn report_connection_state(
+        &self,
+        channel: &str,
+        connection_id: u64,
+        state: ConnectionState,
+    ) {
+        let mut rooms = self.rooms.lock().await;
+        if let Some(room) = rooms
+            .get_mut(channel)
+            .filter(|room| room.is_current(connection_id))
+        {
+            room.states.push(state);
+        }
+    }
+
+    /// Keeps `channel` connected to the signaling server at `url` until it is closed or replaced.
+    /// When the connection is lost, a new socket is created according to `reconnect`.
+    /// Peers are reached through `ice_servers`, or matchbox's default STUN server if not given.
+    async fn run_connection(
+        &self,
+        channel: String,
+        url: String,
+        reconnect: Option<ReconnectPolicy>,
+        ice_servers: Option<RtcIceServerConfig>,
+        connection_id: u64,
+    ) {
+        let mut attempt = 0;
+        loop {
+            let (socket, loop_fut) = build_socket(&url, ice_servers.as_ref());
+
+            {
+                let mut rooms = self.rooms.lock().await;
+                let Some(room) = rooms
+                    .get_mut(&channel)
+                    .filter(|room| room.is_current(connection_id))
+                else {
+                    // Disconnected or replaced by another `connect` call
+                    return;
+                };
+                room.socket = Some(socket);
+                room.state = RoomState::Signaling;
+            }
+
+            let result = Self::run_message_loop(loop_fut).await;
+
+            let was_open = {
+                let mut rooms = self.rooms.lock().await;
+                let Some(room) = rooms
+                    .get_mut(&channel)
+                    .filter(|room| room.is_current(connection_id))
+                else {
+                    return;
+                };
+                // Messages wait in the queue for the next socket
+                room.socket = None;
+                let was_open = room.state == RoomState::Open;
+                room.state = RoomState::Pending;
+                was_open
+            };
+            // Disconnecting takes the room away from this task first, so the loop ending on its
+            // own means the connection was lost, even if it ended cleanly.
+            let reason = match result {
+                Ok(()) => "the message loop ended".to_string(),
+                Err(err) => err,
+            };
+            self.log
+                .room(&channel)
+                .warning(PLUGIN_NAME, format!("Connection lost: {reason}"));
+            // Only count consecutive failures
+            if was_open {
+                attempt = 0;
+            }
+            let Some(policy) = reconnect.filter(|policy| attempt < policy.max_attempts) else {
+                break;
+            };
+
+            attempt += 1;
+            let delay = policy.delay(attempt);
+            self.log.room(&channel).info(
+                PLUGIN_NAME,
+                format!(
+                    "Reconnecting in {:.1}s (attempt {attempt} of {})",
+                    delay.as_secs_f32(),
+                    policy.max_attempts
+                ),
+            );
+            self.report_connection_state(&channel, connection_id, ConnectionState::Reconnecting)
+                .await;
+            time::sleep(delay).await;
+        }
+
+        // Keep the room and its callbacks, so that Lua can still be told about it
+        let mut rooms = self.rooms.lock().await;
+        if let Some(room) = rooms
+            .get_mut(&channel)
+            .filter(|room| room.is_current(connection_id))
+        {
+            room.state = RoomState::Closed;
+            room.states.push(ConnectionState::Closed);
+            if !room.send_queue.is_empty() {
+                self.log.room(&channel).warning(
+                    PLUGIN_NAME,
+                    format!(
+                        "Dropping {} queued messages, the room is closed",
+                        room.send_queue.len()
+                    ),
+                );
+                room.send_queue.clear();
+            }
+        }
+    }
+
+    /// Drives a socket's message loop until it ends. Returns the reason if the loop failed.
+    async fn run_message_loop(loop_fut: MessageLoopFuture) -> Result<(), String> {
+        let loop_fut = loop_fut.fuse();
+        futures::pin_mut!(loop_fut);
+
+        let timeout = time::sleep(Duration::from_millis(100));
+        tokio::pin!(timeout);
+
+        loop {
+            select! {
+                // Restart this loop every 100ms
+                _ = (&mut timeout).fuse() => {
+                    timeout.as_mut().reset(tokio::time::Instant::now() + Duration::from_millis(100));
+                }
+
+                // Or return if the message loop ends (disconnected, closed, etc.)
+                result = &mut loop_fut => {
+                    return result.map_err(|err| err.to_string());
+                }
+            }
+        }
+    }
+
+    /// Passes a connection state change to the channel's `on_connection_state` callback.
+    fn dispatch_connection_state(&self, channel: &str, state: ConnectionState) {
+        self.log
+            .room(channel)
+            .info(PLUGIN_NAME, format!("Connection state: {}", state.name()));
+        let callback = self
+            .on_connection_state_callbacks
+            .blocking_lock()
+            .get(channel)
+            .copied();
+        match callback {
+            Some(callback) => {
+                self.call_callback(channel, None, "on_connection_state", callback, |l| {
+                    self.lua.pushstring(l, state.name());
+                    1
+                })
+            }
+            None => self.buffer_event(channel, PollEvent::State(state)),
+        }
+    }
+
+    /// Buffers `event` for `poll`, if the room was connected without callbacks.
+    fn buffer_event(&self, channel: &str, event: PollEvent) {
+        let mut rooms = self.rooms.blocking_lock();
+        let Some(room) = rooms.get_mut(channel) else {
+            return;
+        };
+        let Some(events) = room.events.as_mut() else {
+            return;
+        };
+        if !events.push(event) {
+            room.stats.dropped_events += 1;
+            if events.dropped() == 1 {
+                self.log.room(channel).warning(
+                    PLUGIN_NAME,
+                    "Event buffer is full, dropping events until the next poll",
+                );
+            }
+        }
+    }
+
+    /// Frees the registry reference to a callback, so that the function can be garbage
+    /// collected.
+    fn release_callback(&self, callback: i32) {
+        let l = self.lua.get_script_environment_state();
+        self.lua.lib_unref(l, LUA_REGISTRYINDEX, callback);
+    }
+
+    /// Removes all callbacks registered for `channel` and releases their references.
+    fn remove_callbacks(&self, channel: &str) {
+        for (_, callbacks) in self.callback_maps() {
+            if let Some(callback) = callbacks.blocking_lock().remove(channel) {
+                self.release_callback(callback);
+            }
+        }
+
+        let methods = self.rpc_methods.blocking_lock().remove(channel);
+        for handler in methods.into_iter().flat_map(HashMap::into_values) {
+            self.release_callback(handler);
+        }
+    }
+
+    /// The callbacks passed to `connect`, by the name they are handed over with on hot reload.
+    fn callback_maps(&self) -> [(&'static str, &Mutex<HashMap<String, i32>>); 7] {
+        [
+            ("on_peer_connected", &self.on_peer_connected_callbacks),
+            ("on_message", &self.on_message_callbacks),
+            ("on_peer_disconnected", &self.on_peer_disconnected_callbacks),
+            ("on_connection_state", &self.on_connection_state_callbacks),
+            ("on_table_message", &self.on_table_message_callbacks),
+            ("on_peer_updated", &self.on_peer_updated_callbacks),
+            ("on_rate_limited", &self.on_rate_limited_callbacks),
+        ]
+    }
+
+    /// Whether messages can be queued for `channel`, or why not.
+    pub(crate) fn check_sendable(&self, channel: &str) -> Result<(), String> {
+        match self.rooms.blocking_lock().get(channel) {
+            Some(room) if room.state.accepts_sends() => Ok(()),
+            Some(room) => Err(format!("channel {channel} is {}", room.state.name())),
+            None => Err(format!("not connected to channel {channel}")),
+        }
+    }
+
+    /// Queues a message from Lua or the console for `recipient`, if the room accepts messages.
+    /// Returns whether it was queued, rather than dropped by the room's send rate limits.
+    pub(crate) fn send_frame(
+        &self,
+        channel: String,
+        recipient: String,
+        data_channel: DataChannel,
+        frame: Frame,
+    ) -> Result<bool, String> {
+        self.check_sendable(&channel)?;
+        if !self.rate_limit(&channel, Direction::Send, &recipient) {
+            return Ok(false);
+        }
+        self.queue_frame(channel, recipient, data_channel, frame);
+        Ok(true)
+    }
+
+    /// Queues `frame` to be sent to `recipient` once the room is open. Frames for rooms that
+    /// don't accept messages anymore, like replies to peers of a closed room, are dropped.
+    pub(crate) fn queue_frame(
+        &self,
+        channel: String,
+        recipient: String,
+        data_channel: DataChannel,
+        frame: Frame,
+    ) {
+        let mut rooms = self.rooms.blocking_lock();
+        let Some(room) = rooms
+            .get_mut(&channel)
+            .filter(|room| room.state.accepts_sends())
+        else {
+            return;
+        };
+
+        let log = self.log.room(&channel);
+        if !frame.is_control() && log.enabled(LogLevel::Debug) {
+            log.debug(
+                PLUGIN_NAME,
+                format!(
+                    "{} message to {recipient}: {}",
+                    data_channel.name(),
+                    self.log.payload(frame.payload())
+                ),
+            );
+        }
+        room.send_queue.push(QueuedMessage {
+            recipient,
+            data_channel,
+            frame,
+        });
+    }
+
+    /// Runs the Lua function referenced by `callback` with the arguments pushed by `push_args`,
+    /// which returns how many it pushed.
+    /// The call is protected, so that an error in one mod's callback is logged with a traceback
+    /// instead of unwinding through the plugin.
+    fn call_callback(
+        &self,
+        channel: &str,
+        peer: Option<PeerId>,
+        name: &str,
+        callback: i32,
+        push_args: impl FnOnce(*mut lua_State) -> i32,
+    ) {
+        if let Err(err) = self.pcall_callback(callback, 0, push_args, |_| ()) {
+            let peer = peer
+                .map(|peer| format!(" for peer {peer}"))
+                .unwrap_or_default();
+            self.log.room(channel).error(
+                PLUGIN_NAME,
+                format!("Error in {name} callback{peer}: {err}"),
+            );
+        }
+    }
+
+    /// Like `call_callback`, but returns the error instead of logging it. On success,
+    /// `read_results` is called while the callback's `n_results` results are on the stack.
+    fn pcall_callback<R>(
+        &self,
+        callback: i32,
+        n_results: i32,
+        push_args: impl FnOnce(*mut lua_State) -> i32,
+        read_results: impl FnOnce(*mut lua_State) -> R,
+    ) -> Result<R, String> {
+        let l = self.lua.get_script_environment_state();
+        let top = self.lua.gettop(l);
 
-            self.sockets.blocking_lock().remove(&channel);
+        // Use `debug.traceback` as the error handler, if mods haven't removed it
+        let mut handler = 0;
+        self.lua.getfield(l, LUA_GLOBALSINDEX, "debug");
+        if self.lua.lua_type(l, -1) == LuaType::Table {
+            self.lua.getfield(l, -1, "traceback");
+            if self.lua.lua_type(l, -1) == LuaType::Function {
+                handler = self.lua.gettop(l);
+            }
+        }
+
+        self.lua.rawgeti(l, LUA_REGISTRYINDEX, callback);
+        let n_args = push_args(l);
+
+        let result = if self.lua.pcall(l, n_args, n_results, handler) == 0 {
+            Ok(read_results(l))
+        } else {
+            Err(self
+                .lua
+                .tolstring(l, -1)
+                .map(|err| String::from_utf8_lossy(err).into_owned())
+                .unwrap_or_else(|| "(error object is not a string)".to_string()))
+        };
+
+        self.lua.settop(l, top);
+        result
+    }
 
-            // Clear the message queue if it exists
-            self.send_queue.blocking_lock().remove(&channel);
+    /// Removes the pending calls matching `filter`.
+    fn take_calls(&self, filter: impl Fn(&PendingCall) -> bool) -> Vec<PendingCall> {
+        let mut calls: Vec<(u32, PendingCall)> = self
+            .pending_calls
+            .blocking_lock()
+            .extract_if(|_, call| filter(call))
+            .collect();
+        // Reply in the order the calls were made
+        calls.sort_by_key(|(id, _)| *id);
+        calls.into_iter().map(|(_, call)| call).collect()
+    }
 
-            // Remove the callbacks
-            // TODO: MEMORY LEAK: The callbacks are not removed from the Lua registry
-            self.on_peer_connected_callbacks
-                .blocking_lock()
-                .remove(&channel);
-            self.on_message_callbacks.blocking_lock().remove(&channel);
-            self.on_peer_disconnected_callbacks
-                .blocking_lock()
-                .remove(&channel);
+    /// Passes the result of a call to its `on_reply` callback, and releases the callback.
+    fn finish_call(&self, call: PendingCall, result: Result<Value, CallError>) {
+        let PendingCall {
+            channel,
+            peer,
+            method,
+            on_reply,
+            ..
+        } = call;
+
+        if let Err(err) = &result {
+            self.log.room(&channel).warning(
+                PLUGIN_NAME,
+                format!("Call to {method} on {peer} failed: {err}"),
+            );
         }
 
-        let callbacks = self.on_message_callbacks.blocking_lock();
-        for (channel, socket) in self.sockets.blocking_lock().iter_mut() {
-            // Handle any new peers
-            for (peer, state) in socket.update_peers() {
-                match state {
-                    PeerState::Connected => {
-                        self.log.info(
+        self.call_callback(&channel, Some(peer), "on_reply", on_reply, |l| {
+            match &result {
+                Ok(value) => {
+                    self.lua.pushboolean(l, true);
+                    self.push_decoded_value(l, value);
+                }
+                Err(err) => {
+                    self.lua.pushboolean(l, false);
+                    self.lua.pushlstring(l, err.to_string().as_bytes());
+                }
+            }
+            2
+        });
+        self.release_callback(on_reply);
+    }
+
+    /// Runs the handler registered for a request's method, and queues the reply.
+    fn handle_request(&self, channel: &str, peer: PeerId, id: u32, method: &[u8], payload: &[u8]) {
+        let method = String::from_utf8_lossy(method);
+        let handler = self
+            .rpc_methods
+            .blocking_lock()
+            .get(channel)
+            .and_then(|methods| methods.get(method.as_ref()))
+            .copied();
+
+        let result = match (handler, codec::decode(payload)) {
+            (None, _) => Err(CallError::UnknownMethod.to_string()),
+            (Some(_), Err(err)) => Err(format!("invalid payload: {err}")),
+            (Some(handler), Ok(payload)) => {
+                let result = self.pcall_callback(
+                    handler,
+                    1,
+                    |l| {
+                        self.push_decoded_value(l, &payload);
+                        self.lua.pushstring(l, peer.to_string());
+                        2
+                    },
+                    |l| read_value(&self.lua, l, -1),
+                );
+                match result {
+                    Ok(Ok(reply)) => Ok(reply),
+                    Ok(Err(err)) => {
+                        self.log.room(channel).error(
                             PLUGIN_NAME,
-                            format!("[Channel: {channel}] Peer joined: {peer}"),
+                            format!("Reply of method {method} can't be sent: {err}"),
                         );
-                        let callbacks = self.on_peer_connected_callbacks.blocking_lock();
-                        if let Some(callback) = callbacks.get(channel) {
-                            let l = self.lua.get_script_environment_state();
-                            self.lua.rawgeti(l, LUA_REGISTRYINDEX, *callback);
-                            self.lua.pushstring(l, peer.to_string());
-                            self.lua.call(l, 1, 0);
-                        }
+                        Err(format!("reply can't be serialized: {err}"))
                     }
-                    PeerState::Disconnected => {
-                        self.log.info(
+                    Err(err) => {
+                        self.log.room(channel).error(
                             PLUGIN_NAME,
-                            format!("[Channel: {channel}] Peer left: {peer}"),
+                            format!("Error in method {method} for peer {peer}: {err}"),
                         );
-                        let callbacks = self.on_peer_disconnected_callbacks.blocking_lock();
-                        if let Some(callback) = callbacks.get(channel) {
-                            let l = self.lua.get_script_environment_state();
-                            self.lua.rawgeti(l, LUA_REGISTRYINDEX, *callback);
-                            self.lua.pushstring(l, peer.to_string());
-                            self.lua.call(l, 1, 0);
-                        }
+                        // Keep our traceback to ourselves
+                        Err(err.lines().next().unwrap_or_default().to_string())
                     }
                 }
             }
+        };
 
-            // Accept any messages incoming
-            if let Some(callback) = callbacks.get(channel) {
-                for (peer, packet) in socket.channel_mut(0).receive() {
-                    let message = String::from_utf8_lossy(&packet);
-                    self.log.info(
-                        PLUGIN_NAME,
-                        format!("[Channel: {channel}] Message from {peer}: {message:?}"),
+        let (ok, reply) = match result {
+            Ok(reply) => (true, reply),
+            Err(err) => (false, Value::String(err.into_bytes())),
+        };
+        // The caller times out if its reply is over the send rate limit
+        if !self.rate_limit(channel, Direction::Send, &peer.to_string()) {
+            return;
+        }
+        self.queue_frame(
+            channel.to_string(),
+            peer.to_string(),
+            DataChannel::Reliable,
+            Frame::Response {
+                id,
+                ok,
+                payload: codec::encode(&reply),
+            },
+        );
+    }
+
+    /// Matches a reply to the call it answers.
+    fn handle_response(&self, channel: &str, peer: PeerId, id: u32, ok: bool, payload: &[u8]) {
+        let call = {
+            let mut pending_calls = self.pending_calls.blocking_lock();
+            // Only the peer that was called can answer
+            match pending_calls.get(&id) {
+                Some(call) if call.channel == channel && call.peer == peer => {
+                    pending_calls.remove(&id)
+                }
+                _ => None,
+            }
+        };
+        let Some(call) = call else {
+            self.log.room(channel).warning(
+                PLUGIN_NAME,
+                format!("Ignoring reply from {peer} to unknown call {id}"),
+            );
+            return;
+        };
+
+        let result = match codec::decode(payload) {
+            Ok(value) if ok => Ok(value),
+            Ok(Value::String(err)) => Err(CallError::Remote(
+                String::from_utf8_lossy(&err).into_owned(),
+            )),
+            Ok(_) => Err(CallError::Remote("unknown error".to_string())),
+            Err(err) => Err(CallError::Remote(format!("invalid reply: {err}"))),
+        };
+        self.finish_call(call, result);
+    }
+
+    /// Decodes a packet received from `peer` and passes it to the channel's callbacks, or buffers
+    /// it for `poll` if there are none. `on_message` receives tables too, unless an
+    /// `on_table_message` callback was given.
+    fn dispatch_packet(
+        &self,
+        channel: &str,
+        peer: PeerId,
+        data_channel: DataChannel,
+        packet: &[u8],
+        on_message: Option<i32>,
+    ) {
+        let frame = match Frame::decode(packet) {
+            Ok(frame) => frame,
+            Err(err) => {
+                self.log.room(channel).warning(
+                    PLUGIN_NAME,
+                    format!("Dropping invalid packet from {peer}: {err}"),
+                );
+                return;
+            }
+        };
+
+        if !frame.is_control() && !self.rate_limit(channel, Direction::Receive, &peer.to_string()) {
+            return;
+        }
+
+        let log = self.log.room(channel);
+        if !frame.is_control() && log.enabled(LogLevel::Debug) {
+            log.debug(
+                PLUGIN_NAME,
+                format!(
+                    "{} message from {peer}: {}",
+                    data_channel.name(),
+                    self.log.payload(frame.payload())
+                ),
+            );
+        }
+
+        match frame {
+            Frame::Message(message) => match on_message {
+                Some(on_message) => {
+                    self.call_callback(channel, Some(peer), "on_message", on_message, |l| {
+                        self.lua.pushlstring(l, &message);
+                        self.lua.pushstring(l, peer.to_string());
+                        self.lua.pushstring(l, data_channel.name());
+                        3
+                    })
+                }
+                None => self.buffer_event(
+                    channel,
+                    PollEvent::Message {
+                        peer,
+                        data: message,
+                        data_channel,
+                    },
+                ),
+            },
+            Frame::Table(payload) => {
+                let value = match codec::decode(&payload) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        self.log.room(channel).warning(
+                            PLUGIN_NAME,
+                            format!("Dropping invalid table from {peer}: {err}"),
+                        );
+                        return;
+                    }
+                };
+
+                let callback = self
+                    .on_table_message_callbacks
+                    .blocking_lock()
+                    .get(channel)
+                    .map(|callback| ("on_table_message", *callback))
+                    .or(on_message.map(|callback| ("on_message", callback)));
+                let Some((name, callback)) = callback else {
+                    self.buffer_event(
+                        channel,
+                        PollEvent::Table {
+                            peer,
+                            value,
+                            data_channel,
+                        },
                     );
-                    let l = self.lua.get_script_environment_state();
-                    self.lua.rawgeti(l, LUA_REGISTRYINDEX, *callback);
-                    self.lua.pushstring(l, message.to_string());
+                    return;
+                };
+                self.call_callback(channel, Some(peer), name, callback, |l| {
+                    self.push_decoded_value(l, &value);
                     self.lua.pushstring(l, peer.to_string());
-                    self.lua.call(l, 2, 0);
+                    self.lua.pushstring(l, data_channel.name());
+                    3
+                });
+            }
+            Frame::Request {
+                id,
+                method,
+                payload,
+            } => self.handle_request(channel, peer, id, &method, &payload),
+            Frame::Response { id, ok, payload } => {
+                self.handle_response(channel, peer, id, ok, &payload)
+            }
+            Frame::Ping(timestamp) => self.queue_frame(
+                channel.to_string(),
+                peer.to_string(),
+                DataChannel::Reliable,
+                Frame::Pong(timestamp),
+            ),
+            Frame::Pong(timestamp) => {
+                if let Some(room) = self.rooms.blocking_lock().get_mut(channel) {
+                    room.stats.pong(peer, timestamp, Instant::now());
                 }
             }
+            Frame::Presence(payload) => self.handle_presence(channel, peer, &payload),
+        }
+    }
 
-            // Send any queued outgoing messages
-            if let Some(send_queue) = self.send_queue.blocking_lock().get_mut(channel) {
-                // Use drain(..) to consume and remove all items as you iterate
-                for (recipient, message) in send_queue.drain(..) {
-                    self.log.info(
-                        PLUGIN_NAME,
-                        format!("[Channel {channel}]: Message to {recipient}: {message}"),
+    /// Stores the presence record a peer sent, and passes it to `on_peer_updated`.
+    fn handle_presence(&self, channel: &str, peer: PeerId, payload: &[u8]) {
+        let value = if payload.len() > MAX_PRESENCE_LEN {
+            Err(format!("it is {} bytes long", payload.len()))
+        } else {
+            match codec::decode(payload) {
+                Ok(value @ (Value::Nil | Value::Array(_) | Value::Map(_))) => Ok(value),
+                Ok(_) => Err("it isn't a table".to_string()),
+                Err(err) => Err(err.to_string()),
+            }
+        };
+        let value = match value {
+            Ok(value) => value,
+            Err(err) => {
+                self.log.room(channel).warning(
+                    PLUGIN_NAME,
+                    format!("Dropping invalid presence from {peer}: {err}"),
+                );
+                return;
+            }
+        };
+
+        if let Some(room) = self.rooms.blocking_lock().get_mut(channel) {
+            match &value {
+                Value::Nil => room.peer_info.remove(&peer),
+                value => room.peer_info.insert(peer, value.clone()),
+            };
+        }
+
+        let callback = self
+            .on_peer_updated_callbacks
+            .blocking_lock()
+            .get(channel)
+            .copied();
+        if let Some(callback) = callback {
+            self.call_callback(channel, Some(peer), "on_peer_updated", callback, |l| {
+                self.lua.pushstring(l, peer.to_string());
+                match &value {
+                    Value::Nil => self.lua.pushnil(l),
+                    value => self.push_decoded_value(l, value),
+                }
+                2
+            });
+        }
+    }
+
+    /// Pushes a value decoded by the plugin, or `nil` if it is too deep for the Lua stack.
+    fn push_decoded_value(&self, l: *mut lua_State, value: &Value) {
+        let top = self.lua.gettop(l);
+        if let Err(err) = push_value(&self.lua, l, value) {
+            self.log
+                .error(PLUGIN_NAME, format!("Failed to pass table to Lua: {err}"));
+            self.lua.settop(l, top);
+            self.lua.pushnil(l);
+        }
+    }
+
+    /// Sends `packet` to `peer` right away, and counts it in the room's statistics.
+    fn send_packet(
+        &self,
+        channel: &str,
+        room: &mut Room,
+        data_channel: DataChannel,
+        peer: PeerId,
+        packet: Box<[u8]>,
+    ) {
+        let len = packet.len();
+        let result = match room.socket.as_mut() {
+            // matchbox's message loop panics on packets for peers it has no data channel for
+            Some(socket) if socket.connected_peers().any(|connected| connected == peer) => {
+                self.capture(channel, Event::Sent, peer, data_channel, &packet);
+                socket
+                    .channel_mut(data_channel.index())
+                    .try_send(packet, peer)
+                    .map_err(|err| err.to_string())
+            }
+            _ => Err("peer is not connected".to_string()),
+        };
+
+        match result {
+            Ok(()) => room.stats.sent(peer, len),
+            Err(err) => {
+                room.stats.dropped_sends += 1;
+                self.log
+                    .room(channel)
+                    .warning(PLUGIN_NAME, format!("Failed to send to {peer}: {err}"));
+            }
+        }
+    }
+
+    /// Whether a message from or to `peer` is within the channel's rate limits.
+    fn rate_limit(&self, channel: &str, direction: Direction, peer: &str) -> bool {
+        self.rooms
+            .blocking_lock()
+            .get_mut(channel)
+            .is_none_or(|room| room.rate_limiter.allow(direction, peer, Instant::now()))
+    }
+
+    /// Logs the messages that rate limits dropped, and passes the counts to `on_rate_limited`.
+    fn report_rate_limits(&self, now: Instant) {
+        let reports: Vec<_> = self
+            .rooms
+            .blocking_lock()
+            .iter_mut()
+            .map(|(channel, room)| (channel.clone(), room.rate_limiter.report(now)))
+            .filter(|(_, dropped)| !dropped.is_empty())
+            .collect();
+
+        for (channel, dropped) in reports {
+            for (direction, peer, count) in dropped {
+                let message = match direction {
+                    Direction::Receive => format!("Dropped {count} messages from {peer}"),
+                    Direction::Send => format!("Dropped {count} messages to {peer}"),
+                };
+                self.log.room(&channel).warning(
+                    PLUGIN_NAME,
+                    format!("{message}, over the {} rate limit", direction.name()),
+                );
+
+                let callback = self
+                    .on_rate_limited_callbacks
+                    .blocking_lock()
+                    .get(&channel)
+                    .copied();
+                if let Some(callback) = callback {
+                    self.call_callback(&channel, None, "on_rate_limited", callback, |l| {
+                        self.lua.pushlstring(l, peer.as_bytes());
+                        self.lua.pushstring(l, direction.name());
+                        self.lua.pushnumber(l, count as f64);
+                        3
+                    });
+                }
+            }
+        }
+    }
+
+    fn peer_joined(&self, channel: &str, peer: PeerId, now: Instant) {
+        self.log
+            .room(channel)
+            .info(PLUGIN_NAME, format!("Peer joined: {peer}"));
+        let presence = self
+            .rooms
+            .blocking_lock()
+            .get_mut(channel)
+            .and_then(|room| {
+                room.stats.peer_joined(peer, now);
+                room.config.presence.clone()
+            });
+        if let Some(presence) = presence {
+            self.queue_frame(
+                channel.to_string(),
+                peer.to_string(),
+                DataChannel::Reliable,
+                Frame::Presence(presence),
+            );
+        }
+        let callback = self
+            .on_peer_connected_callbacks
+            .blocking_lock()
+            .get(channel)
+            .copied();
+        match callback {
+            Some(callback) => {
+                self.call_callback(channel, Some(peer), "on_peer_connected", callback, |l| {
+                    self.lua.pushstring(l, peer.to_string());
+                    1
+                })
+            }
+            None => self.buffer_event(channel, PollEvent::PeerConnected(peer)),
+        }
+    }
+
+    fn peer_left(&self, channel: &str, peer: PeerId) {
+        self.log
+            .room(channel)
+            .info(PLUGIN_NAME, format!("Peer left: {peer}"));
+        if let Some(room) = self.rooms.blocking_lock().get_mut(channel) {
+            room.stats.peer_left(peer);
+            room.peer_info.remove(&peer);
+            room.rate_limiter.peer_left(&peer.to_string());
+        }
+        let callback = self
+            .on_peer_disconnected_callbacks
+            .blocking_lock()
+            .get(channel)
+            .copied();
+        match callback {
+            Some(callback) => {
+                self.call_callback(channel, Some(peer), "on_peer_disconnected", callback, |l| {
+                    self.lua.pushstring(l, peer.to_string());
+                    1
+                })
+            }
+            None => self.buffer_event(channel, PollEvent::PeerDisconnected(peer)),
+        }
+
+        for call in self.take_calls(|call| call.channel == channel && call.peer == peer) {
+            self.finish_call(call, Err(CallError::PeerDisconnected));
+        }
+    }
+
+    /// Counts a packet from a socket or replay, and passes it to the callbacks, or buffers it.
+    fn receive_packet(
+        &self,
+        channel: &str,
+        peer: PeerId,
+        data_channel: DataChannel,
+        packet: &[u8],
+    ) {
+        if let Some(room) = self.rooms.blocking_lock().get_mut(channel) {
+            room.stats.received(peer, packet.len());
+        }
+        let callback = self
+            .on_message_callbacks
+            .blocking_lock()
+            .get(channel)
+            .copied();
+        self.dispatch_packet(channel, peer, data_channel, packet, callback);
+    }
+
+    /// Takes what happened in every room since the last update, from its background task and
+    /// its socket or replay. Only what doesn't run Lua is handled here, since the rooms are
+    /// locked.
+    fn collect_room_events(&self, now: Instant) -> Vec<(String, u64, Vec<RoomEvent>)> {
+        let mut rooms = Vec::new();
+        for (channel, room) in self.rooms.blocking_lock().iter_mut() {
+            if room.state == RoomState::Closing {
+                continue;
+            }
+            let mut events: Vec<RoomEvent> = room.states.drain(..).map(RoomEvent::State).collect();
+            if room.socket.is_some() {
+                self.collect_socket_events(channel, room, now, &mut events);
+            } else if room.replay.is_some() {
+                self.collect_replay_events(channel, room, now, &mut events);
+            }
+            rooms.push((channel.clone(), room.connection_id, events));
+        }
+        rooms
+    }
+
+    fn collect_socket_events(
+        &self,
+        channel: &str,
+        room: &mut Room,
+        now: Instant,
+        events: &mut Vec<RoomEvent>,
+    ) {
+        let Some(socket) = room.socket.as_mut() else {
+            return;
+        };
+        let has_id = socket.id().is_some();
+
+        // Handle any new peers. This fails once the message loop has ended, until the
+        // background task has removed the socket.
+        let peers = socket.try_update_peers().unwrap_or_default();
+
+        if room.state == RoomState::Signaling && has_id {
+            room.open(now);
+            events.push(RoomEvent::State(ConnectionState::Connected));
+        }
+
+        for (peer, state) in peers {
+            match state {
+                PeerState::Connected => {
+                    self.capture(
+                        channel,
+                        Event::PeerJoined,
+                        peer,
+                        DataChannel::Unreliable,
+                        &[],
                     );
+                    events.push(RoomEvent::PeerJoined(peer));
+                }
+                PeerState::Disconnected => {
+                    self.capture(channel, Event::PeerLeft, peer, DataChannel::Unreliable, &[]);
+                    events.push(RoomEvent::PeerLeft(peer));
+                }
+            }
+        }
 
-                    let packet = message.as_bytes().to_vec().into_boxed_slice();
-                    if recipient == "all" {
-                        for peer in socket.connected_peers().collect::<Vec<PeerId>>() {
-                            socket.channel_mut(0).send(packet.clone(), peer);
-                        }
-                    } else {
-                        if let Ok(uuid) = Uuid::parse_str(&recipient) {
-                            let peer_id = PeerId::from(uuid);
-                            socket.channel_mut(0).send(packet, peer_id);
-                        } else {
-                            self.log.error(
-                                PLUGIN_NAME,
-                                format!("send: recipient {recipient} is not a valid Uuid"),
-                            );
+        // Measure the round trip time to every peer. Pings go over the reliable channel, which
+        // earlier releases don't open, so that they aren't passed to their `on_message`.
+        if let Some(timestamp) = room.stats.ping_due(now) {
+            let packet = Frame::Ping(timestamp).encode().into_boxed_slice();
+            for peer in room.peers() {
+                self.send_packet(channel, room, DataChannel::Reliable, peer, packet.clone());
+            }
+        }
+
+        // Accept any messages incoming
+        let Some(socket) = room.socket.as_mut() else {
+            return;
+        };
+        for data_channel in DataChannel::ALL {
+            for (peer, packet) in socket.channel_mut(data_channel.index()).receive() {
+                self.capture(channel, Event::Received, peer, data_channel, &packet);
+                events.push(RoomEvent::Packet {
+                    peer,
+                    data_channel,
+                    packet,
+                });
+            }
+        }
+    }
+
+    /// Plays back the records of a replayed capture that are due, as if they came from a socket.
+    fn collect_replay_events(
+        &self,
+        channel: &str,
+        room: &mut Room,
+        now: Instant,
+        events: &mut Vec<RoomEvent>,
+    ) {
+        if room.state == RoomState::Signaling {
+            room.open(now);
+            events.push(RoomEvent::State(ConnectionState::Connected));
+        }
+        let Some(replay) = room.replay.as_mut() else {
+            return;
+        };
+
+        for record in replay.due(now) {
+            events.push(match record.event {
+                Event::PeerJoined => RoomEvent::PeerJoined(record.peer),
+                Event::PeerLeft => RoomEvent::PeerLeft(record.peer),
+                Event::Received => RoomEvent::Packet {
+                    peer: record.peer,
+                    data_channel: record.data_channel,
+                    packet: record.packet.into_boxed_slice(),
+                },
+                Event::Sent => continue,
+            });
+        }
+
+        if replay.is_finished() {
+            self.log.room(channel).info(PLUGIN_NAME, "Replay finished");
+            room.replay = None;
+            room.state = RoomState::Closed;
+            events.push(RoomEvent::State(ConnectionState::Closed));
+        }
+    }
+
+    /// Whether events collected for `connection_id` should still be passed to Lua, which may
+    /// have disconnected or connected the channel again in an earlier callback.
+    fn is_dispatching(&self, channel: &str, connection_id: u64) -> bool {
+        self.rooms
+            .blocking_lock()
+            .get(channel)
+            .is_some_and(|room| room.is_current(connection_id))
+    }
+
+    pub fn update_game(&self, _dt: f32) {
+        // Lua callbacks may call any RTC function, so no lock is held while they run
+        self.remove_closing_rooms();
+
+        let now = Instant::now();
+        for call in self.take_calls(|call| call.deadline <= now) {
+            self.finish_call(call, Err(CallError::Timeout));
+        }
+
+        self.report_rate_limits(now);
+
+        for (channel, connection_id, events) in self.collect_room_events(now) {
+            for event in events {
+                if !self.is_dispatching(&channel, connection_id) {
+                    break;
+                }
+                match event {
+                    RoomEvent::State(state) => {
+                        if let Some(room) = self.rooms.blocking_lock().get_mut(&channel) {
+                            room.reported = Some(state);
                         }
+                        self.dispatch_connection_state(&channel, state);
                     }
+                    RoomEvent::PeerJoined(peer) => self.peer_joined(&channel, peer, now),
+                    RoomEvent::PeerLeft(peer) => self.peer_left(&channel, peer),
+                    RoomEvent::Packet {
+                        peer,
+                        data_channel,
+                        packet,
+                    } => self.receive_packet(&channel, peer, data_channel, &packet),
+                }
+            }
+        }
+
+        // Send the messages queued for open rooms, including those queued by the callbacks.
+        // Other rooms keep theirs until they open.
+        for (channel, room) in self.rooms.blocking_lock().iter_mut() {
+            if room.state != RoomState::Open {
+                continue;
+            }
+            if room.socket.is_some() {
+                self.flush_send_queue(channel, room);
+            } else {
+                // There is no one to send messages and replies to in a replay
+                room.send_queue.clear();
+            }
+        }
+    }
+
+    /// Sends the messages queued for an open room. Returns whether there were any.
+    fn flush_send_queue(&self, channel: &str, room: &mut Room) -> bool {
+        let send_queue = std::mem::take(&mut room.send_queue);
+        let flushed = !send_queue.is_empty();
+        for QueuedMessage {
+            recipient,
+            data_channel,
+            frame,
+        } in send_queue
+        {
+            let packet = frame.encode().into_boxed_slice();
+            if recipient == "all" {
+                for peer in room.peers() {
+                    self.send_packet(channel, room, data_channel, peer, packet.clone());
+                }
+            } else {
+                if let Ok(uuid) = Uuid::parse_str(&recipient) {
+                    let peer_id = PeerId::from(uuid);
+                    self.send_packet(channel, room, data_channel, peer_id, packet);
+                } else {
+                    self.log.error(
+                        PLUGIN_NAME,
+                        format!("send: recipient {recipient} is not a valid Uuid"),
+                    );
                 }
             }
         }
+        flushed
     }
 }
 
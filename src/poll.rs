@@ -0,0 +1,82 @@
+//! Events of rooms connected without callbacks, buffered until Lua takes them with `RTC.poll`.
+
+use crate::codec::Value;
+use crate::reconnect::ConnectionState;
+use crate::socket::DataChannel;
+use matchbox_socket::PeerId;
+use std::collections::VecDeque;
+
+/// How many events are buffered, unless `connect` is passed `event_buffer`.
+pub(crate) const DEFAULT_EVENT_BUFFER: usize = 256;
+/// The largest `event_buffer` allowed, which bounds the memory a room that isn't polled uses.
+pub(crate) const MAX_EVENT_BUFFER: usize = 65536;
+
+pub(crate) enum PollEvent {
+    Message {
+        peer: PeerId,
+        data: Vec<u8>,
+        data_channel: DataChannel,
+    },
+    /// A table sent with `send_table`, decoded.
+    Table {
+        peer: PeerId,
+        value: Value,
+        data_channel: DataChannel,
+    },
+    PeerConnected(PeerId),
+    PeerDisconnected(PeerId),
+    State(ConnectionState),
+}
+
+impl PollEvent {
+    /// The `type` field of the event's table.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PollEvent::Message { .. } | PollEvent::Table { .. } => "message",
+            PollEvent::PeerConnected(_) => "peer_connected",
+            PollEvent::PeerDisconnected(_) => "peer_disconnected",
+            PollEvent::State(_) => "state",
+        }
+    }
+}
+
+/// A bounded queue of events. Once it is full, new events are dropped and counted until the
+/// next poll, so that a room that isn't polled can't use up memory.
+pub(crate) struct EventBuffer {
+    events: VecDeque<PollEvent>,
+    capacity: usize,
+    /// Events dropped since the last poll.
+    dropped: u64,
+}
+
+impl EventBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::new(),
+            capacity,
+            dropped: 0,
+        }
+    }
+
+    /// Buffers `event`, or drops it if the buffer is full. Returns whether it was buffered.
+    pub fn push(&mut self, event: PollEvent) -> bool {
+        if self.events.len() >= self.capacity {
+            self.dropped += 1;
+            return false;
+        }
+        self.events.push_back(event);
+        true
+    }
+
+    /// Events dropped since the last poll.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Takes up to `max` events, oldest first, and how many were dropped since the last poll.
+    pub fn take(&mut self, max: usize) -> (Vec<PollEvent>, u64) {
+        let n = max.min(self.events.len());
+        let events = self.events.drain(..n).collect();
+        (events, std::mem::take(&mut self.dropped))
+    }
+}
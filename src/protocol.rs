@@ -0,0 +1,334 @@
+/// A packet sent between peers. Messages sent with `RTC.send` are sent as is, like earlier
+/// releases did, so that peers running them can still talk to us. Every other packet starts with
+/// `MARKER`, followed by a tag identifying the kind of frame and the frame's payload.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    /// A message sent with `RTC.send`, passed to Lua as is.
+    Message(Vec<u8>),
+    /// A table sent with `RTC.send_table`, encoded with `codec`.
+    Table(Vec<u8>),
+    /// A call made with `RTC.call`. The payload is encoded with `codec`.
+    Request {
+        id: u32,
+        method: Vec<u8>,
+        payload: Vec<u8>,
+    },
+    /// The reply to the request with the same `id`. If `ok` is false, the payload is the error
+    /// message. The payload is encoded with `codec`.
+    Response { id: u32, ok: bool, payload: Vec<u8> },
+    /// Sent to every peer periodically to measure the round trip time. The timestamp is only
+    /// meaningful to the sender.
+    Ping(u64),
+    /// The reply to a ping, echoing its timestamp.
+    Pong(u64),
+    /// The sender's presence record, encoded with `codec`. Sent to every peer that joins, and
+    /// to all peers when it changes. A nil record means the sender has none anymore.
+    Presence(Vec<u8>),
+}
+
+/// Starts every packet that isn't a plain message. Text never starts with 0xFF, since it isn't
+/// valid UTF-8, and messages that do start with the marker are framed, to keep them apart.
+const MARKER: [u8; 4] = [0xFF, b'R', b'T', b'C'];
+
+const TAG_MESSAGE: u8 = 0;
+const TAG_TABLE: u8 = 1;
+const TAG_REQUEST: u8 = 2;
+const TAG_RESPONSE: u8 = 3;
+const TAG_PING: u8 = 4;
+const TAG_PONG: u8 = 5;
+const TAG_PRESENCE: u8 = 6;
+
+/// The longest method name a request can carry, since its length is sent as a single byte.
+pub const MAX_METHOD_LEN: usize = u8::MAX as usize;
+/// The largest presence record, once encoded. Presence is sent to every peer that joins, so it
+/// is meant for a few small fields, like a name and a version.
+pub const MAX_PRESENCE_LEN: usize = 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FrameError {
+    UnknownTag(u8),
+    Truncated,
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownTag(tag) => write!(f, "unknown frame tag {tag}"),
+            Self::Truncated => write!(f, "truncated or malformed frame header"),
+        }
+    }
+}
+
+impl Frame {
+    pub fn encode(&self) -> Vec<u8> {
+        if let Frame::Message(payload) = self
+            && !payload.starts_with(&MARKER)
+        {
+            return payload.clone();
+        }
+
+        let mut packet = Vec::with_capacity(self.payload().len() + MARKER.len() + 6);
+        packet.extend_from_slice(&MARKER);
+        match self {
+            Frame::Message(payload) => {
+                packet.push(TAG_MESSAGE);
+                packet.extend_from_slice(payload);
+            }
+            Frame::Table(payload) => {
+                packet.push(TAG_TABLE);
+                packet.extend_from_slice(payload);
+            }
+            Frame::Request {
+                id,
+                method,
+                payload,
+            } => {
+                packet.push(TAG_REQUEST);
+                packet.extend_from_slice(&id.to_be_bytes());
+                // Method names are checked against `MAX_METHOD_LEN` when the call is made
+                let method = &method[..method.len().min(MAX_METHOD_LEN)];
+                packet.push(method.len() as u8);
+                packet.extend_from_slice(method);
+                packet.extend_from_slice(payload);
+            }
+            Frame::Response { id, ok, payload } => {
+                packet.push(TAG_RESPONSE);
+                packet.extend_from_slice(&id.to_be_bytes());
+                packet.push(*ok as u8);
+                packet.extend_from_slice(payload);
+            }
+            Frame::Ping(timestamp) => {
+                packet.push(TAG_PING);
+                packet.extend_from_slice(&timestamp.to_be_bytes());
+            }
+            Frame::Pong(timestamp) => {
+                packet.push(TAG_PONG);
+                packet.extend_from_slice(&timestamp.to_be_bytes());
+            }
+            Frame::Presence(payload) => {
+                packet.push(TAG_PRESENCE);
+                packet.extend_from_slice(payload);
+            }
+        }
+        packet
+    }
+
+    pub fn decode(packet: &[u8]) -> Result<Self, FrameError> {
+        let Some(packet) = packet.strip_prefix(&MARKER) else {
+            return Ok(Frame::Message(packet.to_vec()));
+        };
+        let (&tag, rest) = packet.split_first().ok_or(FrameError::Truncated)?;
+        match tag {
+            TAG_MESSAGE => Ok(Frame::Message(rest.to_vec())),
+            TAG_TABLE => Ok(Frame::Table(rest.to_vec())),
+            TAG_REQUEST => {
+                let (id, rest) = split_id(rest)?;
+                let (&method_len, rest) = rest.split_first().ok_or(FrameError::Truncated)?;
+                if rest.len() < method_len as usize {
+                    return Err(FrameError::Truncated);
+                }
+                let (method, payload) = rest.split_at(method_len as usize);
+                Ok(Frame::Request {
+                    id,
+                    method: method.to_vec(),
+                    payload: payload.to_vec(),
+                })
+            }
+            TAG_RESPONSE => {
+                let (id, rest) = split_id(rest)?;
+                let (&ok, payload) = rest.split_first().ok_or(FrameError::Truncated)?;
+                Ok(Frame::Response {
+                    id,
+                    ok: ok != 0,
+                    payload: payload.to_vec(),
+                })
+            }
+            TAG_PING => Ok(Frame::Ping(decode_timestamp(rest)?)),
+            TAG_PONG => Ok(Frame::Pong(decode_timestamp(rest)?)),
+            TAG_PRESENCE => Ok(Frame::Presence(rest.to_vec())),
+            _ => Err(FrameError::UnknownTag(tag)),
+        }
+    }
+
+    /// The frame's payload, for logging.
+    pub fn payload(&self) -> &[u8] {
+        match self {
+            Frame::Message(payload)
+            | Frame::Table(payload)
+            | Frame::Request { payload, .. }
+            | Frame::Response { payload, .. }
+            | Frame::Presence(payload) => payload,
+            Frame::Ping(_) | Frame::Pong(_) => &[],
+        }
+    }
+
+    /// Whether the frame is exchanged by the plugin on its own, without involving Lua.
+    pub fn is_control(&self) -> bool {
+        matches!(self, Frame::Ping(_) | Frame::Pong(_))
+    }
+}
+
+fn decode_timestamp(data: &[u8]) -> Result<u64, FrameError> {
+    let timestamp = data.try_into().map_err(|_| FrameError::Truncated)?;
+    Ok(u64::from_be_bytes(timestamp))
+}
+
+fn split_id(data: &[u8]) -> Result<(u32, &[u8]), FrameError> {
+    let (id, rest) = data.split_first_chunk().ok_or(FrameError::Truncated)?;
+    Ok((u32::from_be_bytes(*id), rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_sent_as_is() {
+        for message in [&b"hello"[..], b"", b"\0tagged", b"\x01\x02"] {
+            let packet = Frame::Message(message.to_vec()).encode();
+            assert_eq!(packet, message);
+            assert_eq!(Frame::decode(&packet), Ok(Frame::Message(message.to_vec())));
+        }
+    }
+
+    #[test]
+    fn messages_starting_with_the_marker_are_framed() {
+        let message = [&MARKER[..], b"tail"].concat();
+        let packet = Frame::Message(message.clone()).encode();
+        assert_eq!(packet, [&MARKER[..], &[TAG_MESSAGE], &message].concat());
+        assert_eq!(Frame::decode(&packet), Ok(Frame::Message(message)));
+    }
+
+    #[test]
+    fn other_frames_start_with_the_marker() {
+        let packet = Frame::Table(vec![0x90]).encode();
+        assert_eq!(packet, [&MARKER[..], &[TAG_TABLE, 0x90]].concat());
+        assert_eq!(Frame::decode(&packet), Ok(Frame::Table(vec![0x90])));
+
+        // Old peers can't send anything that starts with the marker by accident, unless it is
+        // binary data
+        assert!(std::str::from_utf8(&packet).is_err());
+    }
+
+    #[test]
+    fn marker_without_tag_is_truncated() {
+        assert_eq!(Frame::decode(&MARKER), Err(FrameError::Truncated));
+        assert_eq!(
+            Frame::decode(&[&MARKER[..], &[0x7F]].concat()),
+            Err(FrameError::UnknownTag(0x7F))
+        );
+        // A partial marker is an ordinary message
+        assert_eq!(
+            Frame::decode(&MARKER[..3]),
+            Ok(Frame::Message(MARKER[..3].to_vec()))
+        );
+    }
+
+    #[test]
+    fn calls_round_trip() {
+        let frames = [
+            Frame::Request {
+                id: 7,
+                method: b"add".to_vec(),
+                payload: vec![0x92, 0x01, 0x02],
+            },
+            Frame::Request {
+                id: u32::MAX,
+                method: vec![b'm'; MAX_METHOD_LEN],
+                payload: vec![],
+            },
+            Frame::Response {
+                id: 7,
+                ok: true,
+                payload: vec![0x03],
+            },
+            Frame::Response {
+                id: 0,
+                ok: false,
+                payload: b"\xa7timeout".to_vec(),
+            },
+            Frame::Ping(u64::MAX),
+            Frame::Pong(12345),
+            Frame::Presence(vec![0x80]),
+        ];
+        for frame in frames {
+            assert_eq!(Frame::decode(&frame.encode()), Ok(frame));
+        }
+    }
+
+    #[test]
+    fn request_layout() {
+        let packet = Frame::Request {
+            id: 0x01020304,
+            method: b"add".to_vec(),
+            payload: vec![0xc0],
+        }
+        .encode();
+        assert_eq!(
+            packet,
+            [&MARKER[..], &[TAG_REQUEST, 1, 2, 3, 4, 3], b"add", &[0xc0]].concat()
+        );
+    }
+
+    #[test]
+    fn truncated_calls_are_rejected() {
+        let request = Frame::Request {
+            id: 1,
+            method: b"method".to_vec(),
+            payload: vec![],
+        }
+        .encode();
+        // Everything up to the end of the method name is required
+        for len in MARKER.len() + 1..request.len() {
+            assert_eq!(
+                Frame::decode(&request[..len]),
+                Err(FrameError::Truncated),
+                "{len} bytes"
+            );
+        }
+
+        let response = Frame::Response {
+            id: 1,
+            ok: true,
+            payload: vec![],
+        }
+        .encode();
+        for len in MARKER.len() + 1..response.len() {
+            assert_eq!(
+                Frame::decode(&response[..len]),
+                Err(FrameError::Truncated),
+                "{len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn timestamps_must_be_8_bytes() {
+        let ping = Frame::Ping(1).encode();
+        assert_eq!(
+            Frame::decode(&ping[..ping.len() - 1]),
+            Err(FrameError::Truncated)
+        );
+        assert_eq!(
+            Frame::decode(&[&ping[..], &[0]].concat()),
+            Err(FrameError::Truncated)
+        );
+    }
+
+    #[test]
+    fn long_method_names_are_cut_off() {
+        let frame = Frame::Request {
+            id: 1,
+            method: vec![b'm'; MAX_METHOD_LEN + 1],
+            payload: vec![0xc0],
+        };
+        assert_eq!(
+            Frame::decode(&frame.encode()),
+            Ok(Frame::Request {
+                id: 1,
+                method: vec![b'm'; MAX_METHOD_LEN],
+                payload: vec![0xc0],
+            })
+        );
+    }
+}
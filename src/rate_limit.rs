@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How often the messages dropped by a room's limits are logged and reported to Lua.
+pub(crate) const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A token bucket: `burst` messages can be handled at once, refilled at `rate` per second.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Limit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+/// The limits set with the `rate_limits` option of `RTC.connect`. Unset limits don't apply.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct RateLimits {
+    /// Messages from a single peer that are passed to Lua.
+    pub receive_per_peer: Option<Limit>,
+    /// Messages from all peers that are passed to Lua.
+    pub receive_per_room: Option<Limit>,
+    /// Messages queued for a single peer.
+    pub send_per_peer: Option<Limit>,
+    /// Messages queued for the room, including those sent to "all".
+    pub send_per_room: Option<Limit>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Direction {
+    Receive,
+    Send,
+}
+
+impl Direction {
+    /// The name used for the direction in Lua.
+    pub fn name(self) -> &'static str {
+        match self {
+            Direction::Receive => "receive",
+            Direction::Send => "send",
+        }
+    }
+}
+
+struct TokenBucket {
+    limit: Limit,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: Limit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            refilled: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst);
+        self.refilled = now;
+    }
+
+    fn has_token(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= 1.0
+    }
+}
+
+/// Applies a room's `RateLimits`, and counts what they dropped.
+pub(crate) struct RateLimiter {
+    limits: RateLimits,
+    room: HashMap<Direction, TokenBucket>,
+    /// Buckets by direction and peer, as the PeerId string.
+    peers: HashMap<(Direction, String), TokenBucket>,
+    /// Messages dropped since the last report, by direction and peer, or "all" for messages
+    /// sent to every peer.
+    dropped: HashMap<(Direction, String), u64>,
+    reported: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits, now: Instant) -> Self {
+        let room = [
+            (Direction::Receive, limits.receive_per_room),
+            (Direction::Send, limits.send_per_room),
+        ]
+        .into_iter()
+        .filter_map(|(direction, limit)| Some((direction, TokenBucket::new(limit?, now))))
+        .collect();
+        Self {
+            limits,
+            room,
+            peers: HashMap::new(),
+            dropped: HashMap::new(),
+            reported: now,
+        }
+    }
+
+    /// Whether a message from or to `peer` is within the limits, taking a token from each
+    /// bucket it counts against if it is. Messages sent to "all" only count against the room.
+    pub fn allow(&mut self, direction: Direction, peer: &str, now: Instant) -> bool {
+        let peer_limit = match direction {
+            Direction::Receive => self.limits.receive_per_peer,
+            Direction::Send => self.limits.send_per_peer,
+        };
+        let mut peer_bucket = match peer_limit {
+            Some(limit) if peer != "all" => Some(
+                self.peers
+                    .entry((direction, peer.to_string()))
+                    .or_insert_with(|| TokenBucket::new(limit, now)),
+            ),
+            _ => None,
+        };
+        let mut room_bucket = self.room.get_mut(&direction);
+
+        let allowed = peer_bucket
+            .as_mut()
+            .is_none_or(|bucket| bucket.has_token(now))
+            && room_bucket
+                .as_mut()
+                .is_none_or(|bucket| bucket.has_token(now));
+        if allowed {
+            for bucket in [peer_bucket, room_bucket].into_iter().flatten() {
+                bucket.tokens -= 1.0;
+            }
+        } else {
+            *self
+                .dropped
+                .entry((direction, peer.to_string()))
+                .or_default() += 1;
+        }
+        allowed
+    }
+
+    /// Forgets the buckets of a peer that left.
+    pub fn peer_left(&mut self, peer: &str) {
+        self.peers.retain(|(_, bucket_peer), _| bucket_peer != peer);
+    }
+
+    /// Takes the counts of dropped messages, once every `REPORT_INTERVAL`.
+    pub fn report(&mut self, now: Instant) -> Vec<(Direction, String, u64)> {
+        if now.saturating_duration_since(self.reported) < REPORT_INTERVAL {
+            return Vec::new();
+        }
+        self.reported = now;
+        let mut dropped: Vec<_> = self
+            .dropped
+            .drain()
+            .map(|((direction, peer), count)| (direction, peer, count))
+            .collect();
+        dropped.sort_by(|a, b| (a.0.name(), &a.1).cmp(&(b.0.name(), &b.1)));
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+    const OTHER: &str = "00e8487e-a0e8-4d15-a813-0dc5819af6b6";
+
+    fn limit(rate: f64, burst: f64) -> Option<Limit> {
+        Some(Limit { rate, burst })
+    }
+
+    #[test]
+    fn burst_is_allowed_then_refilled_at_the_rate() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(
+            RateLimits {
+                receive_per_peer: limit(2.0, 3.0),
+                ..RateLimits::default()
+            },
+            now,
+        );
+
+        let allowed = (0..5)
+            .filter(|_| limiter.allow(Direction::Receive, PEER, now))
+            .count();
+        assert_eq!(allowed, 3);
+
+        // Half a second refills one token at 2 per second
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.allow(Direction::Receive, PEER, later));
+        assert!(!limiter.allow(Direction::Receive, PEER, later));
+
+        // Tokens don't pile up past the burst
+        let much_later = later + Duration::from_secs(60);
+        let allowed = (0..5)
+            .filter(|_| limiter.allow(Direction::Receive, PEER, much_later))
+            .count();
+        assert_eq!(allowed, 3);
+    }
+
+    #[test]
+    fn peers_and_directions_have_their_own_buckets() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(
+            RateLimits {
+                receive_per_peer: limit(1.0, 1.0),
+                send_per_peer: limit(1.0, 1.0),
+                ..RateLimits::default()
+            },
+            now,
+        );
+
+        assert!(limiter.allow(Direction::Receive, PEER, now));
+        assert!(!limiter.allow(Direction::Receive, PEER, now));
+        assert!(limiter.allow(Direction::Receive, OTHER, now));
+        assert!(limiter.allow(Direction::Send, PEER, now));
+
+        // Messages to everyone only count against the room, which has no limit here
+        assert!((0..10).all(|_| limiter.allow(Direction::Send, "all", now)));
+    }
+
+    #[test]
+    fn room_limit_applies_to_all_peers() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(
+            RateLimits {
+                send_per_peer: limit(0.001, 2.0),
+                send_per_room: limit(1.0, 3.0),
+                ..RateLimits::default()
+            },
+            now,
+        );
+
+        assert!(limiter.allow(Direction::Send, PEER, now));
+        assert!(limiter.allow(Direction::Send, OTHER, now));
+        assert!(limiter.allow(Direction::Send, "all", now));
+        assert!(!limiter.allow(Direction::Send, PEER, now));
+
+        // The message dropped by the room limit took no token from the peer's bucket, which
+        // doesn't refill in the meantime
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.allow(Direction::Send, PEER, later));
+        assert!(!limiter.allow(Direction::Send, PEER, later + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn unset_limits_allow_everything() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(RateLimits::default(), now);
+        assert!((0..1000).all(|_| limiter.allow(Direction::Receive, PEER, now)));
+        assert!(limiter.report(now + REPORT_INTERVAL).is_empty());
+    }
+
+    #[test]
+    fn drops_are_reported_once_per_interval() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(
+            RateLimits {
+                receive_per_peer: limit(0.001, 1.0),
+                send_per_room: limit(0.001, 1.0),
+                ..RateLimits::default()
+            },
+            now,
+        );
+        for _ in 0..3 {
+            limiter.allow(Direction::Receive, PEER, now);
+            limiter.allow(Direction::Send, "all", now);
+        }
+        limiter.allow(Direction::Receive, OTHER, now);
+
+        assert!(limiter.report(now).is_empty());
+        let reported = now + REPORT_INTERVAL;
+        assert_eq!(
+            limiter.report(reported),
+            [
+                (Direction::Receive, PEER.to_string(), 2),
+                (Direction::Send, "all".to_string(), 2),
+            ]
+        );
+        // Counts start over after a report
+        limiter.allow(Direction::Receive, PEER, reported);
+        assert!(limiter.report(reported + REPORT_INTERVAL / 2).is_empty());
+        assert_eq!(
+            limiter.report(reported + REPORT_INTERVAL),
+            [(Direction::Receive, PEER.to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn peers_that_leave_start_over() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(
+            RateLimits {
+                receive_per_peer: limit(0.001, 1.0),
+                ..RateLimits::default()
+            },
+            now,
+        );
+        assert!(limiter.allow(Direction::Receive, PEER, now));
+        assert!(!limiter.allow(Direction::Receive, PEER, now));
+        limiter.peer_left(PEER);
+        assert!(limiter.allow(Direction::Receive, PEER, now));
+    }
+}
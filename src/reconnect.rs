@@ -0,0 +1,142 @@
+use std::time::Duration;
+
+/// How a room retries after its connection to the signaling server is lost.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ReconnectPolicy {
+    /// How many times to try again in a row, before giving up.
+    pub max_attempts: u32,
+    /// The delay before the first attempt. Doubles with every failed attempt.
+    pub initial_delay: Duration,
+    /// The upper bound for the delay between attempts.
+    pub max_delay: Duration,
+    /// How much the delay is randomized, as a fraction of the delay. `0.25` means the delay
+    /// varies by up to 25% in either direction, so that peers dropped at the same time don't all
+    /// hit the signaling server at once.
+    pub jitter: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.25,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// The delay before reconnect attempt number `attempt`, starting at `1`.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = self
+            .initial_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        let factor = 1.0 + self.jitter * (fastrand::f64() * 2.0 - 1.0);
+        Duration::try_from_secs_f64(delay.as_secs_f64() * factor.max(0.0)).unwrap_or(Duration::MAX)
+    }
+}
+
+/// The state of a room's connection, as reported to the `on_connection_state` callback.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ConnectionState {
+    /// Connecting to the signaling server for the first time.
+    Connecting,
+    /// The signaling server has accepted us into the room.
+    Connected,
+    /// The connection was lost, and we're waiting to try again.
+    Reconnecting,
+    /// The room was disconnected, or the connection was lost for good.
+    Closed,
+}
+
+impl ConnectionState {
+    pub fn name(self) -> &'static str {
+        match self {
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Connected => "connected",
+            ConnectionState::Reconnecting => "reconnecting",
+            ConnectionState::Closed => "closed",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn without_jitter() -> ReconnectPolicy {
+        ReconnectPolicy {
+            jitter: 0.0,
+            ..ReconnectPolicy::default()
+        }
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_maximum() {
+        let policy = without_jitter();
+        let delays: Vec<u64> = (1..=7).map(|n| policy.delay(n).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30]);
+        // Attempts are counted from 1
+        assert_eq!(policy.delay(0), policy.delay(1));
+    }
+
+    #[test]
+    fn delay_does_not_overflow() {
+        let policy = ReconnectPolicy {
+            max_delay: Duration::MAX,
+            ..without_jitter()
+        };
+        assert_eq!(policy.delay(32), Duration::from_secs(1 << 31));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(1 << 31));
+
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::MAX,
+            max_delay: Duration::MAX,
+            ..without_jitter()
+        };
+        assert_eq!(policy.delay(10), Duration::MAX);
+    }
+
+    #[test]
+    fn zero_delays_retry_right_away() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::ZERO,
+            ..ReconnectPolicy::default()
+        };
+        assert_eq!(policy.delay(3), Duration::ZERO);
+
+        let policy = ReconnectPolicy {
+            max_delay: Duration::ZERO,
+            ..ReconnectPolicy::default()
+        };
+        assert_eq!(policy.delay(3), Duration::ZERO);
+    }
+
+    #[test]
+    fn jitter_varies_the_delay_within_bounds() {
+        let policy = ReconnectPolicy::default();
+        let delays: Vec<Duration> = (0..1000).map(|_| policy.delay(3)).collect();
+        // 4 seconds, give or take 25%
+        assert!(
+            delays
+                .iter()
+                .all(|delay| { (Duration::from_secs(3)..=Duration::from_secs(5)).contains(delay) })
+        );
+        assert!(delays.iter().any(|delay| *delay < Duration::from_secs(4)));
+        assert!(delays.iter().any(|delay| *delay > Duration::from_secs(4)));
+
+        // The jitter applies on top of the maximum
+        let delay = policy.delay(10);
+        assert!((Duration::from_millis(22500)..=Duration::from_millis(37500)).contains(&delay));
+
+        // Full jitter can shorten the delay to nothing, but never below
+        let policy = ReconnectPolicy {
+            jitter: 1.0,
+            ..ReconnectPolicy::default()
+        };
+        assert!((0..1000).all(|_| policy.delay(1) <= Duration::from_secs(2)));
+    }
+}
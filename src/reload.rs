@@ -0,0 +1,435 @@
+//! The state handed from the old plugin to the new one when the engine hot reloads the DLL.
+//!
+//! Sockets and background tasks run code from the old DLL, so they can't be handed over. What
+//! survives is plain data: the rooms to connect to again, the registry references of callbacks,
+//! and queued messages and calls. The two DLLs may have been built differently, so the state
+//! crosses over encoded with `codec`, in a buffer allocated with `malloc`.
+
+use crate::codec::{self, Value};
+use crate::logging::LogLevel;
+use crate::protocol::Frame;
+use crate::rate_limit::{Limit, RateLimits};
+use crate::reconnect::ReconnectPolicy;
+use crate::room::{QueuedMessage, RoomConfig};
+use crate::rpc::PendingCall;
+use crate::socket::DataChannel;
+use matchbox_socket::{PeerId, RtcIceServerConfig};
+use std::ffi::c_void;
+use std::time::{Duration, Instant};
+
+/// Bumped whenever the encoding changes. A state with a different version is ignored.
+const VERSION: i64 = 8;
+
+pub(crate) struct ReloadState {
+    pub signaling_url: String,
+    /// The rooms, with the ids of the `connect` calls that created them, which room handles in
+    /// Lua refer to.
+    pub rooms: Vec<(String, u64, RoomConfig)>,
+    pub next_connection_id: u64,
+    /// Callbacks passed to `connect`, as (kind, channel, registry reference).
+    pub callbacks: Vec<(String, String, i32)>,
+    /// Handlers registered with `register_method`, as (channel, method, registry reference).
+    pub methods: Vec<(String, String, i32)>,
+    pub queued: Vec<(String, QueuedMessage)>,
+    pub calls: Vec<(u32, PendingCall)>,
+    pub next_call_id: u32,
+    /// Channels that `disconnect` was called for since the last update.
+    pub disconnects: Vec<String>,
+    pub log_level: LogLevel,
+    pub room_log_levels: Vec<(String, LogLevel)>,
+    pub log_payload_limit: usize,
+}
+
+impl ReloadState {
+    pub fn encode(&self) -> Vec<u8> {
+        let now = Instant::now();
+        let rooms = self
+            .rooms
+            .iter()
+            .map(|(channel, connection_id, config)| {
+                let reconnect = match &config.reconnect {
+                    Some(policy) => Value::Array(vec![
+                        Value::Integer(policy.max_attempts.into()),
+                        seconds(policy.initial_delay),
+                        seconds(policy.max_delay),
+                        Value::Float(policy.jitter),
+                    ]),
+                    None => Value::Nil,
+                };
+                let ice_servers = match &config.ice_servers {
+                    Some(servers) => Value::Array(vec![
+                        Value::Array(servers.urls.iter().map(|url| string(url)).collect()),
+                        optional_string(servers.username.as_deref()),
+                        optional_string(servers.credential.as_deref()),
+                    ]),
+                    None => Value::Nil,
+                };
+                Value::Array(vec![
+                    string(channel),
+                    Value::Integer(*connection_id as i64),
+                    string(&config.url),
+                    reconnect,
+                    ice_servers,
+                    optional_string(config.replay.as_deref()),
+                    config.presence.clone().map_or(Value::Nil, Value::String),
+                    Value::Array(
+                        [
+                            config.rate_limits.receive_per_peer,
+                            config.rate_limits.receive_per_room,
+                            config.rate_limits.send_per_peer,
+                            config.rate_limits.send_per_room,
+                        ]
+                        .map(|limit| match limit {
+                            Some(limit) => Value::Array(vec![
+                                Value::Float(limit.rate),
+                                Value::Float(limit.burst),
+                            ]),
+                            None => Value::Nil,
+                        })
+                        .to_vec(),
+                    ),
+                    config
+                        .event_buffer
+                        .map_or(Value::Nil, |size| Value::Integer(size as i64)),
+                    Value::Boolean(config.close_on_gc),
+                ])
+            })
+            .collect();
+        let references = |references: &[(String, String, i32)]| {
+            references
+                .iter()
+                .map(|(a, b, reference)| {
+                    Value::Array(vec![
+                        string(a),
+                        string(b),
+                        Value::Integer((*reference).into()),
+                    ])
+                })
+                .collect()
+        };
+        let queued = self
+            .queued
+            .iter()
+            .map(|(channel, message)| {
+                Value::Array(vec![
+                    string(channel),
+                    string(&message.recipient),
+                    Value::Integer(message.data_channel.index() as i64),
+                    Value::String(message.frame.encode()),
+                ])
+            })
+            .collect();
+        let calls = self
+            .calls
+            .iter()
+            .map(|(id, call)| {
+                Value::Array(vec![
+                    Value::Integer((*id).into()),
+                    string(&call.channel),
+                    string(&call.peer.to_string()),
+                    string(&call.method),
+                    Value::Integer(call.on_reply.into()),
+                    seconds(call.deadline.saturating_duration_since(now)),
+                ])
+            })
+            .collect();
+
+        codec::encode(&Value::Array(vec![
+            Value::Integer(VERSION),
+            string(&self.signaling_url),
+            Value::Array(rooms),
+            Value::Integer(self.next_connection_id as i64),
+            Value::Array(references(&self.callbacks)),
+            Value::Array(references(&self.methods)),
+            Value::Array(queued),
+            Value::Array(calls),
+            Value::Integer(self.next_call_id.into()),
+            Value::Array(
+                self.disconnects
+                    .iter()
+                    .map(|channel| string(channel))
+                    .collect(),
+            ),
+            string(self.log_level.name()),
+            Value::Array(
+                self.room_log_levels
+                    .iter()
+                    .map(|(channel, level)| {
+                        Value::Array(vec![string(channel), string(level.name())])
+                    })
+                    .collect(),
+            ),
+            Value::Integer(self.log_payload_limit as i64),
+        ]))
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, String> {
+        let value = codec::decode(data).map_err(|err| err.to_string())?;
+        let mut fields = Fields::new(&value)?;
+        let version = fields.integer()?;
+        if version != VERSION {
+            return Err(format!("unsupported version {version}, expected {VERSION}"));
+        }
+        let now = Instant::now();
+
+        let signaling_url = fields.string()?;
+        let rooms = fields.records(|room| {
+            let channel = room.string()?;
+            let connection_id = room.integer()? as u64;
+            let url = room.string()?;
+            let reconnect = room.optional(|policy| {
+                Ok(ReconnectPolicy {
+                    max_attempts: policy.integer()? as u32,
+                    initial_delay: policy.duration()?,
+                    max_delay: policy.duration()?,
+                    jitter: policy.float()?,
+                })
+            })?;
+            let ice_servers = room.optional(|servers| {
+                Ok(RtcIceServerConfig {
+                    urls: servers.records(|url| url.string())?,
+                    username: servers.optional_string()?,
+                    credential: servers.optional_string()?,
+                })
+            })?;
+            let replay = room.optional_string()?;
+            let presence = room.optional_bytes()?;
+            let mut limits = Fields::new(room.next()?)?;
+            let mut limit = || {
+                limits.optional(|limit| {
+                    Ok(Limit {
+                        rate: limit.float()?,
+                        burst: limit.float()?,
+                    })
+                })
+            };
+            let rate_limits = RateLimits {
+                receive_per_peer: limit()?,
+                receive_per_room: limit()?,
+                send_per_peer: limit()?,
+                send_per_room: limit()?,
+            };
+            let event_buffer = match room.next()? {
+                Value::Nil => None,
+                Value::Integer(size) => Some(*size as usize),
+                _ => return Err("expected an integer or nil".to_string()),
+            };
+            let close_on_gc = match room.next()? {
+                Value::Boolean(close_on_gc) => *close_on_gc,
+                _ => return Err("expected a boolean".to_string()),
+            };
+            Ok((
+                channel,
+                connection_id,
+                RoomConfig {
+                    url,
+                    reconnect,
+                    ice_servers,
+                    replay,
+                    presence,
+                    rate_limits,
+                    event_buffer,
+                    close_on_gc,
+                },
+            ))
+        })?;
+        let next_connection_id = fields.integer()? as u64;
+        let callbacks = fields.records(read_reference)?;
+        let methods = fields.records(read_reference)?;
+        let queued = fields.records(|message| {
+            let channel = message.string()?;
+            let recipient = message.string()?;
+            let data_channel = DataChannel::ALL
+                .get(message.integer()? as usize)
+                .copied()
+                .ok_or("unknown data channel")?;
+            let frame = Frame::decode(message.bytes()?).map_err(|err| err.to_string())?;
+            Ok((
+                channel,
+                QueuedMessage {
+                    recipient,
+                    data_channel,
+                    frame,
+                },
+            ))
+        })?;
+        let calls = fields.records(|call| {
+            let id = call.integer()? as u32;
+            let channel = call.string()?;
+            let peer = call.string()?;
+            let peer = PeerId(peer.parse().map_err(|_| format!("invalid peer {peer}"))?);
+            Ok((
+                id,
+                PendingCall {
+                    channel,
+                    peer,
+                    method: call.string()?,
+                    on_reply: call.integer()? as i32,
+                    deadline: now + call.duration()?,
+                },
+            ))
+        })?;
+        let next_call_id = fields.integer()? as u32;
+        let disconnects = fields.records(|channel| channel.string())?;
+        let log_level = fields.log_level()?;
+        let room_log_levels = fields.records(|room| Ok((room.string()?, room.log_level()?)))?;
+        let log_payload_limit = fields.integer()? as usize;
+
+        Ok(Self {
+            signaling_url,
+            rooms,
+            next_connection_id,
+            callbacks,
+            methods,
+            queued,
+            calls,
+            next_call_id,
+            disconnects,
+            log_level,
+            room_log_levels,
+            log_payload_limit,
+        })
+    }
+}
+
+/// Copies `data` into a buffer that the new DLL can free, prefixed with its length.
+pub(crate) fn into_raw(data: &[u8]) -> *mut c_void {
+    let len = data.len() as u64;
+    // Safety: The buffer is allocated with room for the length and the data.
+    unsafe {
+        let buffer = libc::malloc(size_of::<u64>() + data.len()).cast::<u8>();
+        if buffer.is_null() {
+            return std::ptr::null_mut();
+        }
+        buffer.cast::<u64>().write_unaligned(len);
+        std::ptr::copy_nonoverlapping(data.as_ptr(), buffer.add(size_of::<u64>()), data.len());
+        buffer.cast()
+    }
+}
+
+/// Takes back a buffer created with `into_raw`, possibly by another build of the plugin.
+///
+/// # Safety
+/// `state` must be null or a buffer returned by `into_raw` that hasn't been freed yet.
+pub(crate) unsafe fn from_raw(state: *mut c_void) -> Option<Vec<u8>> {
+    if state.is_null() {
+        return None;
+    }
+    // Safety: The caller guarantees that the buffer starts with the length of its data.
+    unsafe {
+        let buffer = state.cast::<u8>();
+        let len = buffer.cast::<u64>().read_unaligned() as usize;
+        let data = std::slice::from_raw_parts(buffer.add(size_of::<u64>()), len).to_vec();
+        libc::free(state);
+        Some(data)
+    }
+}
+
+fn string(s: &str) -> Value {
+    Value::String(s.as_bytes().to_vec())
+}
+
+fn optional_string(s: Option<&str>) -> Value {
+    s.map(string).unwrap_or(Value::Nil)
+}
+
+fn seconds(duration: Duration) -> Value {
+    Value::Float(duration.as_secs_f64())
+}
+
+fn read_reference(fields: &mut Fields) -> Result<(String, String, i32), String> {
+    Ok((fields.string()?, fields.string()?, fields.integer()? as i32))
+}
+
+/// Reads the items of an encoded array in order.
+struct Fields<'a>(std::slice::Iter<'a, Value>);
+
+impl<'a> Fields<'a> {
+    fn new(value: &'a Value) -> Result<Self, String> {
+        match value {
+            Value::Array(items) => Ok(Self(items.iter())),
+            _ => Err("expected an array".to_string()),
+        }
+    }
+
+    fn next(&mut self) -> Result<&'a Value, String> {
+        self.0.next().ok_or_else(|| "missing field".to_string())
+    }
+
+    fn integer(&mut self) -> Result<i64, String> {
+        match self.next()? {
+            Value::Integer(n) => Ok(*n),
+            _ => Err("expected an integer".to_string()),
+        }
+    }
+
+    fn float(&mut self) -> Result<f64, String> {
+        match self.next()? {
+            Value::Float(n) => Ok(*n),
+            Value::Integer(n) => Ok(*n as f64),
+            _ => Err("expected a number".to_string()),
+        }
+    }
+
+    fn duration(&mut self) -> Result<Duration, String> {
+        Duration::try_from_secs_f64(self.float()?).map_err(|err| err.to_string())
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], String> {
+        match self.next()? {
+            Value::String(s) => Ok(s),
+            _ => Err("expected a string".to_string()),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        Ok(String::from_utf8_lossy(self.bytes()?).into_owned())
+    }
+
+    fn log_level(&mut self) -> Result<LogLevel, String> {
+        let name = self.string()?;
+        LogLevel::parse(&name).ok_or_else(|| format!("unknown log level {name}"))
+    }
+
+    fn optional_bytes(&mut self) -> Result<Option<Vec<u8>>, String> {
+        match self.next()? {
+            Value::Nil => Ok(None),
+            Value::String(s) => Ok(Some(s.clone())),
+            _ => Err("expected a string or nil".to_string()),
+        }
+    }
+
+    fn optional_string(&mut self) -> Result<Option<String>, String> {
+        Ok(self
+            .optional_bytes()?
+            .map(|s| String::from_utf8_lossy(&s).into_owned()))
+    }
+
+    /// Reads an array, or nil, with `read`.
+    fn optional<T>(
+        &mut self,
+        read: impl FnOnce(&mut Fields<'a>) -> Result<T, String>,
+    ) -> Result<Option<T>, String> {
+        match self.next()? {
+            Value::Nil => Ok(None),
+            value => read(&mut Fields::new(value)?).map(Some),
+        }
+    }
+
+    /// Reads an array of records, reading each with `read`.
+    fn records<T>(
+        &mut self,
+        mut read: impl FnMut(&mut Fields<'a>) -> Result<T, String>,
+    ) -> Result<Vec<T>, String> {
+        let mut records = Vec::new();
+        for item in Fields::new(self.next()?)?.0 {
+            let record = match item {
+                Value::Array(_) => read(&mut Fields::new(item)?)?,
+                // Arrays of plain values, like urls
+                _ => read(&mut Fields(std::slice::from_ref(item).iter()))?,
+            };
+            records.push(record);
+        }
+        Ok(records)
+    }
+}
@@ -0,0 +1,154 @@
+use crate::capture::Replay;
+use crate::codec::Value;
+use crate::poll::EventBuffer;
+use crate::protocol::Frame;
+use crate::rate_limit::{RateLimiter, RateLimits};
+use crate::reconnect::{ConnectionState, ReconnectPolicy};
+use crate::socket::DataChannel;
+use crate::stats::RoomStats;
+use matchbox_socket::{PeerId, RtcIceServerConfig, WebRtcSocket};
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Where a room is in its lifecycle. Rooms are created by `connect`, move between `Pending`,
+/// `Signaling` and `Open` while the background task keeps them connected, and are removed on
+/// the update after `disconnect`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RoomState {
+    /// Waiting for the background task to create a socket, or to reconnect.
+    Pending,
+    /// The socket exists, but the signaling server hasn't accepted us into the room yet.
+    Signaling,
+    /// Accepted into the room, so that messages can be sent to peers.
+    Open,
+    /// `disconnect` was called. The room is removed on the next update.
+    Closing,
+    /// The connection was closed or lost for good, and won't be retried.
+    Closed,
+}
+
+impl RoomState {
+    pub fn name(self) -> &'static str {
+        match self {
+            RoomState::Pending => "pending",
+            RoomState::Signaling => "signaling",
+            RoomState::Open => "open",
+            RoomState::Closing => "closing",
+            RoomState::Closed => "closed",
+        }
+    }
+
+    /// Whether messages can be queued. They are sent once the room is open.
+    pub fn accepts_sends(self) -> bool {
+        matches!(
+            self,
+            RoomState::Pending | RoomState::Signaling | RoomState::Open
+        )
+    }
+}
+
+/// The connection options of a room, besides its callbacks.
+#[derive(Clone)]
+pub(crate) struct RoomConfig {
+    /// The signaling url, including the room name.
+    pub url: String,
+    pub reconnect: Option<ReconnectPolicy>,
+    pub ice_servers: Option<RtcIceServerConfig>,
+    /// A capture file to play back instead of connecting.
+    pub replay: Option<String>,
+    /// Our presence record, encoded with `codec`.
+    pub presence: Option<Vec<u8>>,
+    pub rate_limits: RateLimits,
+    /// How many events are buffered for `poll`, for rooms connected without callbacks.
+    pub event_buffer: Option<usize>,
+    /// Whether the room is disconnected when its handle is garbage collected.
+    pub close_on_gc: bool,
+}
+
+pub(crate) struct QueuedMessage {
+    pub recipient: String,
+    pub data_channel: DataChannel,
+    pub frame: Frame,
+}
+
+/// Everything the plugin knows about a room, from `connect` until it is removed.
+pub(crate) struct Room {
+    pub state: RoomState,
+    /// The id of the `connect` call that created the room. Background tasks with another id have
+    /// been replaced, and must leave the room alone.
+    pub connection_id: u64,
+    /// How the room was connected, so that it can be connected again after a hot reload.
+    pub config: RoomConfig,
+    pub socket: Option<WebRtcSocket>,
+    /// The capture played back in place of a socket, for rooms connected with `replay`.
+    pub replay: Option<Replay>,
+    /// Outgoing messages, sent on the first update that the room is open.
+    pub send_queue: Vec<QueuedMessage>,
+    /// State changes reported by the background task, to be passed to Lua on the next update.
+    pub states: Vec<ConnectionState>,
+    /// The last state passed to `on_connection_state`, so that `Closed` is reported only once.
+    pub reported: Option<ConnectionState>,
+    pub stats: RoomStats,
+    /// The presence records that connected peers sent.
+    pub peer_info: HashMap<PeerId, Value>,
+    pub rate_limiter: RateLimiter,
+    /// Events waiting for `poll`, for rooms connected without callbacks.
+    pub events: Option<EventBuffer>,
+}
+
+impl Room {
+    pub fn new(connection_id: u64, config: RoomConfig, now: Instant) -> Self {
+        Self {
+            state: RoomState::Pending,
+            connection_id,
+            rate_limiter: RateLimiter::new(config.rate_limits, now),
+            events: config.event_buffer.map(EventBuffer::new),
+            config,
+            socket: None,
+            replay: None,
+            send_queue: Vec::new(),
+            states: vec![ConnectionState::Connecting],
+            reported: None,
+            stats: RoomStats::new(now),
+            peer_info: HashMap::new(),
+        }
+    }
+
+    /// Whether the background task with `connection_id` still owns the room.
+    pub fn is_current(&self, connection_id: u64) -> bool {
+        self.connection_id == connection_id && self.state != RoomState::Closing
+    }
+
+    /// The peers in the room, which are only known while it is open.
+    pub fn peers(&self) -> Vec<PeerId> {
+        if self.state != RoomState::Open {
+            return Vec::new();
+        }
+        match (&self.socket, &self.replay) {
+            (Some(socket), _) => socket.connected_peers().collect(),
+            (None, Some(replay)) => {
+                // Sorted, since replays are mostly used to reproduce problems
+                let mut peers: Vec<PeerId> = replay.peers.iter().copied().collect();
+                peers.sort_by_key(|peer| peer.0);
+                peers
+            }
+            (None, None) => Vec::new(),
+        }
+    }
+
+    /// Marks the room as accepted by the signaling server, or as started for a replay.
+    pub fn open(&mut self, now: Instant) {
+        self.state = RoomState::Open;
+        self.stats.connected(now);
+        // Peers of a previous connection may have left without us noticing
+        self.peer_info.clear();
+    }
+
+    /// Closes the socket, which ends its message loop and lets the signaling server know that
+    /// we left.
+    pub fn close_socket(&mut self) {
+        if let Some(mut socket) = self.socket.take() {
+            socket.close();
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use matchbox_socket::PeerId;
+use std::time::{Duration, Instant};
+
+/// How long `RTC.call` waits for a reply, if no timeout is given.
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The longest timeout `RTC.call` accepts. Longer ones are shortened to this.
+pub(crate) const MAX_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A call made with `RTC.call` that is waiting for a reply.
+pub(crate) struct PendingCall {
+    pub channel: String,
+    pub peer: PeerId,
+    pub method: String,
+    /// Registry reference to the `on_reply` callback.
+    pub on_reply: i32,
+    pub deadline: Instant,
+}
+
+/// Why a call failed, as passed to its `on_reply` callback.
+#[derive(Debug)]
+pub(crate) enum CallError {
+    /// No reply arrived before the deadline.
+    Timeout,
+    /// The peer left the room before replying.
+    PeerDisconnected,
+    /// We disconnected from the room before the peer replied.
+    Disconnected,
+    /// The peer has no handler registered for the method.
+    UnknownMethod,
+    /// The peer's handler raised an error, or its reply couldn't be sent or read.
+    Remote(String),
+}
+
+impl std::fmt::Display for CallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "timeout"),
+            Self::PeerDisconnected => write!(f, "peer disconnected"),
+            Self::Disconnected => write!(f, "disconnected"),
+            Self::UnknownMethod => write!(f, "unknown method"),
+            Self::Remote(err) => write!(f, "{err}"),
+        }
+    }
+}
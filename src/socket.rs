@@ -0,0 +1,54 @@
+//! The WebRTC sockets that rooms are made of, shared by the plugin and `rtc-cli`.
+
+use matchbox_socket::{MessageLoopFuture, RtcIceServerConfig, WebRtcSocket};
+
+/// The signaling server used for rooms that don't specify one.
+pub const DEFAULT_SIGNALING_URL: &str = "wss://rtc.darkti.de";
+
+/// The WebRTC data channels every socket is created with. The discriminant is the index the
+/// channel is added to the socket at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataChannel {
+    /// Unordered, no retransmits. Messages may be lost, but arrive with the least delay.
+    Unreliable = 0,
+    /// Ordered and retransmitted until delivered.
+    Reliable = 1,
+}
+
+impl DataChannel {
+    pub const ALL: [DataChannel; 2] = [DataChannel::Unreliable, DataChannel::Reliable];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// The name used to select the channel from Lua.
+    pub fn name(self) -> &'static str {
+        match self {
+            DataChannel::Unreliable => "unreliable",
+            DataChannel::Reliable => "reliable",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|channel| channel.name() == name)
+    }
+}
+
+/// Creates a socket for the room at `url`, with a channel for every `DataChannel`. Peers are
+/// reached through `ice_servers`, or matchbox's default STUN server if not given.
+///
+/// The socket does nothing until the returned future is polled.
+pub fn build_socket(
+    url: &str,
+    ice_servers: Option<&RtcIceServerConfig>,
+) -> (WebRtcSocket, MessageLoopFuture) {
+    // Channels must be added in the order of `DataChannel`'s discriminants
+    let mut builder = WebRtcSocket::builder(url)
+        .add_unreliable_channel()
+        .add_reliable_channel();
+    if let Some(ice_servers) = ice_servers {
+        builder = builder.ice_server(ice_servers.clone());
+    }
+    builder.build()
+}
@@ -0,0 +1,202 @@
+use crate::codec::Value;
+use matchbox_socket::PeerId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How often every peer is pinged to measure the round trip time.
+pub(crate) const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Traffic counters, including the plugin's own frames like pings and replies.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Counters {
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+}
+
+impl Counters {
+    fn sent(&mut self, bytes: usize) {
+        self.packets_sent += 1;
+        self.bytes_sent += bytes as u64;
+    }
+
+    fn received(&mut self, bytes: usize) {
+        self.packets_received += 1;
+        self.bytes_received += bytes as u64;
+    }
+
+    fn entries(&self) -> Vec<(Value, Value)> {
+        vec![
+            field("packets_sent", Value::Integer(self.packets_sent as i64)),
+            field("bytes_sent", Value::Integer(self.bytes_sent as i64)),
+            field(
+                "packets_received",
+                Value::Integer(self.packets_received as i64),
+            ),
+            field("bytes_received", Value::Integer(self.bytes_received as i64)),
+        ]
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct PeerStats {
+    pub counters: Counters,
+    pub joined_at: Instant,
+    /// Smoothed round trip time, once a ping has been answered.
+    pub rtt: Option<Duration>,
+}
+
+/// Statistics for a room since the last `connect` call.
+#[derive(Debug)]
+pub(crate) struct RoomStats {
+    pub counters: Counters,
+    /// Sends that failed, because the peer isn't connected or the socket is closed.
+    pub dropped_sends: u64,
+    /// Events dropped because the room's event buffer was full.
+    pub dropped_events: u64,
+    pub connect_started_at: Instant,
+    /// When the signaling server accepted us into the room, for the current socket.
+    pub connected_at: Option<Instant>,
+    pub last_ping_at: Option<Instant>,
+    pub peers: HashMap<PeerId, PeerStats>,
+}
+
+impl RoomStats {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            counters: Counters::default(),
+            dropped_sends: 0,
+            dropped_events: 0,
+            connect_started_at: now,
+            connected_at: None,
+            last_ping_at: None,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Called when a new socket has connected. Peers of the previous socket are forgotten.
+    pub fn connected(&mut self, now: Instant) {
+        self.connected_at = Some(now);
+        self.peers.clear();
+    }
+
+    pub fn peer_joined(&mut self, peer: PeerId, now: Instant) {
+        self.peers.insert(
+            peer,
+            PeerStats {
+                counters: Counters::default(),
+                joined_at: now,
+                rtt: None,
+            },
+        );
+    }
+
+    pub fn peer_left(&mut self, peer: PeerId) {
+        self.peers.remove(&peer);
+    }
+
+    pub fn sent(&mut self, peer: PeerId, bytes: usize) {
+        self.counters.sent(bytes);
+        if let Some(peer) = self.peers.get_mut(&peer) {
+            peer.counters.sent(bytes);
+        }
+    }
+
+    pub fn received(&mut self, peer: PeerId, bytes: usize) {
+        self.counters.received(bytes);
+        if let Some(peer) = self.peers.get_mut(&peer) {
+            peer.counters.received(bytes);
+        }
+    }
+
+    /// The timestamp to put in a ping, which the peer echoes back in its pong.
+    pub fn timestamp(&self, now: Instant) -> u64 {
+        now.duration_since(self.connect_started_at).as_micros() as u64
+    }
+
+    /// Returns the timestamp for the next round of pings, if it is time to send them.
+    pub fn ping_due(&mut self, now: Instant) -> Option<u64> {
+        let due = self
+            .last_ping_at
+            .is_none_or(|last| now.duration_since(last) >= PING_INTERVAL);
+        if !due {
+            return None;
+        }
+        self.last_ping_at = Some(now);
+        Some(self.timestamp(now))
+    }
+
+    /// Updates a peer's round trip time with the timestamp echoed in its pong.
+    pub fn pong(&mut self, peer: PeerId, timestamp: u64, now: Instant) {
+        // Pongs to pings from before a reconnect have timestamps from the future
+        let Some(micros) = self.timestamp(now).checked_sub(timestamp) else {
+            return;
+        };
+        let sample = Duration::from_micros(micros);
+
+        if let Some(peer) = self.peers.get_mut(&peer) {
+            // Smoothed like TCP's round trip time estimate
+            peer.rtt = Some(match peer.rtt {
+                Some(rtt) => rtt.mul_f64(0.875) + sample.mul_f64(0.125),
+                None => sample,
+            });
+        }
+    }
+
+    /// The statistics as a value that can be passed to Lua. `queued_sends` and `pending_calls`
+    /// are tracked elsewhere.
+    pub fn to_value(&self, now: Instant, queued_sends: usize, pending_calls: usize) -> Value {
+        let mut entries = self.counters.entries();
+        entries.push(field(
+            "dropped_sends",
+            Value::Integer(self.dropped_sends as i64),
+        ));
+        entries.push(field(
+            "dropped_events",
+            Value::Integer(self.dropped_events as i64),
+        ));
+        entries.push(field("queued_sends", Value::Integer(queued_sends as i64)));
+        entries.push(field("pending_calls", Value::Integer(pending_calls as i64)));
+        if let Some(connected_at) = self.connected_at {
+            entries.push(field(
+                "connect_duration",
+                seconds(connected_at.duration_since(self.connect_started_at)),
+            ));
+            entries.push(field(
+                "connected_for",
+                seconds(now.duration_since(connected_at)),
+            ));
+        }
+
+        let peers = self
+            .peers
+            .iter()
+            .map(|(peer, stats)| {
+                let mut entries = stats.counters.entries();
+                entries.push(field(
+                    "connected_for",
+                    seconds(now.duration_since(stats.joined_at)),
+                ));
+                if let Some(rtt) = stats.rtt {
+                    entries.push(field("rtt", seconds(rtt)));
+                }
+                (
+                    Value::String(peer.to_string().into_bytes()),
+                    Value::Map(entries),
+                )
+            })
+            .collect();
+        entries.push(field("peers", Value::Map(peers)));
+
+        Value::Map(entries)
+    }
+}
+
+fn field(key: &str, value: Value) -> (Value, Value) {
+    (Value::String(key.as_bytes().to_vec()), value)
+}
+
+fn seconds(duration: Duration) -> Value {
+    Value::Float(duration.as_secs_f64())
+}
@@ -12,6 +12,7 @@ use std::ffi::CStr;
 use std::ffi::CString;
 use std::os::raw::c_char;
 use std::os::raw::c_void;
+use std::ptr;
 
 pub use bindings::GetApiFunction;
 pub use bindings::PluginApi;
@@ -60,6 +61,19 @@ fn get_engine_api(f: GetApiFunction, id: PluginApiID) -> *mut c_void {
     unsafe { f(id as u32) }
 }
 
+/// Converts a log message to a `CString`, escaping NUL bytes instead of failing, since messages
+/// may contain text coming from Lua or the network.
+fn log_message(message: impl Into<Vec<u8>>) -> CString {
+    let mut message = message.into();
+    if message.contains(&0) {
+        message = message
+            .into_iter()
+            .flat_map(|b| if b == 0 { b"\\0".to_vec() } else { vec![b] })
+            .collect();
+    }
+    CString::new(message).expect("Invalid CString")
+}
+
 pub struct LoggingApi {
     info: unsafe extern "C" fn(*const c_char, *const c_char),
     warning: unsafe extern "C" fn(*const c_char, *const c_char),
@@ -84,7 +98,7 @@ impl LoggingApi {
 
     pub fn info(&self, system: impl Into<Vec<u8>>, message: impl Into<Vec<u8>>) {
         let system = CString::new(system).expect("Invalid CString");
-        let message = CString::new(message).expect("Invalid CString");
+        let message = log_message(message);
         unsafe {
             (self.info)(system.as_ptr(), message.as_ptr());
         }
@@ -92,7 +106,7 @@ impl LoggingApi {
 
     pub fn warning(&self, system: impl Into<Vec<u8>>, message: impl Into<Vec<u8>>) {
         let system = CString::new(system).expect("Invalid CString");
-        let message = CString::new(message).expect("Invalid CString");
+        let message = log_message(message);
         unsafe {
             (self.warning)(system.as_ptr(), message.as_ptr());
         }
@@ -100,7 +114,7 @@ impl LoggingApi {
 
     pub fn error(&self, system: impl Into<Vec<u8>>, message: impl Into<Vec<u8>>) {
         let system = CString::new(system).expect("Invalid CString");
-        let message = CString::new(message).expect("Invalid CString");
+        let message = log_message(message);
         unsafe {
             (self.error)(system.as_ptr(), message.as_ptr());
         }
@@ -161,16 +175,41 @@ impl std::fmt::Display for LuaType {
 
 pub struct LuaApi {
     add_module_function: unsafe extern "C" fn(*const c_char, *const c_char, lua_CFunction),
+    add_console_command: unsafe extern "C" fn(*const c_char, lua_CFunction, *const c_char, ...),
     set_module_number: unsafe extern "C" fn(*const c_char, *const c_char, f64),
     set_module_string: unsafe extern "C" fn(*const c_char, *const c_char, *const c_char),
     tolstring: unsafe extern "C" fn(*mut lua_State, i32, *mut usize) -> *const c_char,
     pushstring: unsafe extern "C" fn(*mut lua_State, *const c_char),
+    pushlstring: unsafe extern "C" fn(*mut lua_State, *const c_char, usize),
     pushboolean: unsafe extern "C" fn(*mut lua_State, i32),
+    pushnil: unsafe extern "C" fn(*mut lua_State),
+    pushnumber: unsafe extern "C" fn(*mut lua_State, f64),
+    pushcclosure: unsafe extern "C" fn(*mut lua_State, lua_CFunction, i32),
+    checkstack: unsafe extern "C" fn(*mut lua_State, i32) -> i32,
     pushvalue: unsafe extern "C" fn(*mut lua_State, i32),
     lib_ref: unsafe extern "C" fn(*mut lua_State, i32) -> i32,
+    lib_unref: unsafe extern "C" fn(*mut lua_State, i32, i32),
     rawgeti: unsafe extern "C" fn(*mut lua_State, i32, i32),
+    getfield: unsafe extern "C" fn(*mut lua_State, i32, *const c_char),
+    createtable: unsafe extern "C" fn(*mut lua_State, i32, i32),
+    newuserdata: unsafe extern "C" fn(*mut lua_State, usize) -> *mut c_void,
+    touserdata: unsafe extern "C" fn(*mut lua_State, i32) -> *mut c_void,
+    getmetatable: unsafe extern "C" fn(*mut lua_State, i32) -> i32,
+    setmetatable: unsafe extern "C" fn(*mut lua_State, i32) -> i32,
+    lib_newmetatable: unsafe extern "C" fn(*mut lua_State, *const c_char) -> i32,
+    setfield: unsafe extern "C" fn(*mut lua_State, i32, *const c_char),
+    rawseti: unsafe extern "C" fn(*mut lua_State, i32, i32),
+    rawset: unsafe extern "C" fn(*mut lua_State, i32),
+    next: unsafe extern "C" fn(*mut lua_State, i32) -> i32,
+    topointer: unsafe extern "C" fn(*mut lua_State, i32) -> *const c_void,
+    pop: unsafe extern "C" fn(*mut lua_State),
     call: unsafe extern "C" fn(*mut lua_State, i32, i32) -> (),
+    pcall: unsafe extern "C" fn(*mut lua_State, i32, i32, i32) -> i32,
+    gettop: unsafe extern "C" fn(*mut lua_State) -> i32,
+    settop: unsafe extern "C" fn(*mut lua_State, i32),
     getscriptenvironmentstate: unsafe extern "C" fn() -> *mut lua_State,
+    tonumber: unsafe extern "C" fn(*mut lua_State, i32) -> f64,
+    toboolean: unsafe extern "C" fn(*mut lua_State, i32) -> i32,
     lua_type: unsafe extern "C" fn(*mut lua_State, i32) -> i32,
     lua_typename: unsafe extern "C" fn(*mut lua_State, i32) -> *const c_char,
 }
@@ -185,16 +224,41 @@ impl LuaApi {
         unsafe {
             Self {
                 add_module_function: (*api).add_module_function.unwrap_unchecked(),
+                add_console_command: (*api).add_console_command.unwrap_unchecked(),
                 set_module_number: (*api).set_module_number.unwrap_unchecked(),
                 set_module_string: (*api).set_module_string.unwrap_unchecked(),
                 tolstring: (*api).tolstring.unwrap_unchecked(),
                 pushstring: (*api).pushstring.unwrap_unchecked(),
+                pushlstring: (*api).pushlstring.unwrap_unchecked(),
                 pushboolean: (*api).pushboolean.unwrap_unchecked(),
+                pushnil: (*api).pushnil.unwrap_unchecked(),
+                pushnumber: (*api).pushnumber.unwrap_unchecked(),
+                pushcclosure: (*api).pushcclosure.unwrap_unchecked(),
+                checkstack: (*api).checkstack.unwrap_unchecked(),
                 pushvalue: (*api).pushvalue.unwrap_unchecked(),
                 lib_ref: (*api).lib_ref.unwrap_unchecked(),
+                lib_unref: (*api).lib_unref.unwrap_unchecked(),
                 rawgeti: (*api).rawgeti.unwrap_unchecked(),
+                getfield: (*api).getfield.unwrap_unchecked(),
+                createtable: (*api).createtable.unwrap_unchecked(),
+                newuserdata: (*api).newuserdata.unwrap_unchecked(),
+                touserdata: (*api).touserdata.unwrap_unchecked(),
+                getmetatable: (*api).getmetatable.unwrap_unchecked(),
+                setmetatable: (*api).setmetatable.unwrap_unchecked(),
+                lib_newmetatable: (*api).lib_newmetatable.unwrap_unchecked(),
+                setfield: (*api).setfield.unwrap_unchecked(),
+                rawseti: (*api).rawseti.unwrap_unchecked(),
+                rawset: (*api).rawset.unwrap_unchecked(),
+                next: (*api).next.unwrap_unchecked(),
+                topointer: (*api).topointer.unwrap_unchecked(),
+                pop: (*api).pop.unwrap_unchecked(),
                 call: (*api).call.unwrap_unchecked(),
+                pcall: (*api).pcall.unwrap_unchecked(),
+                gettop: (*api).gettop.unwrap_unchecked(),
+                settop: (*api).settop.unwrap_unchecked(),
                 getscriptenvironmentstate: (*api).getscriptenvironmentstate.unwrap_unchecked(),
+                tonumber: (*api).tonumber.unwrap_unchecked(),
+                toboolean: (*api).toboolean.unwrap_unchecked(),
                 lua_type: (*api).type_.unwrap_unchecked(),
                 lua_typename: (*api).lua_typename.unwrap_unchecked(),
             }
@@ -213,6 +277,46 @@ impl LuaApi {
         unsafe { (self.add_module_function)(module.as_ptr(), name.as_ptr(), Some(cb)) }
     }
 
+    /// Adds a console command, which the engine calls with the words typed after it as string
+    /// arguments. `usage` documents the arguments, as the arguments and what the command does
+    /// with them.
+    pub fn add_console_command(
+        &self,
+        command: impl Into<Vec<u8>>,
+        cb: extern "C" fn(*mut lua_State) -> i32,
+        description: impl Into<Vec<u8>>,
+        usage: Option<(&str, &str)>,
+    ) {
+        let command = CString::new(command).expect("Invalid CString");
+        let description = CString::new(description).expect("Invalid CString");
+
+        // The documentation strings are ended by a null pointer
+        match usage {
+            Some((arguments, help)) => {
+                let arguments = CString::new(arguments).expect("Invalid CString");
+                let help = CString::new(help).expect("Invalid CString");
+                unsafe {
+                    (self.add_console_command)(
+                        command.as_ptr(),
+                        Some(cb),
+                        description.as_ptr(),
+                        arguments.as_ptr(),
+                        help.as_ptr(),
+                        ptr::null::<c_char>(),
+                    )
+                }
+            }
+            None => unsafe {
+                (self.add_console_command)(
+                    command.as_ptr(),
+                    Some(cb),
+                    description.as_ptr(),
+                    ptr::null::<c_char>(),
+                )
+            },
+        }
+    }
+
     pub fn set_module_number(
         &self,
         module: impl Into<Vec<u8>>,
@@ -238,29 +342,69 @@ impl LuaApi {
         unsafe { (self.set_module_string)(module.as_ptr(), name.as_ptr(), value.as_ptr()) }
     }
 
-    pub fn tolstring(&self, L: *mut lua_State, idx: i32) -> Option<&CStr> {
+    /// Returns the bytes of the string (or number, which Lua converts in place) at `idx`.
+    /// The slice may contain any bytes, including NUL.
+    pub fn tolstring(&self, L: *mut lua_State, idx: i32) -> Option<&[u8]> {
         let mut len: usize = 0;
 
         let c = unsafe { (self.tolstring)(L, idx, &mut len as *mut _) };
 
-        if len == 0 {
+        if c.is_null() {
             None
         } else {
-            // Safety: As long as `len > 0`, Lua guarantees the constraints that `CStr::from_ptr`
-            // requires.
-            Some(unsafe { CStr::from_ptr(c) })
+            // Safety: Lua guarantees that a non-null result points to `len` bytes, which stay
+            // valid as long as the value is on the stack.
+            Some(unsafe { std::slice::from_raw_parts(c as *const u8, len) })
         }
     }
 
+    pub fn tonumber(&self, L: *mut lua_State, idx: i32) -> f64 {
+        unsafe { (self.tonumber)(L, idx) }
+    }
+
+    pub fn toboolean(&self, L: *mut lua_State, idx: i32) -> bool {
+        unsafe { (self.toboolean)(L, idx) != 0 }
+    }
+
+    /// Pushes `s` as a Lua string, cut off at its first NUL byte like a C string would be. Use
+    /// `pushlstring` for text that may contain NUL bytes.
     pub fn pushstring(&self, L: *mut lua_State, s: impl Into<Vec<u8>>) {
-        let s = CString::new(s).expect("Invalid CString");
+        let mut s = s.into();
+        if let Some(nul) = s.iter().position(|&b| b == 0) {
+            s.truncate(nul);
+        }
+        // Safety: `s` has no NUL bytes left
+        let s = unsafe { CString::from_vec_unchecked(s) };
         unsafe { (self.pushstring)(L, s.as_ptr()) }
     }
 
+    /// Pushes `s` as a Lua string. Unlike `pushstring`, `s` may contain NUL bytes.
+    pub fn pushlstring(&self, L: *mut lua_State, s: &[u8]) {
+        unsafe { (self.pushlstring)(L, s.as_ptr() as *const c_char, s.len()) }
+    }
+
     pub fn pushboolean(&self, L: *mut lua_State, b: bool) {
         unsafe { (self.pushboolean)(L, b as i32) }
     }
 
+    pub fn pushnil(&self, L: *mut lua_State) {
+        unsafe { (self.pushnil)(L) }
+    }
+
+    pub fn pushnumber(&self, L: *mut lua_State, n: f64) {
+        unsafe { (self.pushnumber)(L, n) }
+    }
+
+    pub fn pushcfunction(&self, L: *mut lua_State, f: extern "C" fn(*mut lua_State) -> i32) {
+        unsafe { (self.pushcclosure)(L, Some(f), 0) }
+    }
+
+    /// Ensures there is space for at least `extra` more values on the stack. Returns `false` if
+    /// the stack can't grow that large.
+    pub fn checkstack(&self, L: *mut lua_State, extra: i32) -> bool {
+        unsafe { (self.checkstack)(L, extra) != 0 }
+    }
+
     pub fn pushvalue(&self, L: *mut lua_State, idx: i32) {
         unsafe { (self.pushvalue)(L, idx) }
     }
@@ -269,14 +413,107 @@ impl LuaApi {
         unsafe { (self.lib_ref)(L, idx) }
     }
 
+    pub fn lib_unref(&self, L: *mut lua_State, t: i32, r: i32) {
+        unsafe { (self.lib_unref)(L, t, r) }
+    }
+
     pub fn rawgeti(&self, L: *mut lua_State, idx: i32, n: i32) {
         unsafe { (self.rawgeti)(L, idx, n) }
     }
 
+    pub fn getfield(&self, L: *mut lua_State, idx: i32, k: impl Into<Vec<u8>>) {
+        let k = CString::new(k).expect("Invalid CString");
+        unsafe { (self.getfield)(L, idx, k.as_ptr()) }
+    }
+
+    /// Pushes a new table with space pre-allocated for `narr` array elements and `nrec` other
+    /// fields.
+    pub fn createtable(&self, L: *mut lua_State, narr: i32, nrec: i32) {
+        unsafe { (self.createtable)(L, narr, nrec) }
+    }
+
+    /// Pushes a new full userdata of `size` bytes, and returns its address. Lua owns the memory,
+    /// which is aligned like a `double`, and frees it without running any destructor.
+    pub fn newuserdata(&self, L: *mut lua_State, size: usize) -> *mut c_void {
+        unsafe { (self.newuserdata)(L, size) }
+    }
+
+    /// Returns the address of the userdata at `idx`, or null if it isn't one.
+    pub fn touserdata(&self, L: *mut lua_State, idx: i32) -> *mut c_void {
+        unsafe { (self.touserdata)(L, idx) }
+    }
+
+    /// Pushes the metatable of the value at `idx`. Returns `false`, and pushes nothing, if it
+    /// doesn't have one.
+    pub fn getmetatable(&self, L: *mut lua_State, idx: i32) -> bool {
+        unsafe { (self.getmetatable)(L, idx) != 0 }
+    }
+
+    /// Pops a table and sets it as the metatable of the value at `idx`.
+    pub fn setmetatable(&self, L: *mut lua_State, idx: i32) {
+        unsafe { (self.setmetatable)(L, idx) };
+    }
+
+    /// Pushes the metatable stored in the registry under `tname`, creating it if needed.
+    /// Returns `true` if it was created.
+    pub fn lib_newmetatable(&self, L: *mut lua_State, tname: impl Into<Vec<u8>>) -> bool {
+        let tname = CString::new(tname).expect("Invalid CString");
+        unsafe { (self.lib_newmetatable)(L, tname.as_ptr()) != 0 }
+    }
+
+    /// Pops a value and assigns it to field `k` of the table at `idx`.
+    pub fn setfield(&self, L: *mut lua_State, idx: i32, k: impl Into<Vec<u8>>) {
+        let k = CString::new(k).expect("Invalid CString");
+        unsafe { (self.setfield)(L, idx, k.as_ptr()) }
+    }
+
+    /// Pops a value and assigns it to index `n` of the table at `idx`, without invoking
+    /// metamethods.
+    pub fn rawseti(&self, L: *mut lua_State, idx: i32, n: i32) {
+        unsafe { (self.rawseti)(L, idx, n) }
+    }
+
+    /// Pops a key and a value and assigns them to the table at `idx`, without invoking
+    /// metamethods.
+    pub fn rawset(&self, L: *mut lua_State, idx: i32) {
+        unsafe { (self.rawset)(L, idx) }
+    }
+
+    /// Pops a key and pushes the next key-value pair of the table at `idx`. Returns `false`, and
+    /// pushes nothing, when there are no more pairs.
+    pub fn next(&self, L: *mut lua_State, idx: i32) -> bool {
+        unsafe { (self.next)(L, idx) != 0 }
+    }
+
+    /// Returns the address of the table, function, thread or userdata at `idx`. Only useful to
+    /// compare values' identity.
+    pub fn topointer(&self, L: *mut lua_State, idx: i32) -> *const c_void {
+        unsafe { (self.topointer)(L, idx) }
+    }
+
+    pub fn pop(&self, L: *mut lua_State) {
+        unsafe { (self.pop)(L) }
+    }
+
     pub fn call(&self, L: *mut lua_State, n_args: i32, n_results: i32) {
         unsafe { (self.call)(L, n_args, n_results) }
     }
 
+    /// Calls a function in protected mode. Returns `0` on success, or one of Lua's error codes,
+    /// in which case the error object is left on the stack.
+    /// `errfunc` is the stack index of an error handler, or `0` for none.
+    pub fn pcall(&self, L: *mut lua_State, n_args: i32, n_results: i32, errfunc: i32) -> i32 {
+        unsafe { (self.pcall)(L, n_args, n_results, errfunc) }
+    }
+
+    pub fn gettop(&self, L: *mut lua_State) -> i32 {
+        unsafe { (self.gettop)(L) }
+    }
+
+    pub fn settop(&self, L: *mut lua_State, idx: i32) {
+        unsafe { (self.settop)(L, idx) }
+    }
+
     pub fn get_script_environment_state(&self) -> *mut lua_State {
         unsafe { (self.getscriptenvironmentstate)() }
     }
@@ -0,0 +1,689 @@
+//! A fake Stingray engine for driving the plugin in tests.
+//!
+//! The engine's Lua API tables are filled with functions backed by a vanilla Lua 5.1 state, and
+//! the logging API records every line so that tests can assert on errors. The plugin is set up
+//! once per test binary, since `PLUGIN` can't be reset. Tests take turns through `engine()`,
+//! which also makes sure that only one thread touches the Lua state at a time.
+
+#![allow(dead_code)]
+
+use mlua_sys as ffi;
+use std::ffi::{CStr, CString, c_char, c_int, c_uint, c_void};
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Mutex, MutexGuard, Once, PoisonError};
+use std::time::{Duration, Instant};
+
+#[allow(non_upper_case_globals)]
+#[allow(non_camel_case_types)]
+#[allow(non_snake_case)]
+#[allow(dead_code)]
+#[allow(clippy::all)]
+mod bindings {
+    include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+}
+
+use bindings::PluginApiID;
+
+static STATE: AtomicPtr<ffi::lua_State> = AtomicPtr::new(ptr::null_mut());
+static ENGINE: Mutex<()> = Mutex::new(());
+static SETUP: Once = Once::new();
+static LOGS: Mutex<Vec<LogLine>> = Mutex::new(Vec::new());
+/// The console commands the plugin added, with their descriptions.
+static CONSOLE_COMMANDS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Debug)]
+pub struct LogLine {
+    pub level: Level,
+    pub message: String,
+}
+
+fn state() -> *mut ffi::lua_State {
+    STATE.load(Ordering::Acquire)
+}
+
+fn cast(l: *mut bindings::lua_State) -> *mut ffi::lua_State {
+    l.cast()
+}
+
+fn to_ffi_function(f: bindings::lua_CFunction) -> ffi::lua_CFunction {
+    let f = f.expect("engine functions are never registered as null");
+    // Safety: Both are C functions taking a `lua_State`. The plugin never raises Lua errors, so
+    // the unwind ABI doesn't matter.
+    unsafe {
+        mem::transmute::<unsafe extern "C" fn(*mut bindings::lua_State) -> c_int, ffi::lua_CFunction>(
+            f,
+        )
+    }
+}
+
+/// Pushes the global table `module`, creating it if needed.
+unsafe fn push_module(l: *mut ffi::lua_State, module: *const c_char) {
+    unsafe {
+        ffi::lua_getfield(l, ffi::LUA_GLOBALSINDEX, module);
+        if ffi::lua_type(l, -1) != ffi::LUA_TTABLE {
+            ffi::lua_pop(l, 1);
+            ffi::lua_createtable(l, 0, 0);
+            ffi::lua_pushvalue(l, -1);
+            ffi::lua_setfield(l, ffi::LUA_GLOBALSINDEX, module);
+        }
+    }
+}
+
+unsafe extern "C" fn add_module_function(
+    module: *const c_char,
+    name: *const c_char,
+    f: bindings::lua_CFunction,
+) {
+    let l = state();
+    unsafe {
+        push_module(l, module);
+        ffi::lua_pushcfunction(l, to_ffi_function(f));
+        ffi::lua_setfield(l, -2, name);
+        ffi::lua_pop(l, 1);
+    }
+}
+
+/// Adds the command to the global `Console` table, like the engine's `stingray.Console`.
+/// The documentation strings after `desc` are ignored.
+unsafe extern "C" fn add_console_command(
+    command: *const c_char,
+    f: bindings::lua_CFunction,
+    desc: *const c_char,
+    _docs: ...
+) {
+    let l = state();
+    unsafe {
+        push_module(l, c"Console".as_ptr());
+        ffi::lua_pushcfunction(l, to_ffi_function(f));
+        ffi::lua_setfield(l, -2, command);
+        ffi::lua_pop(l, 1);
+    }
+
+    let [command, desc] =
+        [command, desc].map(|s| unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned());
+    let mut commands = CONSOLE_COMMANDS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    commands.retain(|(name, _)| *name != command);
+    commands.push((command, desc));
+}
+
+unsafe extern "C" fn set_module_number(module: *const c_char, key: *const c_char, value: f64) {
+    let l = state();
+    unsafe {
+        push_module(l, module);
+        ffi::lua_pushnumber(l, value);
+        ffi::lua_setfield(l, -2, key);
+        ffi::lua_pop(l, 1);
+    }
+}
+
+unsafe extern "C" fn set_module_string(
+    module: *const c_char,
+    key: *const c_char,
+    value: *const c_char,
+) {
+    let l = state();
+    unsafe {
+        push_module(l, module);
+        ffi::lua_pushstring(l, value);
+        ffi::lua_setfield(l, -2, key);
+        ffi::lua_pop(l, 1);
+    }
+}
+
+unsafe extern "C" fn getscriptenvironmentstate() -> *mut bindings::lua_State {
+    state().cast()
+}
+
+unsafe extern "C" fn tolstring(
+    l: *mut bindings::lua_State,
+    idx: c_int,
+    len: *mut usize,
+) -> *const c_char {
+    unsafe { ffi::lua_tolstring(cast(l), idx, len) }
+}
+
+unsafe extern "C" fn pushstring(l: *mut bindings::lua_State, s: *const c_char) {
+    unsafe { ffi::lua_pushstring(cast(l), s) };
+}
+
+unsafe extern "C" fn pushlstring(l: *mut bindings::lua_State, s: *const c_char, len: usize) {
+    unsafe { ffi::lua_pushlstring(cast(l), s, len) };
+}
+
+unsafe extern "C" fn pushboolean(l: *mut bindings::lua_State, b: c_int) {
+    unsafe { ffi::lua_pushboolean(cast(l), b) }
+}
+
+unsafe extern "C" fn pushnil(l: *mut bindings::lua_State) {
+    unsafe { ffi::lua_pushnil(cast(l)) }
+}
+
+unsafe extern "C" fn pushnumber(l: *mut bindings::lua_State, n: f64) {
+    unsafe { ffi::lua_pushnumber(cast(l), n) }
+}
+
+unsafe extern "C" fn pushcclosure(
+    l: *mut bindings::lua_State,
+    f: bindings::lua_CFunction,
+    n: c_int,
+) {
+    unsafe { ffi::lua_pushcclosure(cast(l), to_ffi_function(f), n) }
+}
+
+unsafe extern "C" fn checkstack(l: *mut bindings::lua_State, size: c_int) -> c_int {
+    unsafe { ffi::lua_checkstack(cast(l), size) }
+}
+
+unsafe extern "C" fn pushvalue(l: *mut bindings::lua_State, idx: c_int) {
+    unsafe { ffi::lua_pushvalue(cast(l), idx) }
+}
+
+unsafe extern "C" fn lib_ref(l: *mut bindings::lua_State, t: c_int) -> c_int {
+    unsafe { ffi::luaL_ref(cast(l), t) }
+}
+
+unsafe extern "C" fn lib_unref(l: *mut bindings::lua_State, t: c_int, r: c_int) {
+    unsafe { ffi::luaL_unref(cast(l), t, r) }
+}
+
+unsafe extern "C" fn rawgeti(l: *mut bindings::lua_State, idx: c_int, n: c_int) {
+    unsafe { ffi::lua_rawgeti(cast(l), idx, n.into()) };
+}
+
+unsafe extern "C" fn getfield(l: *mut bindings::lua_State, idx: c_int, k: *const c_char) {
+    unsafe { ffi::lua_getfield(cast(l), idx, k) };
+}
+
+unsafe extern "C" fn createtable(l: *mut bindings::lua_State, narr: c_int, nrec: c_int) {
+    unsafe { ffi::lua_createtable(cast(l), narr, nrec) }
+}
+
+unsafe extern "C" fn newuserdata(l: *mut bindings::lua_State, size: usize) -> *mut c_void {
+    unsafe { ffi::lua_newuserdata(cast(l), size) }
+}
+
+unsafe extern "C" fn touserdata(l: *mut bindings::lua_State, idx: c_int) -> *mut c_void {
+    unsafe { ffi::lua_touserdata(cast(l), idx) }
+}
+
+unsafe extern "C" fn getmetatable(l: *mut bindings::lua_State, idx: c_int) -> c_int {
+    unsafe { ffi::lua_getmetatable(cast(l), idx) }
+}
+
+unsafe extern "C" fn setmetatable(l: *mut bindings::lua_State, idx: c_int) -> c_int {
+    unsafe { ffi::lua_setmetatable(cast(l), idx) }
+}
+
+unsafe extern "C" fn lib_newmetatable(l: *mut bindings::lua_State, tname: *const c_char) -> c_int {
+    unsafe { ffi::luaL_newmetatable(cast(l), tname) }
+}
+
+unsafe extern "C" fn setfield(l: *mut bindings::lua_State, idx: c_int, k: *const c_char) {
+    unsafe { ffi::lua_setfield(cast(l), idx, k) }
+}
+
+unsafe extern "C" fn rawseti(l: *mut bindings::lua_State, idx: c_int, n: c_int) {
+    unsafe { ffi::lua_rawseti(cast(l), idx, n.into()) }
+}
+
+unsafe extern "C" fn rawset(l: *mut bindings::lua_State, idx: c_int) {
+    unsafe { ffi::lua_rawset(cast(l), idx) }
+}
+
+unsafe extern "C" fn next(l: *mut bindings::lua_State, idx: c_int) -> c_int {
+    unsafe { ffi::lua_next(cast(l), idx) }
+}
+
+unsafe extern "C" fn topointer(l: *mut bindings::lua_State, idx: c_int) -> *const c_void {
+    unsafe { ffi::lua_topointer(cast(l), idx) }
+}
+
+/// The engine's `pop` always pops a single value.
+unsafe extern "C" fn pop(l: *mut bindings::lua_State) {
+    unsafe { ffi::lua_pop(cast(l), 1) }
+}
+
+unsafe extern "C" fn call(l: *mut bindings::lua_State, nargs: c_int, nresults: c_int) {
+    unsafe { ffi::lua_call(cast(l), nargs, nresults) }
+}
+
+unsafe extern "C" fn pcall(
+    l: *mut bindings::lua_State,
+    nargs: c_int,
+    nresults: c_int,
+    errfunc: c_int,
+) -> c_int {
+    unsafe { ffi::lua_pcall(cast(l), nargs, nresults, errfunc) }
+}
+
+unsafe extern "C" fn gettop(l: *mut bindings::lua_State) -> c_int {
+    unsafe { ffi::lua_gettop(cast(l)) }
+}
+
+unsafe extern "C" fn settop(l: *mut bindings::lua_State, idx: c_int) {
+    unsafe { ffi::lua_settop(cast(l), idx) }
+}
+
+unsafe extern "C" fn tonumber(l: *mut bindings::lua_State, idx: c_int) -> f64 {
+    unsafe { ffi::lua_tonumber(cast(l), idx) }
+}
+
+unsafe extern "C" fn toboolean(l: *mut bindings::lua_State, idx: c_int) -> c_int {
+    unsafe { ffi::lua_toboolean(cast(l), idx) }
+}
+
+unsafe extern "C" fn type_(l: *mut bindings::lua_State, idx: c_int) -> c_int {
+    unsafe { ffi::lua_type(cast(l), idx) }
+}
+
+unsafe extern "C" fn lua_typename(l: *mut bindings::lua_State, tp: c_int) -> *const c_char {
+    // Out of range types read past the end of Lua's name table
+    if !(ffi::LUA_TNONE..=ffi::LUA_TTHREAD).contains(&tp) {
+        return c"unknown".as_ptr();
+    }
+    unsafe { ffi::lua_typename(cast(l), tp) }
+}
+
+fn record_log(level: Level, message: *const c_char) {
+    let message = unsafe { CStr::from_ptr(message) }
+        .to_string_lossy()
+        .into_owned();
+    LOGS.lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(LogLine { level, message });
+}
+
+unsafe extern "C" fn log_info(_system: *const c_char, message: *const c_char) {
+    record_log(Level::Info, message);
+}
+
+unsafe extern "C" fn log_warning(_system: *const c_char, message: *const c_char) {
+    record_log(Level::Warning, message);
+}
+
+unsafe extern "C" fn log_error(_system: *const c_char, message: *const c_char) {
+    record_log(Level::Error, message);
+}
+
+fn lua_api() -> bindings::LuaApi {
+    // Safety: The table only consists of optional function pointers, for which zero is `None`.
+    let mut api: bindings::LuaApi = unsafe { mem::zeroed() };
+    api.add_module_function = Some(add_module_function);
+    api.add_console_command = Some(add_console_command);
+    api.set_module_number = Some(set_module_number);
+    api.set_module_string = Some(set_module_string);
+    api.getscriptenvironmentstate = Some(getscriptenvironmentstate);
+    api.tolstring = Some(tolstring);
+    api.pushstring = Some(pushstring);
+    api.pushlstring = Some(pushlstring);
+    api.pushboolean = Some(pushboolean);
+    api.pushnil = Some(pushnil);
+    api.pushnumber = Some(pushnumber);
+    api.pushcclosure = Some(pushcclosure);
+    api.checkstack = Some(checkstack);
+    api.pushvalue = Some(pushvalue);
+    api.lib_ref = Some(lib_ref);
+    api.lib_unref = Some(lib_unref);
+    api.rawgeti = Some(rawgeti);
+    api.getfield = Some(getfield);
+    api.createtable = Some(createtable);
+    api.newuserdata = Some(newuserdata);
+    api.touserdata = Some(touserdata);
+    api.getmetatable = Some(getmetatable);
+    api.setmetatable = Some(setmetatable);
+    api.lib_newmetatable = Some(lib_newmetatable);
+    api.setfield = Some(setfield);
+    api.rawseti = Some(rawseti);
+    api.rawset = Some(rawset);
+    api.next = Some(next);
+    api.topointer = Some(topointer);
+    api.pop = Some(pop);
+    api.call = Some(call);
+    api.pcall = Some(pcall);
+    api.gettop = Some(gettop);
+    api.settop = Some(settop);
+    api.tonumber = Some(tonumber);
+    api.toboolean = Some(toboolean);
+    api.type_ = Some(type_);
+    api.lua_typename = Some(lua_typename);
+    api
+}
+
+unsafe extern "C" fn get_engine_api(id: c_uint) -> *mut c_void {
+    static LUA_API: AtomicPtr<bindings::LuaApi> = AtomicPtr::new(ptr::null_mut());
+    static LOGGING_API: AtomicPtr<bindings::LoggingApi> = AtomicPtr::new(ptr::null_mut());
+
+    if id == PluginApiID::LUA_API_ID as c_uint {
+        if LUA_API.load(Ordering::Acquire).is_null() {
+            LUA_API.store(Box::into_raw(Box::new(lua_api())), Ordering::Release);
+        }
+        LUA_API.load(Ordering::Acquire).cast()
+    } else if id == PluginApiID::LOGGING_API_ID as c_uint {
+        if LOGGING_API.load(Ordering::Acquire).is_null() {
+            let api = bindings::LoggingApi {
+                info: Some(log_info),
+                warning: Some(log_warning),
+                error: Some(log_error),
+            };
+            LOGGING_API.store(Box::into_raw(Box::new(api)), Ordering::Release);
+        }
+        LOGGING_API.load(Ordering::Acquire).cast()
+    } else {
+        panic!("the plugin asked for an API the fake engine doesn't provide: {id}");
+    }
+}
+
+/// Exclusive access to the fake engine and the plugin running in it.
+pub struct Engine {
+    _guard: MutexGuard<'static, ()>,
+}
+
+/// Waits for other tests to finish with the engine, and sets the plugin up on first use.
+pub fn engine() -> Engine {
+    let guard = ENGINE.lock().unwrap_or_else(PoisonError::into_inner);
+
+    SETUP.call_once(|| {
+        let l = unsafe { ffi::luaL_newstate() };
+        unsafe { ffi::luaL_openlibs(l) };
+        STATE.store(l, Ordering::Release);
+        darktide_plugin_rtc::setup_game(Some(get_engine_api));
+    });
+
+    // A previous test may have failed halfway through
+    unsafe { ffi::lua_settop(state(), 0) };
+    LOGS.lock().unwrap_or_else(PoisonError::into_inner).clear();
+
+    Engine { _guard: guard }
+}
+
+impl Engine {
+    /// Runs a chunk of Lua code, panicking with the Lua error if it fails.
+    pub fn exec(&self, code: &str) {
+        let l = state();
+        unsafe {
+            let top = ffi::lua_gettop(l);
+            self.load(code);
+            let status = ffi::lua_pcall(l, 0, 0, 0);
+            if status != 0 {
+                let err = self.pop_string();
+                ffi::lua_settop(l, top);
+                panic!("Lua error: {err}\n{}", self.dump_logs());
+            }
+        }
+    }
+
+    /// Evaluates a Lua expression and converts the result with `read`.
+    fn eval<T>(&self, expr: &str, read: impl FnOnce(*mut ffi::lua_State) -> T) -> T {
+        let l = state();
+        unsafe {
+            let top = ffi::lua_gettop(l);
+            self.load(&format!("return {expr}"));
+            if ffi::lua_pcall(l, 0, 1, 0) != 0 {
+                let err = self.pop_string();
+                ffi::lua_settop(l, top);
+                panic!("Lua error: {err}\n{}", self.dump_logs());
+            }
+            let value = read(l);
+            ffi::lua_settop(l, top);
+            value
+        }
+    }
+
+    /// Runs a console command line, which the engine splits into the command and its string
+    /// arguments.
+    pub fn console(&self, line: &str) {
+        let mut words = line.split_whitespace();
+        let command = words.next().expect("the console line is empty");
+        let args: Vec<String> = words.map(|word| format!("{word:?}")).collect();
+        self.exec(&format!("Console.{command}({})", args.join(", ")));
+    }
+
+    /// The description of a console command the plugin added.
+    pub fn console_help(&self, command: &str) -> Option<String> {
+        CONSOLE_COMMANDS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .find(|(name, _)| name == command)
+            .map(|(_, desc)| desc.clone())
+    }
+
+    pub fn eval_bool(&self, expr: &str) -> bool {
+        self.eval(expr, |l| unsafe { ffi::lua_toboolean(l, -1) != 0 })
+    }
+
+    pub fn eval_number(&self, expr: &str) -> Option<f64> {
+        self.eval(expr, |l| unsafe {
+            (ffi::lua_type(l, -1) == ffi::LUA_TNUMBER).then(|| ffi::lua_tonumber(l, -1))
+        })
+    }
+
+    /// Evaluates to the string or number `expr` results in, or `None` for anything else.
+    pub fn eval_string(&self, expr: &str) -> Option<String> {
+        self.eval(expr, |l| unsafe {
+            match ffi::lua_type(l, -1) {
+                ffi::LUA_TSTRING | ffi::LUA_TNUMBER => {
+                    let mut len = 0;
+                    let s = ffi::lua_tolstring(l, -1, &mut len);
+                    let bytes = std::slice::from_raw_parts(s.cast::<u8>(), len);
+                    Some(String::from_utf8_lossy(bytes).into_owned())
+                }
+                _ => None,
+            }
+        })
+    }
+
+    pub fn update(&self) {
+        darktide_plugin_rtc::update_game(1.0 / 30.0);
+    }
+
+    /// Shuts the plugin down, along with its runtime. It stays registered, and webrtc can't
+    /// connect again in the same process, so this should be the last thing a test binary does
+    /// with it.
+    pub fn shutdown(&self) {
+        darktide_plugin_rtc::shutdown_game();
+    }
+
+    /// Hot reloads the plugin, as the engine does when the DLL is swapped. The old and new
+    /// plugin are the same build here.
+    pub fn reload(&self) {
+        let state = darktide_plugin_rtc::start_reload(Some(get_engine_api));
+        // Safety: The state is passed on untouched, like the engine does.
+        unsafe { darktide_plugin_rtc::finish_reload(Some(get_engine_api), state) };
+    }
+
+    /// Updates the plugin every few milliseconds until `done` returns true. Returns false if
+    /// that doesn't happen before `timeout`.
+    pub fn update_until(&self, timeout: Duration, mut done: impl FnMut(&Self) -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.update();
+            if done(self) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// The number of values on the Lua stack.
+    pub fn stack_size(&self) -> i32 {
+        unsafe { ffi::lua_gettop(state()) }
+    }
+
+    /// The lines logged since the engine was acquired.
+    pub fn logs(&self) -> Vec<LogLine> {
+        LOGS.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Whether an error containing `text` has been logged.
+    pub fn logged_error(&self, text: &str) -> bool {
+        self.logs()
+            .iter()
+            .any(|line| line.level == Level::Error && line.message.contains(text))
+    }
+
+    /// The log, formatted for assertion messages.
+    pub fn dump_logs(&self) -> String {
+        self.logs()
+            .iter()
+            .map(|line| format!("[{:?}] {}", line.level, line.message))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    unsafe fn load(&self, code: &str) {
+        let l = state();
+        let name = CString::new("=test").unwrap();
+        unsafe {
+            if ffi::luaL_loadbuffer(l, code.as_ptr().cast(), code.len(), name.as_ptr()) != 0 {
+                let err = self.pop_string();
+                panic!("Lua syntax error: {err}");
+            }
+        }
+    }
+
+    unsafe fn pop_string(&self) -> String {
+        let l = state();
+        unsafe {
+            let mut len = 0;
+            let s = ffi::lua_tolstring(l, -1, &mut len);
+            let message = if s.is_null() {
+                "(error object is not a string)".to_string()
+            } else {
+                String::from_utf8_lossy(std::slice::from_raw_parts(s.cast::<u8>(), len))
+                    .into_owned()
+            };
+            ffi::lua_pop(l, 1);
+            message
+        }
+    }
+}
+
+/// Starts a matchbox signaling server on a free localhost port, so that peers can meet without
+/// the internet. Returns its `ws://` url. The server runs until the test binary exits.
+pub fn signaling_server() -> String {
+    let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to build the signaling server's runtime");
+        runtime.block_on(async {
+            let mut server =
+                matchbox_signaling::SignalingServer::full_mesh_builder(([127, 0, 0, 1], 0)).build();
+            let addr = server.bind().expect("failed to bind the signaling server");
+            addr_tx.send(addr).unwrap();
+            server.serve().await.expect("the signaling server stopped");
+        });
+    });
+    let addr = addr_rx
+        .recv()
+        .expect("the signaling server failed to start");
+    format!("ws://{addr}")
+}
+
+/// Runs the ignored test `name` of the current test binary in a new process, as a second plugin
+/// instance. `env` is passed to it, for example to tell it the signaling server's url.
+pub fn spawn_peer(name: &str, env: &[(&str, &str)]) -> std::process::Child {
+    let exe = std::env::current_exe().expect("failed to find the test binary");
+    std::process::Command::new(exe)
+        .args([name, "--exact", "--ignored", "--nocapture"])
+        .envs(env.iter().copied())
+        .spawn()
+        .expect("failed to start the peer process")
+}
+
+/// `rtc-cli` joined to a room as another peer, printing JSON.
+pub struct Cli {
+    child: std::process::Child,
+    stdin: std::process::ChildStdin,
+    /// Every line it printed so far.
+    printed: std::sync::Arc<Mutex<Vec<String>>>,
+}
+
+impl Cli {
+    /// How long to wait for the cli to print something.
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Joins `room` on the signaling server at `url`. The STUN server doesn't exist, so that
+    /// peers only try the addresses of this machine.
+    pub fn spawn(url: &str, room: &str) -> Self {
+        Self::spawn_with(url, room, &[])
+    }
+
+    /// Like `spawn`, with more options for the cli.
+    pub fn spawn_with(url: &str, room: &str, options: &[&str]) -> Self {
+        use std::io::{BufRead, BufReader};
+        use std::process::{Command, Stdio};
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rtc-cli"))
+            .args(["--json", "--url", url, "--ice-server", "stun:127.0.0.1:9"])
+            .args(options)
+            .arg(room)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("failed to start rtc-cli");
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let printed = std::sync::Arc::new(Mutex::new(Vec::new()));
+        std::thread::spawn({
+            let printed = printed.clone();
+            move || {
+                for line in stdout.lines().map_while(Result::ok) {
+                    printed.lock().unwrap().push(line);
+                }
+            }
+        });
+        Self {
+            child,
+            stdin,
+            printed,
+        }
+    }
+
+    /// Types `line` into the cli, which sends it to all peers.
+    pub fn type_line(&mut self, line: &str) {
+        use std::io::Write;
+        writeln!(self.stdin, "{line}").expect("rtc-cli has exited");
+    }
+
+    /// How many printed lines contain `text`.
+    pub fn count(&self, text: &str) -> usize {
+        let printed = self.printed.lock().unwrap();
+        printed.iter().filter(|line| line.contains(text)).count()
+    }
+
+    /// Updates the engine until the cli has printed `count` lines containing `text`.
+    pub fn expect(&self, engine: &Engine, text: &str, count: usize) {
+        let found = engine.update_until(Self::TIMEOUT, |_| self.count(text) >= count);
+        assert!(
+            found,
+            "rtc-cli never printed {text}, only:\n{}",
+            self.printed.lock().unwrap().join("\n")
+        );
+    }
+
+    /// Leaves the room, and returns whether the cli exited successfully.
+    pub fn quit(mut self) -> bool {
+        self.type_line("/quit");
+        self.child
+            .wait()
+            .expect("failed to wait for rtc-cli")
+            .success()
+    }
+}
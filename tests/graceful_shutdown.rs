@@ -0,0 +1,46 @@
+//! Shutting down stops the runtime, which webrtc can't recover from in the same process, so this
+//! test has a binary of its own.
+
+mod common;
+
+use common::{Cli, engine, signaling_server};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[test]
+fn shutdown_flushes_queued_messages() {
+    let engine = engine();
+    let url = signaling_server();
+    let cli = Cli::spawn(&url, "goodbye");
+
+    engine.exec(&format!(
+        r#"
+        connected = {{}}
+        room = RTC.connect("goodbye", function(peer)
+            table.insert(connected, peer)
+        end, function() end, function() end, {{
+            signaling_url = "{url}",
+            ice_servers = {{ urls = "stun:127.0.0.1:9" }},
+        }})
+        "#
+    ));
+    let joined = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#connected == 1"));
+    assert!(joined, "rtc-cli never connected\n{}", engine.dump_logs());
+    cli.expect(&engine, r#""event":"peer_joined""#, 1);
+
+    // Queued, but the game exits before the next update
+    engine.exec(r#"RTC.send("goodbye", "all", "last words", "reliable")"#);
+    engine.shutdown();
+    assert!(
+        engine
+            .logs()
+            .iter()
+            .any(|line| line.message.contains("Shutting down"))
+    );
+
+    cli.expect(&engine, r#""message":"last words""#, 1);
+    // The signaling server tells the room that we left
+    cli.expect(&engine, r#""event":"peer_left""#, 1);
+    assert!(cli.quit(), "rtc-cli failed");
+}
@@ -0,0 +1,489 @@
+//! End-to-end tests against a signaling server on localhost. The plugin can only run once per
+//! process, so the other peer is this test binary again, running one of the ignored tests below.
+
+mod common;
+
+use common::{Cli, engine, signaling_server, spawn_peer};
+use std::time::Duration;
+
+const URL_VAR: &str = "RTC_LOOPBACK_URL";
+const ROOM: &str = "loopback";
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Connects to `ROOM` with callbacks that record everything in Lua tables. The STUN server
+/// doesn't exist, so that peers only try the addresses of this machine.
+fn connect(engine: &common::Engine, url: &str) {
+    engine.exec(&format!(
+        r#"
+        connected = {{}}
+        messages = {{}}
+        disconnected = {{}}
+        room = RTC.connect("{ROOM}", function(peer)
+            table.insert(connected, peer)
+        end, function(message, peer, mode)
+            table.insert(messages, {{ message = message, peer = peer, mode = mode }})
+        end, function(peer)
+            table.insert(disconnected, peer)
+        end, {{
+            signaling_url = "{url}",
+            ice_servers = {{ urls = "stun:127.0.0.1:9" }},
+        }})
+        "#
+    ));
+}
+
+#[test]
+fn peers_exchange_messages() {
+    let engine = engine();
+    let url = signaling_server();
+    let mut peer = spawn_peer("echo_peer", &[(URL_VAR, &url)]);
+    connect(&engine, &url);
+
+    let joined = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#connected == 1"));
+    assert!(joined, "the peer never connected\n{}", engine.dump_logs());
+    assert!(engine.eval_bool(&format!("RTC.peers('{ROOM}')[1] == connected[1]")));
+
+    assert!(engine.eval_bool(&format!(
+        "RTC.send('{ROOM}', connected[1], 'hello', 'reliable')"
+    )));
+    let echoed = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#messages == 1"));
+    assert!(echoed, "the peer never replied\n{}", engine.dump_logs());
+    assert_eq!(
+        engine.eval_string("messages[1].message").as_deref(),
+        Some("echo: hello")
+    );
+    assert!(engine.eval_bool("messages[1].peer == connected[1]"));
+    assert_eq!(
+        engine.eval_string("messages[1].mode").as_deref(),
+        Some("reliable")
+    );
+
+    // The peer leaves the room when told to
+    assert!(engine.eval_bool(&format!("RTC.send('{ROOM}', 'all', 'bye', 'reliable')")));
+    let left = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#disconnected == 1"));
+    assert!(left, "the peer never disconnected\n{}", engine.dump_logs());
+    assert!(engine.eval_bool("disconnected[1] == connected[1]"));
+    assert!(engine.eval_bool(&format!("#RTC.peers('{ROOM}') == 0")));
+
+    let status = peer.wait().expect("failed to wait for the peer process");
+    assert!(status.success(), "the peer process failed");
+
+    engine.exec(&format!("RTC.disconnect('{ROOM}')"));
+    engine.update();
+}
+
+/// The other side of `peers_exchange_messages`. Echoes messages back to their sender until it
+/// receives "bye", then disconnects.
+#[test]
+#[ignore = "started by peers_exchange_messages"]
+fn echo_peer() {
+    let url = std::env::var(URL_VAR).expect("echo_peer is started by peers_exchange_messages");
+    let engine = engine();
+    connect(&engine, &url);
+
+    let done = engine.update_until(TIMEOUT, |engine| {
+        // Replies are sent from here rather than from `on_message`
+        engine.eval_bool(&format!(
+            r#"(function()
+                for _, message in ipairs(messages) do
+                    if message.message == "bye" then
+                        return true
+                    end
+                    RTC.send("{ROOM}", message.peer, "echo: " .. message.message, message.mode)
+                end
+                messages = {{}}
+                return false
+            end)()"#
+        ))
+    });
+    assert!(done, "never received bye\n{}", engine.dump_logs());
+
+    engine.exec(&format!("RTC.disconnect('{ROOM}')"));
+    engine.update();
+}
+
+#[test]
+fn remote_errors_reach_the_caller() {
+    let engine = engine();
+    let url = signaling_server();
+    let mut peer = spawn_peer("failing_peer", &[(URL_VAR, &url)]);
+    connect(&engine, &url);
+
+    let joined = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#connected == 1"));
+    assert!(joined, "the peer never connected\n{}", engine.dump_logs());
+
+    // The peer registers its method once connected, so wait for it to say so
+    let ready = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#messages == 1"));
+    assert!(ready, "the peer never got ready\n{}", engine.dump_logs());
+    engine.exec(&format!(
+        r#"
+        RTC.call("{ROOM}", connected[1], "fail", nil, function(ok, err)
+            reply = {{ ok = ok, err = err }}
+        end)
+        "#
+    ));
+    let replied = engine.update_until(TIMEOUT, |engine| engine.eval_bool("reply ~= nil"));
+    assert!(replied, "the peer never replied\n{}", engine.dump_logs());
+    assert!(engine.eval_bool("reply.ok == false"));
+    // The error keeps its NUL byte
+    assert!(engine.eval_bool(r#"reply.err:sub(-3) == "a\0b""#));
+
+    assert!(engine.eval_bool(&format!("RTC.send('{ROOM}', 'all', 'bye', 'reliable')")));
+    let left = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#disconnected == 1"));
+    assert!(left, "the peer never disconnected\n{}", engine.dump_logs());
+    let status = peer.wait().expect("failed to wait for the peer process");
+    assert!(status.success(), "the peer process failed");
+
+    engine.exec(&format!("RTC.disconnect('{ROOM}')"));
+    engine.update();
+}
+
+/// The other side of `remote_errors_reach_the_caller`. Answers calls to "fail" with an error
+/// containing a NUL byte, until it receives "bye".
+#[test]
+#[ignore = "started by remote_errors_reach_the_caller"]
+fn failing_peer() {
+    let url =
+        std::env::var(URL_VAR).expect("failing_peer is started by remote_errors_reach_the_caller");
+    let engine = engine();
+    connect(&engine, &url);
+    engine.exec(&format!(
+        r#"RTC.register_method("{ROOM}", "fail", function() error("a\0b") end)"#
+    ));
+
+    let joined = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#connected == 1"));
+    assert!(joined, "the caller never connected\n{}", engine.dump_logs());
+    assert!(engine.eval_bool(&format!(
+        "RTC.send('{ROOM}', connected[1], 'ready', 'reliable')"
+    )));
+
+    let done = engine.update_until(TIMEOUT, |engine| {
+        engine.eval_bool(r#"messages[1] ~= nil and messages[#messages].message == "bye""#)
+    });
+    assert!(done, "never received bye\n{}", engine.dump_logs());
+
+    engine.exec(&format!("RTC.disconnect('{ROOM}')"));
+    engine.update();
+}
+
+#[test]
+fn cli_prints_room_traffic() {
+    let engine = engine();
+    let url = signaling_server();
+    let mut cli = Cli::spawn(&url, ROOM);
+
+    connect(&engine, &url);
+    let joined = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#connected == 1"));
+    assert!(joined, "rtc-cli never connected\n{}", engine.dump_logs());
+    cli.expect(&engine, r#""event":"peer_joined""#, 1);
+
+    engine.exec(&format!(
+        r#"
+        RTC.send("{ROOM}", "all", "hello\n", "reliable")
+        RTC.send_table("{ROOM}", "all", {{ 1, "two" }}, "reliable")
+        "#
+    ));
+    cli.expect(&engine, r#""mode":"reliable","message":"hello\n"}"#, 1);
+    cli.expect(&engine, r#""value":[1,"two"]}"#, 1);
+
+    cli.type_line("hi there");
+    let received = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#messages == 1"));
+    assert!(
+        received,
+        "the typed line never arrived\n{}",
+        engine.dump_logs()
+    );
+    assert_eq!(
+        engine.eval_string("messages[1].message").as_deref(),
+        Some("hi there")
+    );
+
+    assert!(cli.quit(), "rtc-cli failed");
+    let left = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#disconnected == 1"));
+    assert!(left, "rtc-cli never left\n{}", engine.dump_logs());
+
+    engine.exec(&format!("RTC.disconnect('{ROOM}')"));
+    engine.update();
+}
+
+#[test]
+fn polled_room_buffers_events() {
+    let engine = engine();
+    let url = signaling_server();
+    let mut cli = Cli::spawn(&url, ROOM);
+
+    engine.exec(&format!(
+        r#"
+        events = {{}}
+        room = RTC.connect("{ROOM}", {{
+            signaling_url = "{url}",
+            ice_servers = {{ urls = "stun:127.0.0.1:9" }},
+        }})
+        function poll_until(kind)
+            for _, event in ipairs(room:poll()) do
+                table.insert(events, event)
+            end
+            return events[#events] and events[#events].type == kind
+        end
+        function types()
+            local types = {{}}
+            for _, event in ipairs(events) do
+                table.insert(types, event.state or event.type)
+            end
+            return table.concat(types, ",")
+        end
+        "#
+    ));
+    let joined = engine.update_until(TIMEOUT, |engine| {
+        engine.eval_bool("poll_until('peer_connected')")
+    });
+    assert!(
+        joined,
+        "rtc-cli never connected
+{}",
+        engine.dump_logs()
+    );
+    cli.expect(&engine, r#""event":"peer_joined""#, 1);
+
+    cli.type_line("polled");
+    let received = engine.update_until(TIMEOUT, |engine| engine.eval_bool("poll_until('message')"));
+    assert!(
+        received,
+        "the typed line never arrived\n{}",
+        engine.dump_logs()
+    );
+    assert_eq!(
+        engine.eval_string("events[#events].data").as_deref(),
+        Some("polled")
+    );
+    assert!(engine.eval_bool("events[#events].peer == events[#events - 1].peer"));
+
+    assert!(cli.quit(), "rtc-cli failed");
+    let left = engine.update_until(TIMEOUT, |engine| {
+        engine.eval_bool("poll_until('peer_disconnected')")
+    });
+    assert!(left, "rtc-cli never left\n{}", engine.dump_logs());
+    assert_eq!(
+        engine.eval_string("types()").as_deref(),
+        Some("connecting,connected,peer_connected,message,peer_disconnected")
+    );
+    assert!(engine.eval_bool("RTC.stats(room).dropped_events == 0"));
+
+    engine.exec("room:close()");
+    engine.update();
+}
+
+#[test]
+fn peers_exchange_presence() {
+    let engine = engine();
+    let url = signaling_server();
+    let cli = Cli::spawn_with(&url, ROOM, &["--presence", "name=cli"]);
+
+    engine.exec(&format!(
+        r#"
+        connected = {{}}
+        updates = {{}}
+        room = RTC.connect("{ROOM}", function(peer)
+            table.insert(connected, peer)
+        end, function() end, function() end, {{
+            signaling_url = "{url}",
+            ice_servers = {{ urls = "stun:127.0.0.1:9" }},
+            presence = {{ name = "engine", version = 3 }},
+            on_peer_updated = function(peer, info)
+                table.insert(updates, {{ peer = peer, info = info }})
+            end,
+        }})
+        "#
+    ));
+    let updated = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#updates == 1"));
+    assert!(
+        updated,
+        "rtc-cli never sent its presence\n{}",
+        engine.dump_logs()
+    );
+    assert!(engine.eval_bool("updates[1].peer == connected[1]"));
+    assert_eq!(
+        engine.eval_string("updates[1].info.name").as_deref(),
+        Some("cli")
+    );
+    assert_eq!(
+        engine
+            .eval_string(&format!("RTC.peer_info('{ROOM}', connected[1]).name"))
+            .as_deref(),
+        Some("cli")
+    );
+    cli.expect(&engine, r#""event":"presence""#, 1);
+    assert_eq!(cli.count(r#""value":{"name":"engine","version":3}"#), 1);
+
+    // The same can be seen and done from the console
+    let peer = engine
+        .eval_string("connected[1]")
+        .expect("no peer connected");
+    engine.console(&format!("rtc_peers {ROOM}"));
+    assert!(
+        engine.logs().iter().any(|line| line
+            .message
+            .starts_with(&format!("  {peer}, connected for"))
+            && line.message.ends_with(", has presence")),
+        "rtc_peers didn't list the peer\n{}",
+        engine.dump_logs()
+    );
+    engine.console(&format!("rtc_send {ROOM} {peer} hello from the console"));
+    cli.expect(&engine, "hello from the console", 1);
+
+    assert!(engine.eval_bool(&format!(
+        "RTC.set_presence('{ROOM}', {{ name = 'renamed' }})"
+    )));
+    cli.expect(&engine, r#""value":{"name":"renamed"}"#, 1);
+    assert!(engine.eval_bool(&format!("RTC.set_presence('{ROOM}', nil)")));
+    cli.expect(&engine, r#""event":"presence","peer""#, 3);
+    assert_eq!(cli.count(r#""value":null"#), 1);
+
+    assert!(cli.quit(), "rtc-cli failed");
+    let left = engine.update_until(TIMEOUT, |engine| {
+        engine.eval_bool(&format!("RTC.peer_info('{ROOM}', connected[1]) == nil"))
+    });
+    assert!(left, "rtc-cli's presence was kept\n{}", engine.dump_logs());
+
+    engine.exec(&format!("RTC.disconnect('{ROOM}')"));
+    engine.update();
+}
+
+#[test]
+fn receive_rate_limit_drops_messages() {
+    let engine = engine();
+    let url = signaling_server();
+    let mut cli = Cli::spawn(&url, ROOM);
+
+    engine.exec(&format!(
+        r#"
+        connected = {{}}
+        messages = {{}}
+        limited = {{}}
+        room = RTC.connect("{ROOM}", function(peer)
+            table.insert(connected, peer)
+        end, function(message)
+            table.insert(messages, message)
+        end, function() end, {{
+            signaling_url = "{url}",
+            ice_servers = {{ urls = "stun:127.0.0.1:9" }},
+            rate_limits = {{ receive_per_peer = {{ rate = 0.001, burst = 2 }} }},
+            on_rate_limited = function(peer, direction, dropped)
+                table.insert(limited, {{ peer = peer, direction = direction, dropped = dropped }})
+            end,
+        }})
+        "#
+    ));
+    let joined = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#connected == 1"));
+    assert!(joined, "rtc-cli never connected\n{}", engine.dump_logs());
+    cli.expect(&engine, r#""event":"peer_joined""#, 1);
+
+    for line in ["one", "two", "three", "four", "five"] {
+        cli.type_line(line);
+    }
+    // The drops may be split over two reports, if they arrive around when one is due
+    let reported = engine.update_until(TIMEOUT, |engine| {
+        engine.eval_bool(
+            r#"(function()
+                local dropped = 0
+                for _, report in ipairs(limited) do
+                    assert(report.peer == connected[1] and report.direction == "receive")
+                    dropped = dropped + report.dropped
+                end
+                return dropped == 3
+            end)()"#,
+        )
+    });
+    assert!(
+        reported,
+        "drops were never reported\n{}",
+        engine.dump_logs()
+    );
+    assert_eq!(
+        engine.eval_string("table.concat(messages, ',')").as_deref(),
+        Some("one,two")
+    );
+
+    assert!(cli.quit(), "rtc-cli failed");
+    engine.exec(&format!("RTC.disconnect('{ROOM}')"));
+    engine.update();
+}
+
+#[test]
+fn captures_replay_room_traffic() {
+    let engine = engine();
+    let url = signaling_server();
+    let path = std::env::temp_dir().join(format!("rtc-capture-{}.rtccap", std::process::id()));
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut cli = Cli::spawn(&url, ROOM);
+
+    connect(&engine, &url);
+    assert!(engine.eval_bool(&format!("RTC.start_capture('{ROOM}', '{path}')")));
+    let joined = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#connected == 1"));
+    assert!(joined, "rtc-cli never connected\n{}", engine.dump_logs());
+    cli.expect(&engine, r#""event":"peer_joined""#, 1);
+
+    cli.type_line("captured");
+    let received = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#messages == 1"));
+    assert!(
+        received,
+        "the typed line never arrived\n{}",
+        engine.dump_logs()
+    );
+    assert!(cli.quit(), "rtc-cli failed");
+    let left = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#disconnected == 1"));
+    assert!(left, "rtc-cli never left\n{}", engine.dump_logs());
+
+    assert!(engine.eval_bool(&format!("RTC.stop_capture('{ROOM}')")));
+    assert!(!engine.eval_bool(&format!("RTC.stop_capture('{ROOM}')")));
+    engine.exec(&format!(
+        r#"
+        RTC.disconnect("{ROOM}")
+        captured = {{ peer = connected[1], message = messages[1].message }}
+        "#
+    ));
+    engine.update();
+
+    // The same callbacks are called again, without a peer on the other end
+    engine.exec(&format!(
+        r#"
+        connected = {{}}
+        messages = {{}}
+        disconnected = {{}}
+        states = {{}}
+        room = RTC.connect("replayed", function(peer)
+            table.insert(connected, peer)
+        end, function(message, peer, mode)
+            table.insert(messages, {{ message = message, peer = peer, mode = mode }})
+        end, function(peer)
+            table.insert(disconnected, peer)
+        end, {{
+            replay = "{path}",
+            on_connection_state = function(state) table.insert(states, state) end,
+        }})
+        "#
+    ));
+    let finished = engine.update_until(TIMEOUT, |engine| {
+        engine.eval_bool("states[#states] == 'closed'")
+    });
+    assert!(
+        finished,
+        "the replay never finished\n{}",
+        engine.dump_logs()
+    );
+    assert_eq!(
+        engine.eval_string("table.concat(states, ',')").as_deref(),
+        Some("connecting,connected,closed")
+    );
+    assert!(engine.eval_bool("#connected == 1 and connected[1] == captured.peer"));
+    assert!(engine.eval_bool("#messages == 1 and messages[1].message == captured.message"));
+    assert_eq!(
+        engine.eval_string("messages[1].mode").as_deref(),
+        Some("reliable")
+    );
+    assert!(engine.eval_bool("#disconnected == 1 and disconnected[1] == captured.peer"));
+
+    engine.exec("RTC.disconnect('replayed')");
+    engine.update();
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,875 @@
+mod common;
+
+use common::engine;
+use std::time::Duration;
+
+/// A signaling url nothing listens on, so connections fail right away.
+const UNREACHABLE: &str = "ws://127.0.0.1:9";
+const PEER: &str = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+/// Connects to `room` through `UNREACHABLE`, waiting a minute before reconnecting, so that the
+/// room stays pending and keeps what is sent to it queued.
+fn connect_pending(engine: &common::Engine, room: &str) {
+    engine.exec(&format!(
+        r#"
+        local function noop() end
+        room = RTC.connect("{room}", noop, noop, noop, {{
+            signaling_url = "{UNREACHABLE}",
+            reconnect = {{ initial_delay = 60 }},
+        }})
+        "#
+    ));
+}
+
+#[test]
+fn setup_registers_module() {
+    let engine = engine();
+
+    assert_eq!(
+        engine.eval_string("RTC.version").as_deref(),
+        Some(env!("CARGO_PKG_VERSION"))
+    );
+    for function in [
+        "connect",
+        "send",
+        "send_table",
+        "call",
+        "register_method",
+        "disconnect",
+        "set_signaling_url",
+        "is_connected",
+        "my_id",
+        "peers",
+        "rooms",
+        "stats",
+        "poll",
+    ] {
+        assert!(
+            engine.eval_bool(&format!("type(RTC.{function}) == 'function'")),
+            "RTC.{function} is not registered"
+        );
+    }
+}
+
+#[test]
+fn send_validates_arguments() {
+    let engine = engine();
+
+    assert!(!engine.eval_bool("RTC.send(nil, 'all', 'hello')"));
+    assert!(engine.logged_error("send: first argument is nil"));
+
+    assert!(!engine.eval_bool("RTC.send('send_validates', 'someone', 'hello')"));
+    assert!(engine.logged_error("is not \"all\" or a valid Uuid"));
+
+    assert!(!engine.eval_bool("RTC.send('send_validates', 'all', 'hello', 'eventually')"));
+    assert!(engine.logged_error("mode \"eventually\""));
+
+    assert!(!engine.eval_bool("RTC.send('send_validates', 'all', {})"));
+    assert!(engine.logged_error("third argument should be the message (string)"));
+
+    assert!(!engine.eval_bool(&format!(
+        "RTC.send('send_validates', '{PEER}', 'hello', 'reliable')"
+    )));
+    assert!(engine.logged_error("send: not connected to channel send_validates"));
+
+    connect_pending(&engine, "send_validates");
+    assert!(engine.eval_bool(&format!(
+        "RTC.send('send_validates', '{PEER}', 'hello', 'reliable')"
+    )));
+    engine.exec("RTC.disconnect('send_validates')");
+    engine.update();
+}
+
+#[test]
+fn send_table_rejects_unserializable_tables() {
+    let engine = engine();
+
+    engine.exec("cyclic = { inner = {} }; cyclic.inner.outer = cyclic");
+    assert!(!engine.eval_bool("RTC.send_table('send_table', 'all', cyclic)"));
+    assert!(engine.logged_error("value.inner.outer contains a cycle"));
+
+    assert!(!engine.eval_bool("RTC.send_table('send_table', 'all', { 1, 2, print })"));
+    assert!(engine.logged_error("value[3] is of type function"));
+
+    assert!(!engine.eval_bool("RTC.send_table('send_table', 'all', { [{}] = true })"));
+    assert!(engine.logged_error("has a key of type table"));
+
+    connect_pending(&engine, "send_table");
+    assert!(engine.eval_bool(
+        "RTC.send_table('send_table', 'all', { 1, 'two', { three = 3.5, [true] = false } })"
+    ));
+    engine.exec("RTC.disconnect('send_table')");
+    engine.update();
+}
+
+#[test]
+fn connect_validates_arguments() {
+    let engine = engine();
+    engine.exec("function noop() end");
+
+    assert!(!engine.eval_bool("RTC.connect('connect_validates', noop, 'noop', noop)"));
+    assert!(engine.logged_error("should be a on_message callback"));
+
+    assert!(!engine.eval_bool(
+        "RTC.connect('connect_validates', noop, noop, noop, { signaling_url = 'https://example.com' })"
+    ));
+    assert!(engine.logged_error("must start with ws:// or wss://"));
+
+    assert!(!engine.eval_bool(
+        "RTC.connect('connect_validates', noop, noop, noop, { ice_servers = { urls = 'turn:example.com' } })"
+    ));
+    assert!(engine.logged_error("required for TURN servers"));
+
+    assert!(!engine.eval_bool(
+        "RTC.connect('connect_validates', noop, noop, noop, { reconnect = { jitter = 2 } })"
+    ));
+    assert!(engine.logged_error("options.reconnect.jitter should be between 0 and 1"));
+
+    assert!(!engine.eval_bool(
+        "RTC.connect('connect_validates', noop, noop, noop, { reconnect = { max_delay = -1 } })"
+    ));
+    assert!(engine.logged_error("options.reconnect.max_delay should be a non-negative number"));
+
+    // Delays too long to represent don't panic
+    engine.exec(
+        r#"
+        forever = RTC.connect("connect_forever", noop, noop, noop, {
+            signaling_url = "ws://127.0.0.1:9",
+            reconnect = { initial_delay = 1e300, max_delay = 1e300 },
+        })
+        "#,
+    );
+    assert!(engine.eval_bool("forever ~= false"));
+    engine.exec("forever:close()");
+    engine.update();
+
+    assert!(!engine.eval_bool(
+        "RTC.connect('connect_validates', noop, noop, noop, { reconnect = { initial_delay = 'soon' } })"
+    ));
+    assert!(engine.logged_error("options.reconnect.initial_delay should be a number"));
+
+    assert!(!engine.eval_bool(r"RTC.connect('connect\0validates', noop, noop, noop)"));
+    assert!(engine.logged_error("room name without NUL bytes"));
+
+    assert!(!engine.eval_bool("RTC.is_connected('connect_validates')"));
+    assert!(engine.eval_bool("#RTC.rooms() == 0"));
+}
+
+#[test]
+fn unreachable_signaling_server_closes_room() {
+    let engine = engine();
+    engine.exec(&format!(
+        r#"
+        states = {{}}
+        function noop() end
+        room = RTC.connect("unreachable", noop, noop, noop, {{
+            signaling_url = "{UNREACHABLE}",
+            on_connection_state = function(state) table.insert(states, state) end,
+        }})
+        "#
+    ));
+    assert!(engine.eval_bool("RTC.rooms()[1] == 'unreachable'"));
+
+    let closed = engine.update_until(Duration::from_secs(10), |engine| {
+        engine.eval_bool("states[#states] == 'closed'")
+    });
+    assert!(closed, "room never closed\n{}", engine.dump_logs());
+    assert_eq!(
+        engine.eval_string("table.concat(states, ',')").as_deref(),
+        Some("connecting,closed")
+    );
+    assert!(!engine.eval_bool("RTC.is_connected('unreachable')"));
+    assert!(engine.eval_bool("RTC.my_id('unreachable') == nil"));
+    assert!(engine.eval_bool("RTC.room_state('unreachable') == 'closed'"));
+
+    assert!(!engine.eval_bool("RTC.send('unreachable', 'all', 'hello')"));
+    assert!(engine.logged_error("send: channel unreachable is closed"));
+    assert!(!engine.eval_bool("RTC.set_presence('unreachable', { name = 'closed' })"));
+    assert!(engine.logged_error("set_presence: channel unreachable is closed"));
+
+    // Closed is only reported once
+    engine.exec("RTC.disconnect('unreachable')");
+    engine.update();
+    assert!(engine.eval_bool("#RTC.rooms() == 0"));
+    assert!(engine.eval_bool("RTC.room_state('unreachable') == nil"));
+    assert_eq!(
+        engine.eval_string("table.concat(states, ',')").as_deref(),
+        Some("connecting,closed")
+    );
+}
+
+#[test]
+fn disconnect_reports_closed_state() {
+    let engine = engine();
+    engine.exec(&format!(
+        r#"
+        states = {{}}
+        function noop() end
+        room = RTC.connect("disconnect_closed", noop, noop, noop, {{
+            signaling_url = "{UNREACHABLE}",
+            reconnect = {{ initial_delay = 60 }},
+            on_connection_state = function(state) table.insert(states, state) end,
+        }})
+        RTC.disconnect("disconnect_closed")
+        "#
+    ));
+    engine.update();
+
+    assert_eq!(
+        engine.eval_string("states[#states]").as_deref(),
+        Some("closed")
+    );
+    assert!(engine.eval_bool("RTC.stats('disconnect_closed') == nil"));
+    assert!(engine.eval_bool("#RTC.rooms() == 0"));
+
+    // The background task finds the room gone, rather than adding a socket without callbacks
+    std::thread::sleep(Duration::from_millis(200));
+    engine.update();
+    assert!(engine.eval_bool("RTC.room_state('disconnect_closed') == nil"));
+    assert_eq!(
+        engine.eval_string("table.concat(states, ',')").as_deref(),
+        Some("closed")
+    );
+}
+
+#[test]
+fn room_states_define_every_operation() {
+    let engine = engine();
+    engine.exec(&format!(
+        r#"
+        states = {{}}
+        function noop() end
+        function connect_room()
+            room = RTC.connect("room_states", noop, noop, noop, {{
+                signaling_url = "{UNREACHABLE}",
+                reconnect = {{ initial_delay = 60 }},
+                on_connection_state = function(state) table.insert(states, state) end,
+            }})
+        end
+        connect_room()
+        "#
+    ));
+    let waiting = engine.update_until(Duration::from_secs(10), |engine| {
+        engine.eval_bool("states[#states] == 'reconnecting'")
+    });
+    assert!(waiting, "room never failed\n{}", engine.dump_logs());
+
+    // Messages are queued until the room is open
+    assert_eq!(
+        engine
+            .eval_string("RTC.room_state('room_states')")
+            .as_deref(),
+        Some("pending")
+    );
+    assert!(engine.eval_bool("RTC.send('room_states', 'all', 'queued')"));
+    assert_eq!(
+        engine.eval_number("RTC.stats('room_states').queued_sends"),
+        Some(1.0)
+    );
+    assert!(!engine.eval_bool("RTC.is_connected('room_states')"));
+    assert!(engine.eval_bool("#RTC.peers('room_states') == 0"));
+
+    // Disconnecting while waiting to reconnect closes the room, and no socket shows up later
+    engine.exec("RTC.disconnect('room_states')");
+    assert_eq!(
+        engine
+            .eval_string("RTC.room_state('room_states')")
+            .as_deref(),
+        Some("closing")
+    );
+    assert!(engine.eval_bool("#RTC.rooms() == 0"));
+    assert!(!engine.eval_bool("RTC.send('room_states', 'all', 'dropped')"));
+    assert!(engine.logged_error("send: channel room_states is closing"));
+    assert!(!engine.eval_bool(&format!(
+        "RTC.call('room_states', '{PEER}', 'add', nil, noop)"
+    )));
+    assert!(engine.logged_error("call: channel room_states is closing"));
+    assert_eq!(
+        engine.eval_number("RTC.stats('room_states').queued_sends"),
+        Some(0.0)
+    );
+
+    std::thread::sleep(Duration::from_millis(200));
+    engine.update();
+    assert_eq!(
+        engine.eval_string("table.concat(states, ',')").as_deref(),
+        Some("connecting,reconnecting,closed")
+    );
+    assert!(engine.eval_bool("RTC.room_state('room_states') == nil"));
+    assert!(!engine.eval_bool("RTC.send('room_states', 'all', 'unknown')"));
+    assert!(engine.logged_error("send: not connected to channel room_states"));
+
+    // Connecting while the room is closing replaces the disconnect
+    engine.exec("states = {} connect_room() RTC.disconnect('room_states') connect_room()");
+    engine.update();
+    assert_eq!(
+        engine.eval_string("table.concat(states, ',')").as_deref(),
+        Some("connecting")
+    );
+    assert!(engine.eval_bool("RTC.rooms()[1] == 'room_states'"));
+    assert!(engine.eval_bool("RTC.send('room_states', 'all', 'queued')"));
+
+    engine.exec("RTC.disconnect('room_states') RTC.disconnect('room_states')");
+    engine.update();
+    assert_eq!(
+        engine.eval_string("table.concat(states, ',')").as_deref(),
+        Some("connecting,closed")
+    );
+}
+
+#[test]
+fn room_handles_refer_to_their_room() {
+    let engine = engine();
+    engine.exec(&format!(
+        r#"
+        function noop() end
+        function connect_room()
+            return RTC.connect("handles", noop, noop, noop, {{
+                signaling_url = "{UNREACHABLE}",
+                reconnect = {{ initial_delay = 60 }},
+                close_on_gc = true,
+            }})
+        end
+        room = connect_room()
+        "#
+    ));
+    assert!(engine.eval_bool("type(room) == 'userdata'"));
+    assert_eq!(engine.eval_string("room:id()").as_deref(), Some("handles"));
+    assert_eq!(
+        engine.eval_string("tostring(room)").as_deref(),
+        Some("RTC room: handles")
+    );
+
+    // Methods are the module functions, with the handle in place of the room name
+    assert!(engine.eval_bool("room:state() == RTC.room_state('handles')"));
+    assert!(engine.eval_bool("room:send('all', 'queued', 'reliable')"));
+    assert!(engine.eval_bool("room:send_table('all', { 1 })"));
+    assert!(engine.eval_bool("room:stats().queued_sends == 2"));
+    assert!(engine.eval_bool("RTC.stats(room).queued_sends == 2"));
+    assert!(engine.eval_bool("#room:peers() == 0"));
+    assert!(!engine.eval_bool("room:is_connected()"));
+    assert!(engine.eval_bool("room:my_id() == nil"));
+
+    assert!(!engine.eval_bool("RTC.send(io.stdout, 'all', 'hello')"));
+    assert!(engine.logged_error("send: first argument should be the room (handle or string)"));
+
+    // A replaced room isn't disconnected by the old handle
+    engine.exec("old = room room = connect_room() old:close()");
+    engine.update();
+    assert!(engine.eval_bool("RTC.rooms()[1] == 'handles'"));
+    engine.exec("old = nil collectgarbage()");
+    engine.update();
+    assert!(engine.eval_bool("RTC.rooms()[1] == 'handles'"));
+    assert!(engine.eval_bool("room:stats().queued_sends == 2"));
+
+    // Collecting the handle disconnects the room, since it was connected with close_on_gc
+    engine.exec("room = nil collectgarbage()");
+    assert!(engine.eval_bool("RTC.room_state('handles') == 'closing'"));
+    engine.update();
+    assert!(engine.eval_bool("#RTC.rooms() == 0"));
+
+    engine.exec("room = connect_room()");
+    assert!(engine.eval_bool("room:close()"));
+    engine.update();
+    assert!(engine.eval_bool("#RTC.rooms() == 0"));
+    assert!(engine.eval_bool("room:state() == nil"));
+    assert!(!engine.eval_bool("room:send('all', 'hello')"));
+    assert!(engine.logged_error("send: not connected to channel handles"));
+}
+
+#[test]
+fn collected_handles_close_their_room() {
+    let engine = engine();
+    engine.exec(&format!(
+        r#"
+        function noop() end
+        local options = {{ signaling_url = "{UNREACHABLE}", reconnect = {{ initial_delay = 60 }} }}
+        kept = RTC.connect("kept_handle", noop, noop, noop, options)
+        RTC.connect("dropped_handle", noop, noop, noop, options)
+        options.close_on_gc = true
+        RTC.connect("closed_on_gc", noop, noop, noop, options)
+        collectgarbage()
+        "#
+    ));
+    engine.update();
+
+    // Only the room connected with close_on_gc went away with its handle
+    assert!(engine.eval_bool(
+        "#RTC.rooms() == 2 and RTC.rooms()[1] == 'dropped_handle' and RTC.rooms()[2] == 'kept_handle'"
+    ));
+    let warned = |room: &str| {
+        engine.logs().iter().any(|line| {
+            line.level == common::Level::Warning
+                && line.message.contains(room)
+                && line.message.contains("handle was garbage collected")
+        })
+    };
+    assert!(warned("closed_on_gc"));
+    assert!(!warned("dropped_handle"));
+
+    // Closing a room through its handle is nothing to warn about
+    assert!(engine.eval_bool("kept:close()"));
+    engine.exec("RTC.disconnect('dropped_handle')");
+    engine.update();
+    assert!(!warned("kept_handle"));
+    assert!(engine.eval_bool("#RTC.rooms() == 0"));
+
+    assert!(
+        !engine.eval_bool("RTC.connect('closed_on_gc', noop, noop, noop, { close_on_gc = 1 })")
+    );
+    assert!(engine.logged_error("options.close_on_gc should be a boolean"));
+}
+
+#[test]
+fn poll_buffers_events_without_callbacks() {
+    let engine = engine();
+    engine.exec(&format!(
+        r#"
+        function noop() end
+        room = RTC.connect("polled", {{
+            signaling_url = "{UNREACHABLE}",
+            reconnect = {{ initial_delay = 60 }},
+            event_buffer = 1,
+        }})
+        "#
+    ));
+    // The second state change doesn't fit in the buffer
+    let dropped = engine.update_until(Duration::from_secs(10), |engine| {
+        engine.eval_bool("RTC.stats('polled').dropped_events == 1")
+    });
+    assert!(dropped, "no event was dropped\n{}", engine.dump_logs());
+    assert!(
+        engine
+            .logs()
+            .iter()
+            .any(|line| line.message.contains("Event buffer is full"))
+    );
+
+    engine.exec("events, dropped = room:poll()");
+    assert!(engine.eval_bool("#events == 1 and dropped == 1"));
+    assert_eq!(
+        engine.eval_string("events[1].type").as_deref(),
+        Some("state")
+    );
+    assert_eq!(
+        engine.eval_string("events[1].state").as_deref(),
+        Some("connecting")
+    );
+    engine.exec("events, dropped = RTC.poll('polled', 10)");
+    assert!(engine.eval_bool("#events == 0 and dropped == 0"));
+    assert!(engine.eval_bool("RTC.stats('polled').dropped_events == 1"));
+
+    assert!(!engine.eval_bool("RTC.poll('polled', 0)"));
+    assert!(engine.logged_error("poll: second argument should be the maximum number of events"));
+    assert!(engine.eval_bool("#RTC.poll('unknown') == 0"));
+    assert!(!engine.eval_bool("RTC.connect('polled', 'noop')"));
+    assert!(engine.logged_error("second argument is not a function"));
+    assert!(!engine.eval_bool("RTC.connect('polled', { event_buffer = 0 })"));
+    assert!(engine.logged_error("options.event_buffer should be between 1 and 65536"));
+
+    // Rooms with callbacks have nothing to poll
+    connect_pending(&engine, "polled");
+    assert!(!engine.eval_bool("room:poll()"));
+    assert!(engine.logged_error("poll: channel polled was connected with callbacks"));
+
+    engine.exec("room:close()");
+    engine.update();
+}
+
+#[test]
+fn call_times_out_without_reply() {
+    let engine = engine();
+    connect_pending(&engine, "call_timeout");
+    engine.exec(&format!(
+        r#"
+        replies = {{}}
+        RTC.call("call_timeout", "{PEER}", "add", {{ 1, 2 }}, function(ok, result)
+            table.insert(replies, {{ ok = ok, result = result }})
+        end, 0.01)
+        "#
+    ));
+    assert_eq!(engine.eval_number("#replies"), Some(0.0));
+
+    let replied = engine.update_until(Duration::from_secs(5), |engine| {
+        engine.eval_bool("#replies > 0")
+    });
+    assert!(replied, "on_reply was never called\n{}", engine.dump_logs());
+    assert!(!engine.eval_bool("replies[1].ok"));
+    assert_eq!(
+        engine.eval_string("replies[1].result").as_deref(),
+        Some("timeout")
+    );
+
+    // `on_reply` is called only once
+    engine.update();
+    assert_eq!(engine.eval_number("#replies"), Some(1.0));
+
+    // Drop the queued request
+    engine.exec("RTC.disconnect('call_timeout')");
+    engine.update();
+}
+
+#[test]
+fn callback_errors_are_logged() {
+    let engine = engine();
+    connect_pending(&engine, "callback_errors");
+    engine.exec(&format!(
+        r#"
+        RTC.call("callback_errors", "{PEER}", "add", nil, function()
+            error("oops")
+        end, 0.01)
+        "#
+    ));
+
+    let logged = engine.update_until(Duration::from_secs(5), |engine| {
+        engine.logged_error("Error in on_reply callback")
+    });
+    assert!(
+        logged,
+        "callback error was not logged\n{}",
+        engine.dump_logs()
+    );
+    assert!(engine.logged_error("oops"));
+    assert!(engine.logged_error("stack traceback"));
+
+    // The error handler and message are popped off the stack
+    assert_eq!(engine.stack_size(), 0);
+    engine.exec("RTC.disconnect('callback_errors')");
+    engine.update();
+}
+
+#[test]
+fn register_method_validates_arguments() {
+    let engine = engine();
+
+    assert!(!engine.eval_bool("RTC.register_method('methods', '', function() end)"));
+    assert!(engine.logged_error("method name should be between 1 and 255 bytes long"));
+
+    assert!(!engine.eval_bool("RTC.register_method('methods', 'add', 'add')"));
+    assert!(engine.logged_error("third argument should be the handler"));
+
+    assert!(engine.eval_bool("RTC.register_method('methods', 'add', function(a) return a end)"));
+    assert!(engine.eval_bool("RTC.register_method('methods', 'add', nil)"));
+}
+
+#[test]
+fn queries_on_unknown_room() {
+    let engine = engine();
+
+    assert!(!engine.eval_bool("RTC.is_connected('unknown')"));
+    assert!(engine.eval_bool("RTC.my_id('unknown') == nil"));
+    assert!(engine.eval_bool("#RTC.peers('unknown') == 0"));
+    assert!(engine.eval_bool("RTC.stats('unknown') == nil"));
+}
+
+#[test]
+fn set_signaling_url_validates_url() {
+    let engine = engine();
+
+    assert!(!engine.eval_bool("RTC.set_signaling_url('rtc.darkti.de')"));
+    assert!(engine.logged_error("must start with ws:// or wss://"));
+    assert!(engine.eval_bool("RTC.set_signaling_url('wss://rtc.darkti.de/')"));
+}
+
+#[test]
+fn log_level_filters_logs() {
+    let engine = engine();
+    let logged = |text: &str| engine.logs().iter().any(|line| line.message.contains(text));
+
+    assert!(!engine.eval_bool("RTC.set_log_level('verbose')"));
+    assert!(engine.logged_error("level \"verbose\" is not"));
+    assert!(!engine.eval_bool("RTC.set_log_level(nil)"));
+    assert!(!engine.eval_bool("RTC.set_log_payload_limit(-1)"));
+
+    // Keeps the room pending, so that messages are queued for no one
+    engine.exec(&format!(
+        r#"
+        states = {{}}
+        function noop() end
+        room = RTC.connect("log_levels", noop, noop, noop, {{
+            signaling_url = "{UNREACHABLE}",
+            reconnect = {{ initial_delay = 60 }},
+            on_connection_state = function(state) table.insert(states, state) end,
+        }})
+        "#
+    ));
+    let waiting = engine.update_until(Duration::from_secs(10), |engine| {
+        engine.eval_bool("states[#states] == 'reconnecting'")
+    });
+    assert!(waiting, "room never failed\n{}", engine.dump_logs());
+
+    // Messages aren't logged by default
+    engine.exec("RTC.send('log_levels', 'all', 'secret message')");
+    engine.update();
+    assert!(!logged("secret"));
+
+    engine.exec(
+        r#"
+        RTC.set_log_level("debug", "log_levels")
+        RTC.set_log_payload_limit(6)
+        RTC.send("log_levels", "all", "secret message")
+        "#,
+    );
+    engine.update();
+    assert!(logged(
+        r#"[Channel: log_levels] unreliable message to all: "secret"... (14 bytes)"#
+    ));
+
+    engine.exec(
+        r#"
+        RTC.set_log_payload_limit(0)
+        RTC.send("log_levels", "all", "secret message")
+        "#,
+    );
+    engine.update();
+    assert!(logged("unreliable message to all: <14 bytes>"));
+
+    // Without the room's level, only warnings and errors are logged
+    engine.exec(
+        r#"
+        RTC.set_log_level("warn")
+        RTC.set_log_level(nil, "log_levels")
+        RTC.send("log_levels", "all", "quiet")
+        RTC.disconnect("log_levels")
+        "#,
+    );
+    engine.update();
+    assert!(!logged("quiet"));
+    assert!(!logged("Disconnecting"));
+    assert!(!engine.eval_bool("RTC.send(nil)"));
+    assert!(engine.logged_error("send: first argument is nil"));
+
+    engine.exec("RTC.set_log_level('off')");
+    assert!(!engine.eval_bool("RTC.disconnect(nil)"));
+    assert!(!engine.logged_error("disconnect: first argument"));
+
+    engine.exec("RTC.set_log_level('info') RTC.set_log_payload_limit(64)");
+}
+
+#[test]
+fn capture_and_replay_validate_arguments() {
+    let engine = engine();
+
+    assert!(!engine.eval_bool("RTC.start_capture('capture_args')"));
+    assert!(engine.logged_error("start_capture: second argument"));
+    assert!(!engine.eval_bool("RTC.start_capture('capture_args', '/no/such/dir/capture')"));
+    assert!(engine.logged_error("start_capture: /no/such/dir/capture can't be created"));
+    assert!(!engine.eval_bool("RTC.stop_capture('capture_args')"));
+
+    engine.exec(
+        r#"
+        states = {}
+        function noop() end
+        ok = RTC.connect("replay_args", noop, noop, noop, { replay = 1 })
+        room = RTC.connect("replay_args", noop, noop, noop, {
+            replay = "/no/such/capture",
+            on_connection_state = function(state) table.insert(states, state) end,
+        })
+        "#,
+    );
+    assert!(engine.eval_bool("ok == false"));
+    assert!(engine.logged_error("options.replay should be a string"));
+    engine.update();
+    assert!(engine.logged_error("Can't replay /no/such/capture"));
+    assert_eq!(
+        engine.eval_string("table.concat(states, ',')").as_deref(),
+        Some("connecting,closed")
+    );
+
+    engine.exec("RTC.disconnect('replay_args')");
+    engine.update();
+    assert!(engine.eval_bool("#RTC.rooms() == 0"));
+}
+
+#[test]
+fn presence_validates_arguments() {
+    let engine = engine();
+
+    engine.exec(
+        r#"
+        function noop() end
+        not_table = RTC.connect("presence_args", noop, noop, noop, { presence = "name" })
+        too_long = RTC.connect("presence_args", noop, noop, noop, {
+            presence = { name = string.rep("x", 2000) },
+        })
+        "#,
+    );
+    assert!(engine.eval_bool("not_table == false"));
+    assert!(engine.logged_error("options.presence should be a table"));
+    assert!(engine.eval_bool("too_long == false"));
+    assert!(engine.logged_error("options.presence is 20"));
+    assert!(!engine.eval_bool("RTC.set_presence('presence_args', { name = 'x' })"));
+    assert!(engine.logged_error("set_presence: not connected to channel presence_args"));
+
+    engine.exec(&format!(
+        r#"
+        room = RTC.connect("presence_args", noop, noop, noop, {{
+            signaling_url = "{UNREACHABLE}",
+            presence = {{ name = "first" }},
+        }})
+        "#
+    ));
+    assert!(!engine.eval_bool("RTC.set_presence('presence_args', 'name')"));
+    assert!(engine.logged_error("set_presence: presence should be a table"));
+    assert!(engine.eval_bool("RTC.set_presence('presence_args', { name = 'second' })"));
+    assert!(engine.eval_bool("RTC.set_presence('presence_args', nil)"));
+    assert!(engine.eval_bool("RTC.peer_info('presence_args', 'not a peer') == nil"));
+    assert!(engine.eval_bool("RTC.peer_info('presence_args', nil) == nil"));
+
+    engine.exec("RTC.disconnect('presence_args')");
+    engine.update();
+}
+
+#[test]
+fn rate_limits_validate_options() {
+    let engine = engine();
+
+    for (limits, error) in [
+        ("1", "options.rate_limits should be a table"),
+        (
+            "{ receive_per_peer = 'fast' }",
+            "options.rate_limits.receive_per_peer should be a number or a table",
+        ),
+        (
+            "{ send_per_room = -1 }",
+            "options.rate_limits.send_per_room should be a positive number",
+        ),
+        (
+            "{ send_per_peer = { burst = 2 } }",
+            "options.rate_limits.send_per_peer.rate should be a positive number",
+        ),
+        (
+            "{ receive_per_room = { rate = 1, burst = 0 } }",
+            "options.rate_limits.receive_per_room.burst should be at least 1",
+        ),
+    ] {
+        engine.exec(&format!(
+            r#"
+            function noop() end
+            ok = RTC.connect("rate_args", noop, noop, noop, {{ rate_limits = {limits} }})
+            "#
+        ));
+        assert!(engine.eval_bool("ok == false"), "{limits} was accepted");
+        assert!(engine.logged_error(error), "{error} wasn't logged");
+    }
+    assert!(engine.eval_bool("#RTC.rooms() == 0"));
+}
+
+#[test]
+fn send_rate_limit_drops_messages() {
+    let engine = engine();
+
+    engine.exec(&format!(
+        r#"
+        function noop() end
+        limited = {{}}
+        room = RTC.connect("rate_send", noop, noop, noop, {{
+            signaling_url = "{UNREACHABLE}",
+            rate_limits = {{
+                send_per_room = {{ rate = 0.001, burst = 3 }},
+                send_per_peer = {{ rate = 0.001, burst = 1 }},
+            }},
+            on_rate_limited = function(peer, direction, dropped)
+                table.insert(limited, {{ peer = peer, direction = direction, dropped = dropped }})
+            end,
+        }})
+        sent = {{
+            RTC.send("rate_send", "{PEER}", "one"),
+            RTC.send("rate_send", "{PEER}", "two"),
+            RTC.send("rate_send", "all", "three"),
+            RTC.send_table("rate_send", "all", {{ 4 }}),
+            RTC.send("rate_send", "all", "five"),
+            RTC.call("rate_send", "{PEER}", "add", nil, noop),
+        }}
+        "#
+    ));
+    assert!(engine.eval_bool(
+        "sent[1] and not sent[2] and sent[3] and sent[4] and not sent[5] and not sent[6]"
+    ));
+    assert!(engine.eval_bool("RTC.stats('rate_send').queued_sends == 3"));
+    // The console goes through the same limits
+    engine.console("rtc_send rate_send all six");
+    assert!(engine.logs().iter().any(|line| {
+        line.message
+            .contains("Dropped message to all in rate_send, over the send rate limit")
+    }));
+
+    let reported = engine.update_until(Duration::from_secs(5), |engine| {
+        engine.eval_bool("#limited == 2")
+    });
+    assert!(
+        reported,
+        "drops were never reported\n{}",
+        engine.dump_logs()
+    );
+    assert!(engine.eval_bool(&format!(
+        r#"limited[1].peer == "{PEER}" and limited[1].direction == "send"
+            and limited[1].dropped == 2"#
+    )));
+    assert!(engine.eval_bool(
+        r#"limited[2].peer == "all" and limited[2].direction == "send"
+            and limited[2].dropped == 2"#
+    ));
+    assert!(engine.logs().iter().any(|line| {
+        line.message
+            .contains("Dropped 2 messages to all, over the send rate limit")
+    }));
+
+    engine.exec("RTC.disconnect('rate_send')");
+    engine.update();
+}
+
+#[test]
+fn console_commands_inspect_and_control_rooms() {
+    let engine = engine();
+    let logged = |text: &str| engine.logs().iter().any(|line| line.message.contains(text));
+
+    for command in [
+        "rtc_rooms",
+        "rtc_peers",
+        "rtc_stats",
+        "rtc_send",
+        "rtc_disconnect",
+    ] {
+        assert!(
+            engine
+                .console_help(command)
+                .is_some_and(|help| !help.is_empty()),
+            "{command} has no help"
+        );
+    }
+
+    // The output was asked for, so it is written even with logging turned off
+    engine.exec("RTC.set_log_level('off')");
+    connect_pending(&engine, "console");
+
+    engine.console("rtc_send console all hello there");
+    assert!(logged("Queued message to all in console"));
+    engine.console("rtc_send console someone hello");
+    assert!(logged(
+        "rtc_send: recipient someone is not \"all\" or a valid Uuid"
+    ));
+    engine.console("rtc_send console all");
+    assert!(logged("usage: rtc_send <room> <peer|all> <message>"));
+
+    engine.console("rtc_rooms");
+    assert!(logged("console: "));
+    engine.console("rtc_stats console");
+    assert!(logged("  queued_sends: 1"));
+    engine.console("rtc_peers unknown");
+    assert!(logged("rtc_peers: not connected to channel unknown"));
+    engine.console("rtc_peers");
+    assert!(logged("usage: rtc_peers <room>"));
+
+    engine.console("rtc_disconnect console");
+    assert!(logged("Disconnecting from console"));
+    assert_eq!(
+        engine.eval_string("RTC.room_state('console')").as_deref(),
+        Some("closing")
+    );
+    engine.update();
+    engine.console("rtc_disconnect console");
+    assert!(logged("rtc_disconnect: not connected to channel console"));
+
+    engine.exec("RTC.set_log_level('info')");
+}
@@ -0,0 +1,180 @@
+//! Lua callbacks calling back into the plugin. Every callback connects, sends to and disconnects
+//! from a room, and queries the room it was called for, which must not deadlock the game thread.
+
+mod common;
+
+use common::{engine, signaling_server, spawn_peer};
+use std::time::Duration;
+
+const URL_VAR: &str = "RTC_REENTRANCY_URL";
+const ROOM: &str = "reentrancy";
+/// A signaling url nothing listens on, for the rooms connected from inside callbacks.
+const UNREACHABLE: &str = "ws://127.0.0.1:9";
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Every callback `connect_reentrant` passes, which both peers end up calling.
+const CALLBACKS: [&str; 9] = [
+    "on_connection_state",
+    "on_peer_connected",
+    "on_message",
+    "on_table_message",
+    "on_peer_updated",
+    "on_rate_limited",
+    "on_reply",
+    "method",
+    "on_peer_disconnected",
+];
+
+/// Connects to `ROOM` with callbacks that all call `reenter`. When a peer joins, it is sent a
+/// message, a table and a call, and once the call is answered, one message too many for the rate
+/// limit, which our reply to the peer's call counts against as well. That way the peer's
+/// callbacks run as well.
+fn connect_reentrant(engine: &common::Engine, url: &str) {
+    engine.exec(&format!(
+        r#"
+        reentered = {{}}
+        local function noop() end
+        function reenter(name)
+            local room = "reentrant_" .. name
+            RTC.connect(room, noop, noop, noop, {{
+                signaling_url = "{UNREACHABLE}",
+                reconnect = false,
+            }})
+            assert(RTC.send(room, "all", name))
+            RTC.disconnect(room)
+            RTC.is_connected("{ROOM}")
+            RTC.my_id("{ROOM}")
+            RTC.peers("{ROOM}")
+            RTC.stats("{ROOM}")
+            reentered[name] = true
+        end
+
+        RTC.register_method("{ROOM}", "ping", function()
+            reenter("method")
+            return "pong"
+        end)
+        room = RTC.connect("{ROOM}", function(peer)
+            reenter("on_peer_connected")
+            RTC.send("{ROOM}", peer, "hello", "reliable")
+            RTC.send_table("{ROOM}", peer, {{ 1 }}, "reliable")
+            RTC.call("{ROOM}", peer, "ping", nil, function(ok, reply)
+                assert(ok and reply == "pong", reply)
+                reenter("on_reply")
+                RTC.send("{ROOM}", peer, "dropped", "reliable")
+            end)
+        end, function()
+            reenter("on_message")
+        end, function()
+            reenter("on_peer_disconnected")
+        end, {{
+            signaling_url = "{url}",
+            ice_servers = {{ urls = "stun:127.0.0.1:9" }},
+            presence = {{ name = "reentrant" }},
+            rate_limits = {{ send_per_peer = {{ rate = 0.001, burst = 4 }} }},
+            on_connection_state = function()
+                reenter("on_connection_state")
+            end,
+            on_table_message = function()
+                reenter("on_table_message")
+            end,
+            on_peer_updated = function()
+                reenter("on_peer_updated")
+            end,
+            on_rate_limited = function()
+                reenter("on_rate_limited")
+            end,
+        }})
+        "#
+    ));
+}
+
+/// Whether every callback in `callbacks` has called `reenter`.
+fn reentered(engine: &common::Engine, callbacks: &[&str]) -> bool {
+    callbacks
+        .iter()
+        .all(|name| engine.eval_bool(&format!("reentered.{name} == true")))
+}
+
+#[test]
+fn callbacks_can_call_rtc_functions() {
+    let engine = engine();
+    let url = signaling_server();
+    let mut peer = spawn_peer("reentrant_peer", &[(URL_VAR, &url)]);
+    connect_reentrant(&engine, &url);
+
+    let done = engine.update_until(TIMEOUT, |engine| reentered(engine, &CALLBACKS));
+    let missing: Vec<&str> = CALLBACKS
+        .into_iter()
+        .filter(|name| !engine.eval_bool(&format!("reentered.{name} == true")))
+        .collect();
+    assert!(
+        done,
+        "{missing:?} never called reenter\n{}",
+        engine.dump_logs()
+    );
+
+    let status = peer.wait().expect("failed to wait for the peer process");
+    assert!(status.success(), "the peer process failed");
+
+    engine.exec(&format!("RTC.disconnect('{ROOM}')"));
+    engine.update();
+}
+
+/// The other side of `callbacks_can_call_rtc_functions`. Leaves once all of its callbacks but
+/// `on_peer_disconnected` have run.
+#[test]
+#[ignore = "started by callbacks_can_call_rtc_functions"]
+fn reentrant_peer() {
+    let url = std::env::var(URL_VAR).expect("reentrant_peer is started by a test");
+    let engine = engine();
+    connect_reentrant(&engine, &url);
+
+    let done = engine.update_until(TIMEOUT, |engine| {
+        reentered(engine, &CALLBACKS[..CALLBACKS.len() - 1])
+    });
+    assert!(done, "not every callback ran\n{}", engine.dump_logs());
+
+    engine.exec(&format!("RTC.disconnect('{ROOM}')"));
+    engine.update();
+}
+
+#[test]
+fn closed_callback_can_connect_again() {
+    let engine = engine();
+    let url = signaling_server();
+
+    engine.exec(&format!(
+        r#"
+        states = {{}}
+        local function noop() end
+        function connect_room()
+            room = RTC.connect("{ROOM}", noop, noop, noop, {{
+                signaling_url = "{url}",
+                on_connection_state = function(state)
+                    table.insert(states, state)
+                    if #states == 2 then
+                        RTC.disconnect("{ROOM}")
+                    elseif #states == 3 then
+                        connect_room()
+                    end
+                end,
+            }})
+        end
+        connect_room()
+        "#
+    ));
+    let connected = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#states == 5"));
+    assert!(connected, "never connected again\n{}", engine.dump_logs());
+    assert_eq!(
+        engine.eval_string("table.concat(states, ',')").as_deref(),
+        Some("connecting,connected,closed,connecting,connected")
+    );
+    assert!(engine.eval_bool(&format!("RTC.is_connected('{ROOM}')")));
+
+    engine.exec(&format!("RTC.disconnect('{ROOM}')"));
+    engine.update();
+    assert_eq!(
+        engine.eval_string("table.concat(states, ',')").as_deref(),
+        Some("connecting,connected,closed,connecting,connected,closed")
+    );
+}
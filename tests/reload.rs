@@ -0,0 +1,104 @@
+mod common;
+
+use common::{Cli, engine, signaling_server};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const PEER: &str = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+#[test]
+fn rooms_survive_hot_reload() {
+    let engine = engine();
+    let url = signaling_server();
+    let mut cli = Cli::spawn(&url, "reload");
+
+    engine.exec(&format!(
+        r#"
+        connected = {{}}
+        messages = {{}}
+        states = {{}}
+        replies = {{}}
+        RTC.set_signaling_url("{url}")
+        room = RTC.connect("reload", function(peer)
+            table.insert(connected, peer)
+        end, function(message, peer)
+            table.insert(messages, message)
+        end, function() end, {{
+            ice_servers = {{ urls = "stun:127.0.0.1:9" }},
+            on_connection_state = function(state) table.insert(states, state) end,
+        }})
+        RTC.register_method("reload", "echo", function(payload) return payload end)
+        "#
+    ));
+    let joined = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#connected == 1"));
+    assert!(joined, "rtc-cli never connected\n{}", engine.dump_logs());
+
+    // Queued before the reload, and only sent after it
+    engine.exec(&format!(
+        r#"
+        RTC.send("reload", "all", "queued")
+        RTC.call("reload", "{PEER}", "echo", nil, function(ok, result)
+            table.insert(replies, result)
+        end, 0.5)
+        "#
+    ));
+    engine.reload();
+
+    assert!(
+        engine
+            .logs()
+            .iter()
+            .any(|line| line.message.contains("Finished hot reload"))
+    );
+    assert!(engine.eval_bool("RTC.rooms()[1] == 'reload'"));
+    // Handles returned before the reload still refer to the room, and can close it
+    assert!(engine.eval_bool("room:id() == 'reload'"));
+    // The message and the request
+    assert_eq!(
+        engine
+            .eval_string("RTC.stats('reload').queued_sends")
+            .as_deref(),
+        Some("2")
+    );
+    assert_eq!(
+        engine
+            .eval_string("RTC.stats('reload').pending_calls")
+            .as_deref(),
+        Some("1")
+    );
+
+    // The room is joined again with a new id, and the old callbacks are still called
+    let rejoined = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#connected == 2"));
+    assert!(
+        rejoined,
+        "rtc-cli never reconnected\n{}",
+        engine.dump_logs()
+    );
+    assert_eq!(
+        engine.eval_string("table.concat(states, ',')").as_deref(),
+        Some("connecting,connected,connecting,connected")
+    );
+    cli.expect(&engine, r#""event":"peer_joined""#, 2);
+
+    cli.type_line("after reload");
+    let received = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#messages == 1"));
+    assert!(
+        received,
+        "the typed line never arrived\n{}",
+        engine.dump_logs()
+    );
+    assert_eq!(
+        engine.eval_string("messages[1]").as_deref(),
+        Some("after reload")
+    );
+
+    // The call made before the reload still times out
+    let replied = engine.update_until(TIMEOUT, |engine| engine.eval_bool("#replies == 1"));
+    assert!(replied, "on_reply was never called\n{}", engine.dump_logs());
+    assert_eq!(engine.eval_string("replies[1]").as_deref(), Some("timeout"));
+
+    assert!(cli.quit(), "rtc-cli failed");
+    engine.exec("room:close()");
+    engine.update();
+    assert!(engine.eval_bool("#RTC.rooms() == 0"));
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use common::engine;
+
+#[test]
+fn shutdown_releases_callbacks() {
+    let engine = engine();
+    engine.exec(
+        r#"
+        states = {}
+        callbacks = setmetatable({}, { __mode = "k" })
+        local function track(f)
+            callbacks[f] = true
+            return f
+        end
+
+        room = RTC.connect(
+            "shutdown",
+            track(function() end),
+            track(function() end),
+            track(function() end),
+            {
+                signaling_url = "ws://127.0.0.1:9",
+                reconnect = { initial_delay = 60 },
+                on_connection_state = track(function(state) table.insert(states, state) end),
+            }
+        )
+        RTC.register_method("shutdown", "echo", track(function(payload) return payload end))
+        RTC.register_method("not_connected", "echo", track(function(payload) return payload end))
+        RTC.call("shutdown", "67e55044-10b1-426f-9247-bb680e5fe0c8", "echo", nil, track(function() end))
+        "#,
+    );
+    engine.exec("collectgarbage()");
+    assert_eq!(
+        engine.eval_number(
+            "(function() local n = 0 for _ in pairs(callbacks) do n = n + 1 end return n end)()"
+        ),
+        Some(7.0)
+    );
+
+    engine.shutdown();
+    assert!(
+        engine
+            .logs()
+            .iter()
+            .any(|line| line.message.contains("Shutting down"))
+    );
+
+    // Only the plugin held on to the functions
+    engine.exec("collectgarbage()");
+    assert!(engine.eval_bool("next(callbacks) == nil"));
+
+    // Nothing is dispatched after shutting down
+    engine.exec("states = {}");
+    engine.update();
+    assert!(engine.eval_bool("#states == 0"));
+
+    // The plugin is freed, so the module functions do nothing
+    assert!(engine.eval_bool("RTC.send('shutdown', 'all', 'late') == nil"));
+    assert!(engine.eval_bool("room:id() == nil"));
+}